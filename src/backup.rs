@@ -0,0 +1,572 @@
+// Mirrors the watch filesystem into a local directory or a tar archive and restores it back
+mod tar;
+
+use crate::polar_watch::{DirEntry, PolarError, PolarWatch};
+use log::info;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_ROOT: &str = "/U/0/";
+pub const MANIFEST_NAME: &str = "MANIFEST";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    Directory,
+    File,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub path: String,
+    pub size: usize,
+}
+
+// List of everything that was backed up, in the order it must be restored. Each line looks like
+// "d 0 /U/0/FAV/" or "f 140 /U/0/FAV/00/TST.BPB"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+
+        for entry in &self.entries {
+            let kind = match entry.kind {
+                EntryKind::Directory => "d",
+                EntryKind::File => "f",
+            };
+
+            result += &format!("{} {} {}\n", kind, entry.size, entry.path);
+        }
+
+        result
+    }
+
+    pub fn parse(text: &str) -> Result<Manifest, PolarError> {
+        let mut entries = vec![];
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let parts: Vec<&str> = line.splitn(3, ' ').collect();
+            let invalid = || PolarError::new(format!("Invalid manifest line '{}'", line));
+
+            if parts.len() != 3 {
+                return Err(invalid());
+            }
+
+            let kind = match parts[0] {
+                "d" => EntryKind::Directory,
+                "f" => EntryKind::File,
+                _ => return Err(invalid()),
+            };
+
+            let size = parts[1].parse().map_err(|_| invalid())?;
+
+            entries.push(ManifestEntry {
+                kind,
+                size,
+                path: parts[2].to_string(),
+            });
+        }
+
+        Ok(Manifest { entries })
+    }
+}
+
+// Shared with sync, for every local file that can't be read or written
+pub(crate) fn io_error<P: AsRef<Path>>(action: &str, path: P, error: std::io::Error) -> PolarError {
+    PolarError::new(format!(
+        "Failed to {} '{}'\n\t{:?}",
+        action,
+        path.as_ref().display(),
+        error
+    ))
+}
+
+// Where the files downloaded from the watch end up
+trait BackupSink {
+    fn add_directory(&mut self, path: &str) -> Result<(), PolarError>;
+    fn add_file(&mut self, path: &str, data: &[u8]) -> Result<(), PolarError>;
+    fn finish(&mut self, manifest: &Manifest) -> Result<(), PolarError>;
+}
+
+struct DirectorySink {
+    base: PathBuf,
+}
+
+impl BackupSink for DirectorySink {
+    fn add_directory(&mut self, path: &str) -> Result<(), PolarError> {
+        let local = local_path(&self.base, path)?;
+        std::fs::create_dir_all(&local).map_err(|error| io_error("create", &local, error))
+    }
+
+    fn add_file(&mut self, path: &str, data: &[u8]) -> Result<(), PolarError> {
+        let local = local_path(&self.base, path)?;
+        std::fs::write(&local, data).map_err(|error| io_error("write", &local, error))
+    }
+
+    fn finish(&mut self, manifest: &Manifest) -> Result<(), PolarError> {
+        let local = self.base.join(MANIFEST_NAME);
+        std::fs::write(&local, manifest.to_text()).map_err(|error| io_error("write", &local, error))
+    }
+}
+
+struct TarSink {
+    path: PathBuf,
+    writer: tar::TarWriter<std::fs::File>,
+}
+
+impl BackupSink for TarSink {
+    fn add_directory(&mut self, path: &str) -> Result<(), PolarError> {
+        let path = path.trim_start_matches("/");
+        let result = self.writer.add_directory(path);
+        result.map_err(|error| io_error("write", &self.path, error))
+    }
+
+    fn add_file(&mut self, path: &str, data: &[u8]) -> Result<(), PolarError> {
+        let path = path.trim_start_matches("/");
+        let result = self.writer.add_file(path, data);
+        result.map_err(|error| io_error("write", &self.path, error))
+    }
+
+    fn finish(&mut self, manifest: &Manifest) -> Result<(), PolarError> {
+        let text = manifest.to_text();
+        let result = self
+            .writer
+            .add_file(MANIFEST_NAME, text.as_bytes())
+            .and_then(|_| self.writer.finish());
+
+        result.map_err(|error| io_error("write", &self.path, error))
+    }
+}
+
+// Paths come from the manifest, so they can't be allowed to leave the backup directory
+fn local_path(base: &Path, remote_path: &str) -> Result<PathBuf, PolarError> {
+    let mut result = base.to_path_buf();
+
+    for part in remote_path.split("/").filter(|part| !part.is_empty()) {
+        if part == "." || part == ".." {
+            return Err(PolarError::new(format!(
+                "Invalid path '{}' in the backup",
+                remote_path
+            )));
+        }
+        result.push(part);
+    }
+
+    Ok(result)
+}
+
+fn is_tar<P: AsRef<Path>>(path: P) -> bool {
+    match path.as_ref().extension() {
+        Some(extension) => extension.to_string_lossy().to_lowercase() == "tar",
+        None => false,
+    }
+}
+
+fn download(
+    watch: &mut PolarWatch,
+    root: String,
+    sink: &mut dyn BackupSink,
+    manifest: &mut Manifest,
+) -> Result<(), PolarError> {
+    // List everything first, the walk borrows the watch so nothing can be downloaded until it ends
+    let entries = watch
+        .walk(root.clone())
+        .collect::<Result<Vec<DirEntry>, PolarError>>()?;
+
+    sink.add_directory(&root)?;
+    manifest.entries.push(ManifestEntry {
+        kind: EntryKind::Directory,
+        path: root,
+        size: 0,
+    });
+
+    for entry in entries {
+        if entry.is_directory {
+            sink.add_directory(&entry.path)?;
+            manifest.entries.push(ManifestEntry {
+                kind: EntryKind::Directory,
+                path: entry.path,
+                size: 0,
+            });
+        } else {
+            let data = watch.get_file(entry.path.clone())?;
+            sink.add_file(&entry.path, &data)?;
+
+            manifest.entries.push(ManifestEntry {
+                kind: EntryKind::File,
+                path: entry.path,
+                size: data.len(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Downloads everything under root. Destinations ending in .tar become a tar archive, anything
+// else is used as a directory
+pub fn backup<P: AsRef<Path>>(
+    watch: &mut PolarWatch,
+    root: &str,
+    destination: P,
+) -> Result<Manifest, PolarError> {
+    let destination = destination.as_ref();
+    let mut root = root.to_string();
+
+    if !root.ends_with("/") {
+        root += "/";
+    }
+
+    info!("Backing up {} into {}", root, destination.display());
+
+    let mut manifest = Manifest::default();
+
+    if !is_tar(destination) {
+        let mut sink = DirectorySink {
+            base: destination.to_path_buf(),
+        };
+        download(watch, root, &mut sink, &mut manifest)?;
+        sink.finish(&manifest)?;
+
+        return Ok(manifest);
+    }
+
+    // The archive is written next to the destination and only renamed once it is complete, so a
+    // failed backup doesn't leave a partial archive behind
+    let partial = partial_path(destination);
+    let file =
+        std::fs::File::create(&partial).map_err(|error| io_error("create", &partial, error))?;
+    let mut sink = TarSink {
+        path: partial.clone(),
+        writer: tar::TarWriter::new(file),
+    };
+
+    let result =
+        download(watch, root, &mut sink, &mut manifest).and_then(|_| sink.finish(&manifest));
+    drop(sink);
+
+    if let Err(error) = result {
+        let _ = std::fs::remove_file(&partial);
+        return Err(error);
+    }
+
+    std::fs::rename(&partial, destination).map_err(|error| io_error("rename", &partial, error))?;
+
+    Ok(manifest)
+}
+
+fn partial_path(destination: &Path) -> PathBuf {
+    let mut name = destination.as_os_str().to_os_string();
+    name.push(".partial");
+
+    PathBuf::from(name)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreStep {
+    CreateDirectory(String),
+    UploadFile { path: String, data: Vec<u8> },
+}
+
+impl RestoreStep {
+    pub fn describe(&self) -> String {
+        match self {
+            RestoreStep::CreateDirectory(path) => format!("mkdir {}", path),
+            RestoreStep::UploadFile { path, data } => {
+                format!("upload {} ({} bytes)", path, data.len())
+            }
+        }
+    }
+}
+
+fn read_local(path: &Path) -> Result<Vec<u8>, PolarError> {
+    std::fs::read(path).map_err(|error| io_error("read", path, error))
+}
+
+// Reads a backup made by `backup` and works out what has to be sent to the watch, without
+// touching it. Files are checked against the sizes in the manifest
+pub fn plan_restore<P: AsRef<Path>>(source: P) -> Result<Vec<RestoreStep>, PolarError> {
+    let source = source.as_ref();
+
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    let manifest = if is_tar(source) {
+        let file = std::fs::File::open(source).map_err(|error| io_error("open", source, error))?;
+
+        for (name, data) in
+            tar::read_files(file).map_err(|error| io_error("read", source, error))?
+        {
+            files.insert(name, data);
+        }
+
+        match files.remove(MANIFEST_NAME) {
+            Some(text) => Manifest::parse(&String::from_utf8_lossy(&text))?,
+            None => return Err(PolarError::new("Backup archive has no manifest")),
+        }
+    } else {
+        let text = read_local(&source.join(MANIFEST_NAME))?;
+        Manifest::parse(&String::from_utf8_lossy(&text))?
+    };
+
+    let mut steps = vec![];
+    for entry in manifest.entries {
+        match entry.kind {
+            EntryKind::Directory => steps.push(RestoreStep::CreateDirectory(entry.path)),
+            EntryKind::File => {
+                let data = if is_tar(source) {
+                    match files.remove(entry.path.trim_start_matches("/")) {
+                        Some(data) => data,
+                        None => {
+                            return Err(PolarError::new(format!(
+                                "File '{}' is missing from the backup",
+                                entry.path
+                            )))
+                        }
+                    }
+                } else {
+                    read_local(&local_path(source, &entry.path)?)?
+                };
+
+                if data.len() != entry.size {
+                    return Err(PolarError::new(format!(
+                        "File '{}' should have {} bytes but has {}",
+                        entry.path,
+                        entry.size,
+                        data.len()
+                    )));
+                }
+
+                steps.push(RestoreStep::UploadFile {
+                    path: entry.path,
+                    data,
+                });
+            }
+        }
+    }
+
+    Ok(steps)
+}
+
+// Everything under root, kept in memory so that it can be put back with `restore`
+pub fn snapshot(watch: &mut PolarWatch, root: &str) -> Result<Vec<RestoreStep>, PolarError> {
+    let entries = watch
+        .walk(root)
+        .collect::<Result<Vec<DirEntry>, PolarError>>()?;
+
+    let mut steps = vec![];
+    for entry in entries {
+        if entry.is_directory {
+            steps.push(RestoreStep::CreateDirectory(entry.path));
+        } else {
+            let data = watch.get_file(entry.path.clone())?;
+            steps.push(RestoreStep::UploadFile {
+                path: entry.path,
+                data,
+            });
+        }
+    }
+
+    Ok(steps)
+}
+
+// Directories in entries that the snapshot doesn't have, leaving out the ones inside another new
+// directory since deleting that one takes them too
+pub fn new_directories(snapshot: &[RestoreStep], entries: &[DirEntry]) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+
+    for entry in entries.iter().filter(|entry| entry.is_directory) {
+        let known = snapshot.iter().any(|step| match step {
+            RestoreStep::CreateDirectory(path) => *path == entry.path,
+            RestoreStep::UploadFile { .. } => false,
+        });
+        let inside_new = result
+            .iter()
+            .any(|path| entry.path.starts_with(path.as_str()));
+
+        if !known && !inside_new {
+            result.push(entry.path.clone());
+        }
+    }
+
+    result
+}
+
+pub fn restore(watch: &mut PolarWatch, steps: &[RestoreStep]) -> Result<(), PolarError> {
+    for step in steps {
+        info!("Restoring: {}", step.describe());
+
+        match step {
+            RestoreStep::CreateDirectory(path) => {
+                // It's okay if this fails, directory might already exist
+                let _ = watch.mkdir(path.clone());
+            }
+            RestoreStep::UploadFile { path, data } => watch.send_file(path.clone(), data)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            entries: vec![
+                ManifestEntry {
+                    kind: EntryKind::Directory,
+                    path: "/U/0/FAV/".into(),
+                    size: 0,
+                },
+                ManifestEntry {
+                    kind: EntryKind::File,
+                    path: "/U/0/FAV/00/TST.BPB".into(),
+                    size: 3,
+                },
+            ],
+        }
+    }
+
+    // An empty directory for each test, under the system's temporary directory
+    fn scratch(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("polar-backup-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let text = manifest().to_text();
+        assert_eq!(text, "d 0 /U/0/FAV/\nf 3 /U/0/FAV/00/TST.BPB\n");
+        assert_eq!(Manifest::parse(&text).unwrap(), manifest());
+
+        // Paths may have spaces, everything after the size is the path
+        let spaced = Manifest::parse("f 1 /U/0/A B.BPB\n\n").unwrap();
+        assert_eq!(spaced.entries[0].path, "/U/0/A B.BPB");
+
+        assert!(Manifest::parse("x 0 /U/0/").is_err());
+        assert!(Manifest::parse("f big /U/0/A").is_err());
+        assert!(Manifest::parse("f 0").is_err());
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let mut archive = vec![];
+        let mut writer = tar::TarWriter::new(&mut archive);
+        writer.add_directory("U/0/FAV").unwrap();
+        writer.add_file("U/0/FAV/00/TST.BPB", &[1, 2, 3]).unwrap();
+        writer.add_file("U/0/EMPTY", &[]).unwrap();
+        writer.add_file("U/0/BLOCK", &[7; 512]).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(archive.len() % 512, 0);
+        assert_eq!(
+            tar::read_files(archive.as_slice()).unwrap(),
+            vec![
+                ("U/0/FAV/00/TST.BPB".to_string(), vec![1, 2, 3]),
+                ("U/0/EMPTY".to_string(), vec![]),
+                ("U/0/BLOCK".to_string(), vec![7; 512]),
+            ]
+        );
+
+        let long = "a".repeat(100);
+        assert!(tar::TarWriter::new(vec![]).add_file(&long, &[]).is_err());
+
+        // A size far past the end of the archive is an error, not a huge allocation
+        let mut corrupt = archive[..1024].to_vec();
+        corrupt[124..136].copy_from_slice(b"77777777777\0");
+        assert!(tar::read_files(corrupt.as_slice()).is_err());
+        assert!(tar::read_files(&archive[..600]).is_err());
+    }
+
+    #[test]
+    fn new_directories_are_found_outermost_first() {
+        let directory = |path: &str| DirEntry {
+            path: path.to_string(),
+            name: String::new(),
+            is_directory: path.ends_with('/'),
+            size: 0,
+            created: None,
+            modified: None,
+        };
+        let snapshot = vec![
+            RestoreStep::CreateDirectory("/U/0/FAV/00/".into()),
+            RestoreStep::UploadFile {
+                path: "/U/0/FAV/00/TST.BPB".into(),
+                data: vec![],
+            },
+        ];
+        let entries = vec![
+            directory("/U/0/FAV/00/"),
+            directory("/U/0/FAV/00/TST.BPB"),
+            directory("/U/0/FAV/01/"),
+            directory("/U/0/FAV/01/EXTRA/"),
+            directory("/U/0/FAV/02/"),
+        ];
+
+        assert_eq!(
+            new_directories(&snapshot, &entries),
+            vec!["/U/0/FAV/01/".to_string(), "/U/0/FAV/02/".to_string()]
+        );
+        assert!(new_directories(&snapshot, &entries[..2]).is_empty());
+    }
+
+    #[test]
+    fn plan_restore_checks_sizes() {
+        let directory = scratch("directory");
+        let sink = &mut DirectorySink {
+            base: directory.clone(),
+        };
+        sink.add_directory("/U/0/FAV/").unwrap();
+        sink.add_directory("/U/0/FAV/00/").unwrap();
+        sink.add_file("/U/0/FAV/00/TST.BPB", &[1, 2, 3]).unwrap();
+        sink.finish(&manifest()).unwrap();
+
+        assert_eq!(
+            plan_restore(&directory).unwrap(),
+            vec![
+                RestoreStep::CreateDirectory("/U/0/FAV/".into()),
+                RestoreStep::UploadFile {
+                    path: "/U/0/FAV/00/TST.BPB".into(),
+                    data: vec![1, 2, 3],
+                },
+            ]
+        );
+
+        std::fs::write(
+            local_path(&directory, "/U/0/FAV/00/TST.BPB").unwrap(),
+            [1, 2],
+        )
+        .unwrap();
+        assert!(plan_restore(&directory).is_err());
+
+        // Nothing outside the backup is read, whatever the manifest says
+        let mut escaping = manifest();
+        escaping.entries[1].path = "/U/0/../../../TST.BPB".into();
+        sink.finish(&escaping).unwrap();
+        assert!(plan_restore(&directory).is_err());
+        assert!(sink.add_file("/U/./TST.BPB", &[1]).is_err());
+
+        let archive = scratch("archive").join("backup.tar");
+        let mut sink = TarSink {
+            path: archive.clone(),
+            writer: tar::TarWriter::new(std::fs::File::create(&archive).unwrap()),
+        };
+        sink.add_directory("/U/0/FAV/").unwrap();
+        sink.add_file("/U/0/FAV/00/TST.BPB", &[1, 2, 3, 4]).unwrap();
+        sink.finish(&manifest()).unwrap();
+        drop(sink);
+
+        assert!(plan_restore(&archive).is_err());
+
+        let _ = std::fs::remove_dir_all(directory);
+        let _ = std::fs::remove_dir_all(archive.parent().unwrap());
+    }
+}
@@ -0,0 +1,127 @@
+// Minimal ustar reader and writer, just enough for backups of the watch filesystem
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+const BLOCK_SIZE: usize = 512;
+
+pub struct TarWriter<W: Write> {
+    writer: W,
+}
+
+fn write_octal(field: &mut [u8], value: usize) {
+    // Octal digits, padded with zeros, followed by a NUL
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[field.len() - 1] = 0;
+}
+
+fn header(name: &str, size: usize, is_directory: bool) -> Result<[u8; BLOCK_SIZE]> {
+    if name.len() >= 100 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Path '{}' is too long for a tar archive", name),
+        ));
+    }
+
+    let mut block = [0u8; BLOCK_SIZE];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(
+        &mut block[100..108],
+        if is_directory { 0o755 } else { 0o644 },
+    );
+    write_octal(&mut block[108..116], 0);
+    write_octal(&mut block[116..124], 0);
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], 0);
+    block[156] = if is_directory { b'5' } else { b'0' };
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    for byte in &mut block[148..156] {
+        *byte = b' ';
+    }
+    let checksum: usize = block.iter().map(|byte| *byte as usize).sum();
+    write_octal(&mut block[148..155], checksum);
+    block[155] = b' ';
+
+    Ok(block)
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(writer: W) -> TarWriter<W> {
+        TarWriter { writer }
+    }
+
+    pub fn add_directory(&mut self, path: &str) -> Result<()> {
+        let mut name = path.to_string();
+        if !name.ends_with("/") {
+            name += "/";
+        }
+
+        self.writer.write_all(&header(&name, 0, true)?)
+    }
+
+    pub fn add_file(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.writer.write_all(&header(path, data.len(), false)?)?;
+        self.writer.write_all(data)?;
+
+        let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        self.writer.write_all(&vec![0u8; padding])
+    }
+
+    // Writes the two empty blocks that mark the end of the archive
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer.write_all(&[0u8; 2 * BLOCK_SIZE])?;
+        self.writer.flush()
+    }
+}
+
+fn read_octal(field: &[u8]) -> Result<usize> {
+    let text: String = field
+        .iter()
+        .take_while(|byte| **byte != 0 && **byte != b' ')
+        .map(|byte| *byte as char)
+        .collect();
+
+    usize::from_str_radix(text.trim(), 8)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid number in tar header"))
+}
+
+// Returns the name and content of every regular file in the archive
+pub fn read_files<R: Read>(mut reader: R) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut files = vec![];
+    let mut block = [0u8; BLOCK_SIZE];
+
+    loop {
+        reader.read_exact(&mut block)?;
+
+        if block.iter().all(|byte| *byte == 0) {
+            return Ok(files);
+        }
+
+        let name_end = block[..100]
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(100);
+        let name = String::from_utf8_lossy(&block[..name_end]).to_string();
+        let size = read_octal(&block[124..136])?;
+        let is_file = block[156] == b'0' || block[156] == 0;
+
+        // The size comes from the archive, so the buffer only grows with what is really there
+        let mut data = vec![];
+        reader.by_ref().take(size as u64).read_to_end(&mut data)?;
+        if data.len() != size {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("'{}' is cut short in the archive", name),
+            ));
+        }
+
+        let padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+        reader.read_exact(&mut block[..padding])?;
+
+        if is_file {
+            files.push((name, data));
+        }
+    }
+}
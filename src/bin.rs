@@ -2,9 +2,18 @@ extern crate nfd;
 extern crate polar_send_training;
 
 use log::{error, info};
-use polar_send_training::polar_watch::PolarError;
-use polar_send_training::{upload_favourites, VERSION};
+use polar_send_training::compliance::{self, ComplianceOptions};
+use polar_send_training::exercise::Recording;
+use polar_send_training::polar_watch::{
+    encode, Device, HeartRateZones, PhysicalData, PolarError, PolarWatch, UsbConfig,
+};
+use polar_send_training::templates::{self, Plan};
+use polar_send_training::zones::{Athlete, Intensity};
+use polar_send_training::{backup, config, formats, shell, sync};
+use polar_send_training::{human_duration, read_favourites, upload_favourites_to};
+use polar_send_training::{UploadOptions, VERSION};
 use simplelog::*;
+use std::time::Duration;
 
 // Pauses the program and waits for the user to press enter
 fn pause(message: &str) {
@@ -23,9 +32,9 @@ fn pause(message: &str) {
 }
 
 // Initializes logging to a file if possible, or to the console if not
-pub fn init_logger() {
-    let log_filename = "polar-send-training.log";
-    let log_level = LevelFilter::Info;
+pub fn init_logger(settings: &config::Config) {
+    let log_filename = &settings.log_file;
+    let log_level = settings.log_level;
     let log_config = Config::default();
 
     let logger_file = std::fs::OpenOptions::new()
@@ -47,20 +56,792 @@ pub fn init_logger() {
     let _ = CombinedLogger::init(vec![logger]);
 }
 
+fn report(result: Result<(), PolarError>, success: &str) {
+    match result {
+        Err(PolarError::LibusbError { error }) => println!("Something went wrong\n\t{:?}\n", error),
+        Err(PolarError::PolarError { error }) => println!("Something went wrong\n\t{:?}\n", error),
+        Err(PolarError::DeviceError { error, .. }) => {
+            println!("Something went wrong\n\t{:?}\n", error)
+        }
+        Err(PolarError::MismatchError { error }) => println!("Something went wrong\n\t{}\n", error),
+        Err(error) => println!("Something went wrong\n\t{:?}\n", error),
+        _ => println!("\n{}\n", success),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, PolarError> {
+    value
+        .parse()
+        .map_err(|_| PolarError::new(format!("Invalid value '{}' for {}", value, flag)))
+}
+
+fn parse_seconds(flag: &str, value: &str) -> Result<Duration, PolarError> {
+    Duration::try_from_secs_f64(parse_number(flag, value)?).map_err(|_| {
+        PolarError::new(format!(
+            "Invalid value '{}' for {}, expected a number of seconds",
+            value, flag
+        ))
+    })
+}
+
+fn parse_ftp(value: &str) -> Result<f32, PolarError> {
+    match parse_number::<f32>("--ftp", value)? {
+        ftp if ftp.is_finite() && ftp > 0.0 => Ok(ftp),
+        _ => Err(PolarError::new(format!(
+            "Invalid value '{}' for --ftp, expected watts above 0",
+            value
+        ))),
+    }
+}
+
+// Flags that change how we talk to the watch, accepted by every command. Returns the other args
+fn parse_usb_args(
+    mut config: UsbConfig,
+    args: Vec<String>,
+) -> Result<(UsbConfig, Vec<String>), PolarError> {
+    let mut rest = vec![];
+
+    for arg in args {
+        let (flag, value) = match arg.find('=') {
+            Some(index) => (&arg[..index], &arg[index + 1..]),
+            None => (arg.as_str(), ""),
+        };
+
+        match flag {
+            "--timeout" => {
+                config.read_timeout = parse_seconds(flag, value)?;
+                config.write_timeout = config.read_timeout;
+            }
+            "--read-timeout" => config.read_timeout = parse_seconds(flag, value)?,
+            "--write-timeout" => config.write_timeout = parse_seconds(flag, value)?,
+            "--packet-size" => config.packet_size = parse_number(flag, value)?,
+            "--interface" => config.interface = parse_number(flag, value)?,
+            "--in-endpoint" => config.in_endpoint = parse_number(flag, value)?,
+            "--out-endpoint" => config.out_endpoint = parse_number(flag, value)?,
+            "--no-detach" => config.detach_kernel_driver = false,
+            "--trace" => config.trace = true,
+            "--capture" => config.capture = Some(value.into()),
+            _ => rest.push(arg),
+        }
+    }
+
+    config.validate()?;
+    Ok((config, rest))
+}
+
+// Settings from the config file with the command line flags on top, for the model of the watch if
+// it is known
+fn parse_options(
+    settings: &config::Config,
+    model: Option<&str>,
+    args: Vec<String>,
+) -> Result<(UploadOptions, Vec<String>), PolarError> {
+    let options = settings.upload_options(model);
+    let (usb, args) = parse_usb_args(options.usb.clone(), args)?;
+
+    parse_upload_args(UploadOptions { usb, ..options }, args)
+}
+
+// Replaces --set=<name> with the files of that favourite set
+fn expand_sets(settings: &config::Config, args: Vec<String>) -> Result<Vec<String>, PolarError> {
+    let mut result = vec![];
+
+    for arg in args {
+        match arg.strip_prefix("--set=") {
+            Some(name) => match settings.sets.get(name) {
+                Some(files) => result.extend(files.iter().cloned()),
+                None => {
+                    return Err(PolarError::new(format!(
+                        "There is no favourite set named '{}' in the config file",
+                        name
+                    )))
+                }
+            },
+            None => result.push(arg),
+        }
+    }
+
+    Ok(result)
+}
+
+// What every command needs to connect to a watch
+struct Setup {
+    settings: config::Config,
+    // Options before knowing the watch model
+    options: UploadOptions,
+    // The whole command line, applied again over the settings of the model
+    flags: Vec<String>,
+}
+
+impl Setup {
+    // Applies the settings for the model of an open watch
+    fn configure(&self, watch: &mut PolarWatch) -> Result<UploadOptions, PolarError> {
+        let model = watch.product_name();
+        info!(
+            "Connected to {:?}, serial {:?}",
+            model,
+            watch.serial_number()
+        );
+
+        let (options, _) = parse_options(&self.settings, model.as_deref(), self.flags.clone())?;
+        watch.set_usb_config(&options.usb)?;
+        watch.set_verification(options.verification);
+
+        Ok(options)
+    }
+
+    // The profile in the config file, with --ftp from the command line
+    fn athlete(&self) -> Athlete {
+        self.options.import.athlete.clone()
+    }
+
+    fn open(&self, device: Device) -> Result<(PolarWatch, UploadOptions), PolarError> {
+        let mut watch = PolarWatch::open(device, &self.options.usb)?;
+        let options = self.configure(&mut watch)?;
+
+        Ok((watch, options))
+    }
+
+    fn connect(&self) -> Result<(PolarWatch, UploadOptions), PolarError> {
+        let mut context = rusb::Context::new()?;
+        let options = &self.options;
+
+        let mut watch = if options.wait_for_device {
+            println!("Waiting for a watch to be connected...");
+            PolarWatch::wait_for_device(&mut context, &options.usb, options.wait_timeout)?
+        } else {
+            match &self.settings.serial {
+                Some(serial) => PolarWatch::find_preferred(&mut context, &options.usb, serial)?,
+                None => PolarWatch::find_one_with(&mut context, &options.usb)?,
+            }
+        };
+
+        let options = self.configure(&mut watch)?;
+        Ok((watch, options))
+    }
+}
+
+// polar_send_training config show
+fn run_config(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("show") => {
+            match config::Config::default_path() {
+                Some(path) => println!("# {}", path.display()),
+                None => println!("# No config directory on this platform"),
+            }
+            print!("{}", setup.settings.to_text());
+
+            Ok(())
+        }
+        _ => Err(PolarError::new("Usage: config show")),
+    }
+}
+
+// polar_send_training backup <directory or file.tar> [watch path]
+fn run_backup(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let destination = match args.first() {
+        Some(destination) => destination,
+        None => {
+            return Err(PolarError::new(
+                "Usage: backup <directory or file.tar> [watch path]",
+            ))
+        }
+    };
+    let root = args
+        .get(1)
+        .map(|root| root.as_str())
+        .unwrap_or(backup::DEFAULT_ROOT);
+
+    let (mut watch, _) = setup.connect()?;
+
+    let manifest = backup::backup(&mut watch, root, destination)?;
+    println!(
+        "Saved {} entries to {}",
+        manifest.entries.len(),
+        destination
+    );
+
+    Ok(())
+}
+
+// polar_send_training restore <directory or file.tar> [--dry-run]
+fn run_restore(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let source = match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(source) => source,
+        None => {
+            return Err(PolarError::new(
+                "Usage: restore <directory or file.tar> [--dry-run]",
+            ))
+        }
+    };
+
+    let steps = backup::plan_restore(source)?;
+
+    if dry_run {
+        for step in &steps {
+            println!("{}", step.describe());
+        }
+
+        return Ok(());
+    }
+
+    let (mut watch, _) = setup.connect()?;
+
+    backup::restore(&mut watch, &steps)
+}
+
+// polar_send_training du [watch path]
+fn run_disk_usage(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let path = args
+        .first()
+        .map(|path| path.as_str())
+        .unwrap_or(backup::DEFAULT_ROOT);
+
+    let (mut watch, _) = setup.connect()?;
+
+    println!("{}: {}", path, watch.disk_usage(path)?);
+
+    Ok(())
+}
+
+// polar_send_training dissector [file.lua]
+fn run_dissector(args: &[String]) -> Result<(), PolarError> {
+    let dissector = PolarWatch::lua_dissector();
+
+    match args.first() {
+        Some(path) => std::fs::write(path, dissector)
+            .map_err(|error| PolarError::new(format!("Failed to write '{}'\n\t{:?}", path, error))),
+        None => {
+            print!("{}", dissector);
+            Ok(())
+        }
+    }
+}
+
+// polar_send_training convert <workout> <file.BPB, .fit, .tcx or .txt>
+fn run_convert(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let (input, output) = match args {
+        [input, output] => (input, output),
+        _ => {
+            return Err(PolarError::new(
+                "Usage: polar_send_training convert <workout> <file.BPB, .fit, .tcx or .txt>",
+            ))
+        }
+    };
+
+    let data = formats::read_session_with(input, &setup.options.import)?;
+    formats::write_session(output, &data)
+}
+
+// polar_send_training timeline <workout>
+fn run_timeline(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let path = match args {
+        [path] => path,
+        _ => {
+            return Err(PolarError::new(
+                "Usage: polar_send_training timeline <workout>",
+            ))
+        }
+    };
+
+    let data = formats::read_session_with(path, &setup.options.import)?;
+    let session = formats::decode_session(&data)?;
+    let blocks = formats::session_blocks(&session)?;
+
+    let offset =
+        |offset: Option<Duration>| offset.map(human_duration).unwrap_or_else(|| "?".into());
+
+    println!(
+        "{:>8} {:>8} {:>3}  {:<16} {:<10} {:<12} Target",
+        "Start", "End", "#", "Phase", "Repeat", "Goal"
+    );
+    for entry in formats::timeline::timeline(&blocks) {
+        let repetitions: Vec<String> = entry
+            .repetitions
+            .iter()
+            .map(|(repetition, times)| format!("{}/{}", repetition, times))
+            .collect();
+
+        println!(
+            "{:>8} {:>8} {:>3}  {:<16} {:<10} {:<12} {}",
+            offset(entry.start),
+            offset(entry.end),
+            entry.phase,
+            entry.name,
+            repetitions.join(" "),
+            entry.goal.to_string(),
+            entry.target
+        );
+    }
+
+    Ok(())
+}
+
+// polar_send_training generate <template> <file.BPB, .fit, .tcx or .txt> [--name=<name>]
+//     [--sport=running|cycling|<id>] [--warm-up=10m] [--cool-down=5m] [--work=hr:4]
+//     [--recovery=hr:1] [--easy=hr:2]
+fn run_generate(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let mut name = None;
+    let mut sport = None;
+    let mut warm_up = None;
+    let mut cool_down = None;
+    let mut work = None;
+    let mut recovery = None;
+    let mut easy = None;
+    let mut paths = vec![];
+
+    // Zones and paces become phase targets with the profile in the config file
+    let athlete = setup.athlete();
+
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("--name=") {
+            name = Some(value.to_string());
+        } else if let Some(value) = arg.strip_prefix("--sport=") {
+            sport = Some(match value {
+                "running" => formats::SPORT_RUNNING,
+                "cycling" => formats::SPORT_CYCLING,
+                id => parse_number("--sport", id)?,
+            });
+        } else if let Some(value) = arg.strip_prefix("--warm-up=") {
+            warm_up = Some(templates::parse_duration(value)?);
+        } else if let Some(value) = arg.strip_prefix("--cool-down=") {
+            cool_down = Some(templates::parse_duration(value)?);
+        } else if let Some((flag, value)) =
+            arg.strip_prefix("--").and_then(|arg| arg.split_once('='))
+        {
+            let target = match flag {
+                "work" => &mut work,
+                "recovery" => &mut recovery,
+                "easy" => &mut easy,
+                _ => return Err(PolarError::new(format!("Unknown option '--{}'", flag))),
+            };
+            *target = Some(value.parse::<Intensity>()?.resolve(&athlete)?);
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    let (template, output) = match paths.as_slice() {
+        [template, output] => (template, output),
+        _ => {
+            return Err(PolarError::new(
+                "Usage: polar_send_training generate <template> <file.BPB, .fit, .tcx or .txt> [--name=<name>] [--sport=running|cycling|<id>] [--warm-up=<duration>] [--cool-down=<duration>] [--work=<target>] [--recovery=<target>] [--easy=<target>]",
+            ))
+        }
+    };
+
+    let mut plan = Plan::new(name.as_deref().unwrap_or(template), template.parse()?);
+    plan.sport = sport;
+    plan.warm_up = warm_up;
+    plan.cool_down = cool_down;
+    if let Some(target) = work {
+        plan.work_target = target;
+    }
+    if let Some(target) = recovery {
+        plan.recovery_target = target;
+    }
+    if let Some(target) = easy {
+        plan.easy_target = target;
+    }
+
+    let data = encode(plan.session()?)
+        .map_err(|error| PolarError::new(format!("Failed to encode session: {:?}", error)))?;
+    formats::write_session(output, &data)
+}
+
+// polar_send_training profile show
+// polar_send_training profile set <name>=<value>...
+fn run_profile(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let usage = || {
+        PolarError::new(
+            "Usage: polar_send_training profile show, or profile set <name>=<value>... with names birthday, gender, weight, height, max_hr, resting_hr, aerobic_threshold, anaerobic_threshold, vo2max, ftp and hr_zones.<sport id>",
+        )
+    };
+
+    let mut changes = PhysicalData::default();
+    let mut zone_changes = vec![];
+    match args.split_first() {
+        Some((command, [])) if command == "show" => (),
+        Some((command, settings)) if command == "set" && !settings.is_empty() => {
+            for setting in settings {
+                let (name, value) = setting.split_once('=').ok_or_else(usage)?;
+                match name.trim().strip_prefix("hr_zones.") {
+                    Some(sport) => zone_changes.push((
+                        parse_number::<u64>("the sport id", sport)?,
+                        value.parse::<HeartRateZones>()?,
+                    )),
+                    None => changes.set_field(name.trim(), value)?,
+                }
+            }
+        }
+        _ => return Err(usage()),
+    }
+
+    let (mut watch, _) = setup.connect()?;
+    if changes != PhysicalData::default() {
+        watch.update_physical_data(&changes)?;
+    }
+    for (sport, zones) in &zone_changes {
+        watch.set_heart_rate_zones(*sport, zones)?;
+    }
+
+    print!("{}", watch.physical_data()?.to_text());
+
+    // The zone limits are kept with each sport profile
+    for profile in watch.sport_profiles()? {
+        if let (Some(sport), Some(zones)) = (profile.sport_id, &profile.heart_rate_zones) {
+            println!("hr_zones.{} = {}", sport, zones);
+        }
+    }
+    Ok(())
+}
+
+// polar_send_training sports list
+// polar_send_training sports add <sport id> [--from=<sport id>]
+// polar_send_training sports remove <sport id>
+// polar_send_training sports move <sport id> <position>
+fn run_sports(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let usage = || {
+        PolarError::new(
+            "Usage: polar_send_training sports list, sports add <sport id> [--from=<sport id>], sports remove <sport id> or sports move <sport id> <position from 1>",
+        )
+    };
+    let sport = |value: &String| parse_number::<u64>("the sport id", value);
+
+    let (command, args) = args.split_first().ok_or_else(usage)?;
+    let (mut watch, _) = setup.connect()?;
+
+    match (command.as_str(), args) {
+        ("list", []) => (),
+        ("add", [sport_id]) => {
+            watch.add_sport_profile(sport(sport_id)?, None)?;
+        }
+        ("add", [sport_id, from]) => {
+            let from = from.strip_prefix("--from=").ok_or_else(usage)?;
+            watch.add_sport_profile(sport(sport_id)?, Some(parse_number("--from", from)?))?;
+        }
+        ("remove", [sport_id]) => watch.remove_sport_profile(sport(sport_id)?)?,
+        ("move", [sport_id, position]) => {
+            let position: usize = parse_number("the position", position)?;
+            if position == 0 {
+                return Err(PolarError::new("Positions start from 1"));
+            }
+            watch.move_sport_profile(sport(sport_id)?, position - 1)?;
+        }
+        _ => return Err(usage()),
+    }
+
+    for profile in watch.sport_profiles()? {
+        println!("{}", profile);
+    }
+    Ok(())
+}
+
+// polar_send_training zones
+fn run_zones(setup: &Setup) -> Result<(), PolarError> {
+    print!("{}", setup.athlete().describe_zones());
+    Ok(())
+}
+
+// polar_send_training report <exercise directory> <workout> [--max-hr=<bpm>]
+//     [--format=text|csv|json] [output file]
+fn run_report(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let mut options = ComplianceOptions {
+        max_heart_rate: setup.settings.max_heart_rate,
+        ftp: setup.options.import.athlete.ftp,
+    };
+    let mut format = None;
+    let mut paths = vec![];
+
+    for arg in args {
+        if let Some(bpm) = arg.strip_prefix("--max-hr=") {
+            options.max_heart_rate = Some(parse_number("--max-hr", bpm)?);
+        } else if let Some(name) = arg.strip_prefix("--format=") {
+            format = Some(name.to_lowercase());
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    let (exercise, workout, output) = match paths.as_slice() {
+        [exercise, workout] => (exercise, workout, None),
+        [exercise, workout, output] => (exercise, workout, Some(output)),
+        _ => {
+            return Err(PolarError::new(
+                "Usage: polar_send_training report <exercise directory> <workout> [--max-hr=<bpm>] [--format=text|csv|json] [output file]",
+            ))
+        }
+    };
+
+    // Without --format, the extension of the output file decides
+    let format = format
+        .or_else(|| {
+            output
+                .and_then(|path| std::path::Path::new(path).extension())
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+        })
+        .unwrap_or_else(|| "text".to_string());
+
+    let data = formats::read_session_with(workout, &setup.options.import)?;
+    let blocks = formats::session_blocks(&formats::decode_session(&data)?)?;
+    let recording = Recording::load(exercise)?;
+    let report = compliance::compare(&blocks, &recording, &options);
+
+    let text = match format.as_str() {
+        "csv" => report.to_csv(),
+        "json" => report.to_json(),
+        "text" | "txt" => report.to_text(),
+        _ => {
+            return Err(PolarError::new(format!(
+                "Unknown report format '{}', use text, csv or json",
+                format
+            )))
+        }
+    };
+
+    match output {
+        Some(path) => std::fs::write(path, text)
+            .map_err(|error| PolarError::new(format!("Failed to write '{}'\n\t{:?}", path, error))),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+// polar_send_training shell
+fn run_shell(setup: &Setup) -> Result<(), PolarError> {
+    let (watch, _) = setup.connect()?;
+    shell::run_shell(watch)
+}
+
+// Runs command through the shell, telling it which watch was connected
+fn run_command(command: &str, device: &Device) -> Result<(), PolarError> {
+    let mut process = if cfg!(windows) {
+        let mut process = std::process::Command::new("cmd");
+        process.arg("/C");
+        process
+    } else {
+        let mut process = std::process::Command::new("sh");
+        process.arg("-c");
+        process
+    };
+
+    let status = process
+        .arg(command)
+        .env("POLAR_BUS", device.bus_number().to_string())
+        .env("POLAR_ADDRESS", device.address().to_string())
+        .status()
+        .map_err(|error| PolarError::new(format!("Failed to run '{}'\n\t{}", command, error)))?;
+
+    if !status.success() {
+        return Err(PolarError::new(format!(
+            "'{}' failed with {}",
+            command, status
+        )));
+    }
+
+    Ok(())
+}
+
+// polar_send_training daemon --exec=<command> | [upload options] <files...>
+// Uploads the files, or runs the command, every time a watch gets connected
+fn run_daemon(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let mut command = None;
+    let mut paths = vec![];
+
+    for arg in args {
+        match arg.strip_prefix("--exec=") {
+            Some(value) => command = Some(value.to_string()),
+            None => paths.push(arg.clone()),
+        }
+    }
+
+    // The command runs instead of the upload, so files next to it would never be sent
+    if command.is_none() == paths.is_empty() {
+        return Err(PolarError::new(
+            "Usage: daemon --exec=<command>, or daemon [upload options] <files...>",
+        ));
+    }
+
+    // Read the files once, so that every watch gets the same favourites
+    let files = read_favourites(&paths, &setup.options.import)?;
+    let mut context = rusb::Context::new()?;
+
+    println!("Waiting for watches to be connected, press Ctrl+C to stop");
+
+    PolarWatch::for_each_connected(&mut context, &mut |device| {
+        println!(
+            "\nWatch connected on bus {} address {}",
+            device.bus_number(),
+            device.address()
+        );
+
+        let result = match &command {
+            Some(command) => run_command(command, &device),
+            None => setup.open(device).and_then(|(mut watch, options)| {
+                upload_favourites_to(&mut watch, &paths, &files, &options)
+            }),
+        };
+
+        report(result, "Done, waiting for the next watch.");
+        Ok(())
+    })
+}
+
+fn sync_watch(
+    watch: &mut PolarWatch,
+    archive: &str,
+    paths: &[String],
+    files: &[Vec<u8>],
+    options: &UploadOptions,
+) -> Result<(), PolarError> {
+    let synced = sync::sync_exercises(watch, archive)?;
+    println!("Downloaded {} new exercises into {}", synced.len(), archive);
+    for id in &synced {
+        println!("\t{}", id);
+    }
+
+    if !paths.is_empty() {
+        upload_favourites_to(watch, paths, files, options)?;
+    }
+
+    Ok(())
+}
+
+// polar_send_training sync <archive directory> [--once] [upload options] [favourite files...]
+// Downloads new exercises from every watch that gets connected, then uploads the favourites
+fn run_sync(args: &[String], setup: &Setup) -> Result<(), PolarError> {
+    let once = args.iter().any(|arg| arg == "--once");
+    let mut paths: Vec<String> = args
+        .iter()
+        .filter(|arg| *arg != "--once")
+        .cloned()
+        .collect();
+
+    if paths.is_empty() {
+        return Err(PolarError::new(
+            "Usage: sync <archive directory> [--once] [favourite files...]",
+        ));
+    }
+    let archive = paths.remove(0);
+
+    let files = read_favourites(&paths, &setup.options.import)?;
+    let mut context = rusb::Context::new()?;
+
+    if once {
+        let (mut watch, options) = setup.connect()?;
+        return sync_watch(&mut watch, &archive, &paths, &files, &options);
+    }
+
+    println!("Waiting for watches to be connected, press Ctrl+C to stop");
+
+    PolarWatch::for_each_connected(&mut context, &mut |device| {
+        println!(
+            "\nWatch connected on bus {} address {}",
+            device.bus_number(),
+            device.address()
+        );
+
+        let result = setup.open(device).and_then(|(mut watch, options)| {
+            sync_watch(&mut watch, &archive, &paths, &files, &options)
+        });
+
+        report(result, "Done, waiting for the next watch.");
+        Ok(())
+    })
+}
+
+// Options go before the files, like --verify=size or --wait=60
+fn parse_upload_args(
+    mut options: UploadOptions,
+    args: Vec<String>,
+) -> Result<(UploadOptions, Vec<String>), PolarError> {
+    let mut files = vec![];
+
+    for arg in args {
+        if let Some(verification) = arg.strip_prefix("--verify=") {
+            options.verification = verification.parse()?;
+        } else if let Some(ftp) = arg.strip_prefix("--ftp=") {
+            options.import.athlete.ftp = Some(parse_ftp(ftp)?);
+        } else if arg == "--no-sport-check" {
+            options.check_sports = false;
+        } else if arg == "--wait" {
+            options.wait_for_device = true;
+        } else if let Some(seconds) = arg.strip_prefix("--wait=") {
+            options.wait_for_device = true;
+            options.wait_timeout = Some(parse_seconds("--wait", seconds)?);
+        } else {
+            files.push(arg);
+        }
+    }
+
+    Ok((options, files))
+}
+
 fn fake_main() {
-    init_logger();
+    let settings = match config::Config::default_path() {
+        Some(path) => config::Config::load(path),
+        None => Ok(config::Config::default()),
+    };
+
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(error) => {
+            init_logger(&config::Config::default());
+            return report(Err(error), "");
+        }
+    };
+
+    init_logger(&settings);
     info!("Initalizing polar-send-training version {}", VERSION);
 
     // Skip program name
-    let mut files: Vec<String> = std::env::args().skip(1).collect();
+    let flags: Vec<String> = std::env::args().skip(1).collect();
+
+    let parsed = expand_sets(&settings, flags.clone())
+        .and_then(|flags| parse_options(&settings, None, flags));
+    let (options, args) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => return report(Err(error), ""),
+    };
+
+    let setup = Setup {
+        settings,
+        options,
+        flags,
+    };
+
+    match args.first().map(|command| command.as_str()) {
+        Some("backup") => return report(run_backup(&args[1..], &setup), "Backup finished."),
+        Some("restore") => return report(run_restore(&args[1..], &setup), "Restore finished."),
+        Some("config") => return report(run_config(&args[1..], &setup), "Done."),
+        Some("dissector") => return report(run_dissector(&args[1..]), "Done."),
+        Some("convert") => return report(run_convert(&args[1..], &setup), "Done."),
+        Some("timeline") => return report(run_timeline(&args[1..], &setup), "Done."),
+        Some("generate") => return report(run_generate(&args[1..], &setup), "Done."),
+        Some("zones") => return report(run_zones(&setup), "Done."),
+        Some("profile") => return report(run_profile(&args[1..], &setup), "Done."),
+        Some("report") => return report(run_report(&args[1..], &setup), "Done."),
+        Some("sports") => return report(run_sports(&args[1..], &setup), "Done."),
+        Some("shell") => return report(run_shell(&setup), "Bye."),
+        Some("du") => return report(run_disk_usage(&args[1..], &setup), "Done."),
+        Some("sync") => return report(run_sync(&args[1..], &setup), "Sync finished."),
+        Some("daemon") => return report(run_daemon(&args[1..], &setup), "Bye."),
+        _ => (),
+    }
+
+    let mut files = args;
 
     if files.is_empty() {
         info!("No files provided, asking user");
-        let result = nfd::open_file_multiple_dialog(Some("BPB"), None).unwrap_or_else(|error| {
-            error!("Failed to open file dialog: {:?}", error);
-            pause("Please close the program and try again.");
-            panic!(error);
-        });
+        let result = nfd::open_file_multiple_dialog(Some("BPB,zwo,fit,erg,mrc"), None)
+            .unwrap_or_else(|error| {
+                error!("Failed to open file dialog: {:?}", error);
+                pause("Please close the program and try again.");
+                panic!(error);
+            });
 
         match result {
             nfd::Response::Okay(file_path) => files = [file_path].to_vec(),
@@ -73,11 +854,15 @@ fn fake_main() {
     }
 
     info!("Uploading files {:?}", files);
-    match upload_favourites(files) {
-        Err(PolarError::LibusbError { error }) => println!("Something went wrong\n\t{:?}\n", error),
-        Err(PolarError::PolarError { error }) => println!("Something went wrong\n\t{:?}\n", error),
-        _ => println!("\nAll files were transfered successfully. Life is good :)\n"),
-    }
+    let result = read_favourites(&files, &setup.options.import).and_then(|data| {
+        let (mut watch, options) = setup.connect()?;
+        upload_favourites_to(&mut watch, &files, &data, &options)
+    });
+
+    report(
+        result,
+        "All files were transfered successfully. Life is good :)",
+    );
 }
 
 // We try to catch all possible errors in main, so that we can warn the user about them
@@ -92,3 +877,102 @@ fn main() {
 
     pause("You can now close the program.");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn usb_flags_are_taken_out_of_the_args() {
+        let (config, rest) = parse_usb_args(
+            UsbConfig::default(),
+            args(&[
+                "a.BPB",
+                "--timeout=2.5",
+                "--packet-size=32",
+                "--trace",
+                "b.BPB",
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(rest, args(&["a.BPB", "b.BPB"]));
+        assert_eq!(config.read_timeout, Duration::from_millis(2500));
+        assert_eq!(config.write_timeout, Duration::from_millis(2500));
+        assert_eq!(config.packet_size, 32);
+        assert!(config.trace);
+    }
+
+    #[test]
+    fn invalid_usb_flags_are_rejected() {
+        for arg in &[
+            "--timeout=-1",
+            "--timeout=nan",
+            "--read-timeout=inf",
+            "--write-timeout=1e30",
+            "--timeout=",
+            "--packet-size=65",
+            "--packet-size=big",
+            "--in-endpoint=256",
+        ] {
+            assert!(
+                parse_usb_args(UsbConfig::default(), args(&[arg])).is_err(),
+                "{}",
+                arg
+            );
+        }
+    }
+
+    #[test]
+    fn ftp_must_be_positive_watts() {
+        let (options, _) =
+            parse_upload_args(UploadOptions::default(), args(&["--ftp=250", "a.erg"])).unwrap();
+        assert_eq!(options.import.athlete.ftp, Some(250.0));
+
+        for arg in &["--ftp=0", "--ftp=-250", "--ftp=inf", "--ftp=NaN", "--ftp="] {
+            assert!(
+                parse_upload_args(UploadOptions::default(), args(&[arg])).is_err(),
+                "{}",
+                arg
+            );
+        }
+    }
+
+    #[test]
+    fn generate_rejects_unknown_options() {
+        let setup = Setup {
+            settings: config::Config::default(),
+            options: UploadOptions::default(),
+            flags: vec![],
+        };
+
+        for flag in &["--wrok=hr:4", "--speed=12"] {
+            let error = run_generate(&args(&["6x3m/2m", "out.BPB", flag]), &setup).unwrap_err();
+            assert!(
+                format!("{:?}", error).contains("Unknown option"),
+                "{}",
+                flag
+            );
+        }
+    }
+
+    #[test]
+    fn sports_are_checked_unless_turned_off() {
+        let (options, files) =
+            parse_upload_args(UploadOptions::default(), args(&["a.BPB"])).unwrap();
+        assert!(options.check_sports);
+        assert_eq!(files, args(&["a.BPB"]));
+
+        let (options, files) = parse_upload_args(
+            UploadOptions::default(),
+            args(&["--no-sport-check", "a.BPB"]),
+        )
+        .unwrap();
+        assert!(!options.check_sports);
+        assert_eq!(files, args(&["a.BPB"]));
+    }
+}
@@ -0,0 +1,411 @@
+// Compares a recorded exercise with the session it was started from. The phases are lined up with
+// the laps when there is one lap per phase, otherwise with the planned times
+use crate::exercise::Recording;
+use crate::formats::timeline::{timeline, TimelineEntry};
+use crate::formats::{heart_rate_percent, pace, power_percent, Block, Goal, Target};
+use std::time::Duration;
+
+// Needed to turn zones into bpm and watts, time in zone is unknown without them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ComplianceOptions {
+    pub max_heart_rate: Option<u32>,
+    pub ftp: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alignment {
+    Laps,
+    Plan,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseResult {
+    pub entry: TimelineEntry,
+    // When the phase was done, None when it couldn't be lined up
+    pub start: Option<Duration>,
+    pub end: Option<Duration>,
+    // None when the target is free, or when there are no samples to compare with
+    pub time_in_zone: Option<Duration>,
+    pub heart_rate: Option<f32>,
+    // km/h
+    pub speed: Option<f32>,
+    pub power: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub alignment: Alignment,
+    pub phases: Vec<PhaseResult>,
+}
+
+fn average<T: Copy + Into<f64>>(samples: &[T]) -> Option<f32> {
+    let values: Vec<f64> = samples
+        .iter()
+        .map(|sample| (*sample).into())
+        .filter(|value| *value > 0.0)
+        .collect();
+
+    if values.is_empty() {
+        None
+    } else {
+        Some((values.iter().sum::<f64>() / values.len() as f64) as f32)
+    }
+}
+
+fn clamp_range(range: std::ops::Range<usize>, length: usize) -> std::ops::Range<usize> {
+    range.start.min(length)..range.end.min(length)
+}
+
+// Samples of the target's kind and the limits of the target in the same units
+fn target_limits<'a>(
+    target: &Target,
+    recording: &'a Recording,
+    options: &ComplianceOptions,
+) -> Option<(Vec<f64>, f64, f64)> {
+    let as_f64 = |samples: &'a [u32]| samples.iter().map(|value| f64::from(*value)).collect();
+
+    match *target {
+        Target::Free => None,
+        Target::HeartRateZone { lower, upper } => {
+            let max = f64::from(options.max_heart_rate?);
+            Some((
+                as_f64(&recording.heart_rate),
+                max * f64::from(heart_rate_percent(lower, false)) / 100.0,
+                max * f64::from(heart_rate_percent(upper, true)) / 100.0,
+            ))
+        }
+        Target::Speed { lower, upper } => Some((
+            recording
+                .speed
+                .iter()
+                .map(|value| f64::from(*value))
+                .collect(),
+            f64::from(lower),
+            f64::from(upper),
+        )),
+        Target::PowerZone { lower, upper } => {
+            let ftp = f64::from(options.ftp?);
+            Some((
+                as_f64(&recording.power),
+                ftp * f64::from(power_percent(lower, false)) / 100.0,
+                ftp * f64::from(power_percent(upper, true)) / 100.0,
+            ))
+        }
+    }
+}
+
+fn time_in_zone(
+    target: &Target,
+    recording: &Recording,
+    range: std::ops::Range<usize>,
+    options: &ComplianceOptions,
+) -> Option<Duration> {
+    let (samples, low, high) = target_limits(target, recording, options)?;
+    let samples = &samples[clamp_range(range, samples.len())];
+    if samples.iter().all(|value| *value <= 0.0) {
+        return None;
+    }
+
+    let inside = samples
+        .iter()
+        .filter(|value| **value > 0.0 && **value >= low && **value <= high)
+        .count();
+
+    Some(recording.interval * inside as u32)
+}
+
+pub fn compare(blocks: &[Block], recording: &Recording, options: &ComplianceOptions) -> Report {
+    let entries: Vec<TimelineEntry> = timeline(blocks).collect();
+
+    // The watch may add one last lap when the exercise is stopped after the session
+    let laps = &recording.laps;
+    let alignment =
+        if !laps.is_empty() && (laps.len() == entries.len() || laps.len() == entries.len() + 1) {
+            Alignment::Laps
+        } else {
+            Alignment::Plan
+        };
+
+    let phases = entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let (start, end) = match alignment {
+                Alignment::Laps => (
+                    Some(match index {
+                        0 => Duration::from_secs(0),
+                        _ => laps[index - 1],
+                    }),
+                    Some(laps[index]),
+                ),
+                Alignment::Plan => (entry.start, entry.end),
+            };
+
+            let mut result = PhaseResult {
+                start,
+                end,
+                time_in_zone: None,
+                heart_rate: None,
+                speed: None,
+                power: None,
+                entry,
+            };
+
+            if let (Some(start), Some(end)) = (start, end) {
+                let range = recording.samples_between(start, end);
+                let samples = |length| clamp_range(range.clone(), length);
+
+                result.time_in_zone =
+                    time_in_zone(&result.entry.target, recording, range.clone(), options);
+                result.heart_rate =
+                    average(&recording.heart_rate[samples(recording.heart_rate.len())]);
+                result.speed = average(&recording.speed[samples(recording.speed.len())]);
+                result.power = average(&recording.power[samples(recording.power.len())]);
+            }
+
+            result
+        })
+        .collect();
+
+    Report { alignment, phases }
+}
+
+fn seconds(duration: Option<Duration>) -> Option<f64> {
+    duration.map(|duration| (duration.as_secs_f64() * 10.0).round() / 10.0)
+}
+
+fn planned(entry: &TimelineEntry) -> Option<Duration> {
+    match entry.goal {
+        Goal::Time(duration) => Some(duration),
+        _ => None,
+    }
+}
+
+fn repetitions(entry: &TimelineEntry) -> String {
+    entry
+        .repetitions
+        .iter()
+        .map(|(repetition, times)| format!("{}/{}", repetition, times))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn or_empty<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn json_string(text: &str) -> String {
+    let mut result = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => result += "\\\"",
+            '\\' => result += "\\\\",
+            '\n' => result += "\\n",
+            character if (character as u32) < 0x20 => {
+                result += &format!("\\u{:04x}", character as u32)
+            }
+            character => result.push(character),
+        }
+    }
+
+    result + "\""
+}
+
+fn json_value<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| "null".to_string())
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains(',') || text.contains('"') || text.contains('\n') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+impl Report {
+    pub fn to_text(&self) -> String {
+        let duration = |duration: Option<Duration>| {
+            duration
+                .map(crate::human_duration)
+                .unwrap_or_else(|| "-".to_string())
+        };
+
+        let mut result = format!(
+            "Phases lined up with the {}\n\n",
+            match self.alignment {
+                Alignment::Laps => "laps",
+                Alignment::Plan => "planned times",
+            }
+        );
+
+        result += &format!(
+            "{:>3}  {:<16} {:<8} {:>8} {:>8}  {:<16} {:>8} {:>6} {:>7} {:>6}\n",
+            "#", "Phase", "Repeat", "Planned", "Actual", "Target", "In zone", "HR", "Pace", "Power"
+        );
+
+        for phase in &self.phases {
+            let actual = match (phase.start, phase.end) {
+                (Some(start), Some(end)) if end >= start => Some(end - start),
+                _ => None,
+            };
+
+            result += &format!(
+                "{:>3}  {:<16} {:<8} {:>8} {:>8}  {:<16} {:>8} {:>6} {:>7} {:>6}\n",
+                phase.entry.phase,
+                phase.entry.name,
+                repetitions(&phase.entry),
+                duration(planned(&phase.entry)),
+                duration(actual),
+                phase.entry.target.to_string(),
+                duration(phase.time_in_zone),
+                phase
+                    .heart_rate
+                    .map_or("-".to_string(), |value| format!("{:.0}", value)),
+                phase.speed.map_or("-".to_string(), pace),
+                phase
+                    .power
+                    .map_or("-".to_string(), |value| format!("{:.0}", value)),
+            );
+        }
+
+        result
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut result = String::from(
+            "phase,name,repetition,planned_seconds,start_seconds,end_seconds,target,time_in_zone_seconds,average_heart_rate,average_speed_kmh,pace_per_km,average_power\n",
+        );
+
+        for phase in &self.phases {
+            let fields = [
+                phase.entry.phase.to_string(),
+                csv_field(&phase.entry.name),
+                repetitions(&phase.entry),
+                or_empty(seconds(planned(&phase.entry))),
+                or_empty(seconds(phase.start)),
+                or_empty(seconds(phase.end)),
+                csv_field(&phase.entry.target.to_string()),
+                or_empty(seconds(phase.time_in_zone)),
+                or_empty(phase.heart_rate.map(|value| value.round())),
+                or_empty(phase.speed.map(|value| (value * 100.0).round() / 100.0)),
+                or_empty(phase.speed.map(pace)),
+                or_empty(phase.power.map(|value| value.round())),
+            ];
+
+            result += &fields.join(",");
+            result += "\n";
+        }
+
+        result
+    }
+
+    pub fn to_json(&self) -> String {
+        let phases: Vec<String> = self
+            .phases
+            .iter()
+            .map(|phase| {
+                format!(
+                    "{{\"phase\":{},\"name\":{},\"repetition\":{},\"planned_seconds\":{},\"start_seconds\":{},\"end_seconds\":{},\"target\":{},\"time_in_zone_seconds\":{},\"average_heart_rate\":{},\"average_speed_kmh\":{},\"average_power\":{}}}",
+                    phase.entry.phase,
+                    json_string(&phase.entry.name),
+                    json_string(&repetitions(&phase.entry)),
+                    json_value(seconds(planned(&phase.entry))),
+                    json_value(seconds(phase.start)),
+                    json_value(seconds(phase.end)),
+                    json_string(&phase.entry.target.to_string()),
+                    json_value(seconds(phase.time_in_zone)),
+                    json_value(phase.heart_rate.map(|value| value.round())),
+                    json_value(phase.speed.map(|value| (value * 100.0).round() / 100.0)),
+                    json_value(phase.power.map(|value| value.round())),
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"alignment\":{},\"phases\":[{}]}}\n",
+            json_string(match self.alignment {
+                Alignment::Laps => "laps",
+                Alignment::Plan => "plan",
+            }),
+            phases.join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::step;
+
+    #[test]
+    fn phases_are_compared_with_the_recording() {
+        let minute = Duration::from_secs(60);
+        let blocks = vec![
+            step(
+                "Steady".to_string(),
+                minute,
+                Target::HeartRateZone { lower: 3, upper: 3 },
+            ),
+            step(
+                "Push".to_string(),
+                minute,
+                Target::PowerZone { lower: 2, upper: 2 },
+            ),
+        ];
+
+        // 140 bpm is zone 3 with a max of 200, 150 W is zone 2 with an FTP of 250
+        let mut recording = Recording {
+            interval: Duration::from_secs(1),
+            heart_rate: [vec![140; 60], vec![100; 60]].concat(),
+            speed: vec![12.0; 120],
+            power: [vec![0; 60], vec![150; 60]].concat(),
+            ..Recording::default()
+        };
+        let options = ComplianceOptions {
+            max_heart_rate: Some(200),
+            ftp: Some(250.0),
+        };
+
+        let report = compare(&blocks, &recording, &options);
+        assert_eq!(report.alignment, Alignment::Plan);
+        assert_eq!(report.phases[0].time_in_zone, Some(Duration::from_secs(60)));
+        assert_eq!(report.phases[0].heart_rate, Some(140.0));
+        assert_eq!(report.phases[0].power, None);
+        assert_eq!(report.phases[1].time_in_zone, Some(Duration::from_secs(60)));
+        assert_eq!(report.phases[1].heart_rate, Some(100.0));
+        assert_eq!(report.phases[1].power, Some(150.0));
+
+        // The first lap was pressed early
+        recording.laps = vec![Duration::from_secs(30), Duration::from_secs(120)];
+        let report = compare(&blocks, &recording, &options);
+        assert_eq!(report.alignment, Alignment::Laps);
+        assert_eq!(report.phases[0].time_in_zone, Some(Duration::from_secs(30)));
+        assert_eq!(report.phases[1].time_in_zone, Some(Duration::from_secs(60)));
+        assert_eq!(report.phases[1].heart_rate.map(f32::round), Some(113.0));
+
+        let csv = report.to_csv();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("1,Steady,,60,0,30,"));
+        assert!(report
+            .to_json()
+            .starts_with("{\"alignment\":\"laps\",\"phases\":[{\"phase\":1,"));
+        assert!(report.to_text().contains("5:00"));
+    }
+
+    #[test]
+    fn text_is_escaped_for_csv_and_json() {
+        assert_eq!(csv_field("Over, under"), "\"Over, under\"");
+        assert_eq!(csv_field("6\" hill"), "\"6\"\" hill\"");
+        assert_eq!(csv_field("Steady"), "Steady");
+
+        assert_eq!(json_string("a \"b\"\\\n"), "\"a \\\"b\\\"\\\\\\n\"");
+        assert_eq!(json_string("\u{1}"), "\"\\u0001\"");
+    }
+}
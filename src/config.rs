@@ -0,0 +1,419 @@
+// Settings read from config.ini in the platform config directory. Command line flags win over the
+// file. The format is one "key = value" per line, with sections for favourite sets and models:
+//
+//     log_file = /home/me/polar.log
+//     verify = size
+//
+//     [set.race-week]
+//     file = /home/me/intervals.BPB
+//     file = /home/me/recovery.BPB
+//
+//     [model.Polar M430]
+//     read_timeout = 10
+use crate::formats::ImportOptions;
+use crate::polar_watch::{PolarError, UsbConfig, Verification};
+use crate::zones::{self, Athlete, HeartRateModel};
+use crate::{UploadOptions, FAVOURITES_PATH};
+use log::LevelFilter;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const CONFIG_DIRECTORY: &str = "polar-send-training";
+pub const CONFIG_NAME: &str = "config.ini";
+pub const DEFAULT_LOG_FILE: &str = "polar-send-training.log";
+
+// Settings that can change depending on the watch model, None keeps the global value
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelOverrides {
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub packet_size: Option<usize>,
+    pub in_endpoint: Option<u8>,
+    pub out_endpoint: Option<u8>,
+    pub verification: Option<Verification>,
+    pub favourites_path: Option<String>,
+}
+
+impl ModelOverrides {
+    pub fn apply(&self, options: &mut UploadOptions) {
+        let usb = &mut options.usb;
+        usb.read_timeout = self.read_timeout.unwrap_or(usb.read_timeout);
+        usb.write_timeout = self.write_timeout.unwrap_or(usb.write_timeout);
+        usb.packet_size = self.packet_size.unwrap_or(usb.packet_size);
+        usb.in_endpoint = self.in_endpoint.unwrap_or(usb.in_endpoint);
+        usb.out_endpoint = self.out_endpoint.unwrap_or(usb.out_endpoint);
+
+        if let Some(verification) = self.verification {
+            options.verification = verification;
+        }
+
+        if let Some(path) = &self.favourites_path {
+            options.favourites_path = path.clone();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub log_file: PathBuf,
+    pub log_level: LevelFilter,
+    pub favourites_path: String,
+    pub verification: Verification,
+    pub wait_for_device: bool,
+    pub wait_timeout: Option<Duration>,
+    pub check_sports: bool,
+    // Used when more than one watch is connected
+    pub serial: Option<String>,
+    // In watts, for workouts with absolute power
+    pub ftp: Option<f32>,
+    // The athlete profile, for zones
+    pub max_heart_rate: Option<u32>,
+    pub resting_heart_rate: Option<u32>,
+    // Time per km
+    pub threshold_pace: Option<Duration>,
+    pub heart_rate_model: HeartRateModel,
+    pub sets: BTreeMap<String, Vec<String>>,
+    // By usb product name, like "Polar M430"
+    pub models: BTreeMap<String, ModelOverrides>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            log_file: PathBuf::from(DEFAULT_LOG_FILE),
+            log_level: LevelFilter::Info,
+            favourites_path: FAVOURITES_PATH.to_string(),
+            verification: Verification::default(),
+            wait_for_device: false,
+            wait_timeout: None,
+            check_sports: true,
+            serial: None,
+            ftp: None,
+            max_heart_rate: None,
+            resting_heart_rate: None,
+            threshold_pace: None,
+            heart_rate_model: HeartRateModel::default(),
+            sets: BTreeMap::new(),
+            models: BTreeMap::new(),
+        }
+    }
+}
+
+enum Section {
+    Global,
+    Set(String),
+    Model(String),
+}
+
+fn parse_value<T: std::str::FromStr>(line: usize, key: &str, value: &str) -> Result<T, PolarError> {
+    value.parse().map_err(|_| {
+        PolarError::new(format!(
+            "Line {}: invalid value '{}' for {}",
+            line, value, key
+        ))
+    })
+}
+
+fn parse_seconds(line: usize, key: &str, value: &str) -> Result<Duration, PolarError> {
+    let seconds: f64 = parse_value(line, key, value)?;
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        PolarError::new(format!(
+            "Line {}: {} must be a number of seconds, got '{}'",
+            line, key, value
+        ))
+    })
+}
+
+fn parse_ftp(line: usize, key: &str, value: &str) -> Result<f32, PolarError> {
+    match parse_value::<f32>(line, key, value)? {
+        ftp if ftp.is_finite() && ftp > 0.0 => Ok(ftp),
+        _ => Err(PolarError::new(format!(
+            "Line {}: {} must be in watts above 0, got '{}'",
+            line, key, value
+        ))),
+    }
+}
+
+fn parse_bool(line: usize, key: &str, value: &str) -> Result<bool, PolarError> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(PolarError::new(format!(
+            "Line {}: expected yes or no for {}, got '{}'",
+            line, key, value
+        ))),
+    }
+}
+
+fn with_slash(path: &str) -> String {
+    if path.ends_with('/') {
+        path.to_string()
+    } else {
+        path.to_string() + "/"
+    }
+}
+
+fn verification_name(verification: Verification) -> &'static str {
+    match verification {
+        Verification::None => "none",
+        Verification::Size => "size",
+        Verification::Full => "full",
+    }
+}
+
+impl Config {
+    // Where the config file is expected, None if the platform has no config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|path| path.join(CONFIG_DIRECTORY).join(CONFIG_NAME))
+    }
+
+    // A missing file gives the default settings
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, PolarError> {
+        let path = path.as_ref();
+
+        match std::fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text).map_err(|error| {
+                PolarError::new(format!(
+                    "Invalid config '{}'\n\t{}",
+                    path.display(),
+                    crate::error_message(&error)
+                ))
+            }),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(error) => Err(PolarError::new(format!(
+                "Failed to read '{}'\n\t{:?}",
+                path.display(),
+                error
+            ))),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, PolarError> {
+        let mut config = Config::default();
+        let mut section = Section::Global;
+
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let name = line[1..line.len() - 1].trim();
+
+                section = if let Some(set) = name.strip_prefix("set.") {
+                    config.sets.entry(set.to_string()).or_default();
+                    Section::Set(set.to_string())
+                } else if let Some(model) = name.strip_prefix("model.") {
+                    config.models.entry(model.to_string()).or_default();
+                    Section::Model(model.to_string())
+                } else {
+                    return Err(PolarError::new(format!(
+                        "Line {}: unknown section '{}', expected [set.<name>] or [model.<name>]",
+                        number, name
+                    )));
+                };
+
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(split) => (line[..split].trim(), line[split + 1..].trim()),
+                None => {
+                    return Err(PolarError::new(format!(
+                        "Line {}: expected 'key = value'",
+                        number
+                    )))
+                }
+            };
+
+            match &section {
+                Section::Global => match key {
+                    "log_file" => config.log_file = PathBuf::from(value),
+                    "log_level" => config.log_level = parse_value(number, key, value)?,
+                    "favourites_path" => config.favourites_path = with_slash(value),
+                    "verify" => config.verification = parse_value(number, key, value)?,
+                    "wait" => config.wait_for_device = parse_bool(number, key, value)?,
+                    "wait_timeout" => {
+                        config.wait_timeout = Some(parse_seconds(number, key, value)?)
+                    }
+                    "check_sports" => config.check_sports = parse_bool(number, key, value)?,
+                    "serial" => config.serial = Some(value.to_string()),
+                    "ftp" => config.ftp = Some(parse_ftp(number, key, value)?),
+                    "max_heart_rate" => {
+                        config.max_heart_rate = Some(parse_value(number, key, value)?)
+                    }
+                    "resting_heart_rate" => {
+                        config.resting_heart_rate = Some(parse_value(number, key, value)?)
+                    }
+                    "threshold_pace" => {
+                        config.threshold_pace = Some(zones::parse_pace(value).map_err(|_| {
+                            PolarError::new(format!(
+                                "Line {}: invalid value '{}' for {}, use minutes:seconds",
+                                number, value, key
+                            ))
+                        })?)
+                    }
+                    "heart_rate_model" => {
+                        config.heart_rate_model = parse_value(number, key, value)?
+                    }
+                    _ => {
+                        return Err(PolarError::new(format!(
+                            "Line {}: unknown setting '{}'",
+                            number, key
+                        )))
+                    }
+                },
+                Section::Set(name) => match key {
+                    "file" => config
+                        .sets
+                        .entry(name.clone())
+                        .or_default()
+                        .push(value.to_string()),
+                    _ => {
+                        return Err(PolarError::new(format!(
+                            "Line {}: favourite sets only have 'file' lines, got '{}'",
+                            number, key
+                        )))
+                    }
+                },
+                Section::Model(name) => {
+                    let model = config.models.entry(name.clone()).or_default();
+
+                    match key {
+                        "read_timeout" => {
+                            model.read_timeout = Some(parse_seconds(number, key, value)?)
+                        }
+                        "write_timeout" => {
+                            model.write_timeout = Some(parse_seconds(number, key, value)?)
+                        }
+                        "packet_size" => model.packet_size = Some(parse_value(number, key, value)?),
+                        "in_endpoint" => model.in_endpoint = Some(parse_value(number, key, value)?),
+                        "out_endpoint" => {
+                            model.out_endpoint = Some(parse_value(number, key, value)?)
+                        }
+                        "verify" => model.verification = Some(parse_value(number, key, value)?),
+                        "favourites_path" => model.favourites_path = Some(with_slash(value)),
+                        _ => {
+                            return Err(PolarError::new(format!(
+                                "Line {}: unknown model setting '{}'",
+                                number, key
+                            )))
+                        }
+                    }
+                }
+            }
+        }
+
+        config.athlete().validate()?;
+        Ok(config)
+    }
+
+    // Same format as the file, with every setting written out
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+
+        result += &format!("log_file = {}\n", self.log_file.display());
+        result += &format!(
+            "log_level = {}\n",
+            self.log_level.to_string().to_lowercase()
+        );
+        result += &format!("favourites_path = {}\n", self.favourites_path);
+        result += &format!("verify = {}\n", verification_name(self.verification));
+        result += &format!(
+            "wait = {}\n",
+            if self.wait_for_device { "yes" } else { "no" }
+        );
+        if let Some(timeout) = self.wait_timeout {
+            result += &format!("wait_timeout = {}\n", timeout.as_secs_f64());
+        }
+        if !self.check_sports {
+            result += "check_sports = no\n";
+        }
+        if let Some(serial) = &self.serial {
+            result += &format!("serial = {}\n", serial);
+        }
+        if let Some(ftp) = self.ftp {
+            result += &format!("ftp = {}\n", ftp);
+        }
+        if let Some(bpm) = self.max_heart_rate {
+            result += &format!("max_heart_rate = {}\n", bpm);
+        }
+        if let Some(bpm) = self.resting_heart_rate {
+            result += &format!("resting_heart_rate = {}\n", bpm);
+        }
+        if let Some(pace) = self.threshold_pace {
+            result += &format!("threshold_pace = {}\n", zones::format_pace(pace));
+        }
+        result += &format!("heart_rate_model = {}\n", self.heart_rate_model);
+
+        for (name, files) in &self.sets {
+            result += &format!("\n[set.{}]\n", name);
+            for file in files {
+                result += &format!("file = {}\n", file);
+            }
+        }
+
+        for (name, model) in &self.models {
+            result += &format!("\n[model.{}]\n", name);
+
+            if let Some(timeout) = model.read_timeout {
+                result += &format!("read_timeout = {}\n", timeout.as_secs_f64());
+            }
+            if let Some(timeout) = model.write_timeout {
+                result += &format!("write_timeout = {}\n", timeout.as_secs_f64());
+            }
+            if let Some(size) = model.packet_size {
+                result += &format!("packet_size = {}\n", size);
+            }
+            if let Some(endpoint) = model.in_endpoint {
+                result += &format!("in_endpoint = {}\n", endpoint);
+            }
+            if let Some(endpoint) = model.out_endpoint {
+                result += &format!("out_endpoint = {}\n", endpoint);
+            }
+            if let Some(verification) = model.verification {
+                result += &format!("verify = {}\n", verification_name(verification));
+            }
+            if let Some(path) = &model.favourites_path {
+                result += &format!("favourites_path = {}\n", path);
+            }
+        }
+
+        result
+    }
+
+    // Upload options from the global settings, plus the overrides for model if there are any
+    pub fn upload_options(&self, model: Option<&str>) -> UploadOptions {
+        let mut options = UploadOptions {
+            verification: self.verification,
+            usb: UsbConfig::default(),
+            wait_for_device: self.wait_for_device,
+            wait_timeout: self.wait_timeout,
+            favourites_path: self.favourites_path.clone(),
+            import: ImportOptions {
+                athlete: self.athlete(),
+            },
+            check_sports: self.check_sports,
+        };
+
+        if let Some(overrides) = model.and_then(|model| self.models.get(model)) {
+            overrides.apply(&mut options);
+        }
+
+        options
+    }
+
+    pub fn athlete(&self) -> Athlete {
+        Athlete {
+            max_heart_rate: self.max_heart_rate,
+            resting_heart_rate: self.resting_heart_rate,
+            threshold_pace: self.threshold_pace,
+            ftp: self.ftp,
+            heart_rate_model: self.heart_rate_model,
+        }
+    }
+}
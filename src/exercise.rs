@@ -0,0 +1,195 @@
+// Laps and samples of an exercise recorded by the watch, as downloaded by sync. The files are in
+// the 00/ directory of the exercise: LAPS.BPB and SAMPLES.GZB, which is gzipped
+use crate::formats::duration_from;
+use crate::polar_watch::{Message, PolarError};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const LAPS_NAME: &str = "LAPS.BPB";
+pub const SAMPLES_NAME: &str = "SAMPLES.GZB";
+
+// The parts of Polar's exercise_laps.proto and exercise_samples.proto that we need, everything else
+// is skipped when decoding
+mod proto {
+    use polar::types::PbDuration;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbLapHeader {
+        // From the start of the exercise to the end of the lap
+        #[prost(message, optional, tag = "1")]
+        pub split_time: Option<PbDuration>,
+        #[prost(message, optional, tag = "2")]
+        pub duration: Option<PbDuration>,
+        #[prost(float, optional, tag = "3")]
+        pub distance: Option<f32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbLap {
+        #[prost(message, optional, tag = "1")]
+        pub header: Option<PbLapHeader>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbLaps {
+        #[prost(message, repeated, tag = "1")]
+        pub laps: Vec<PbLap>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbPowerMeasurements {
+        #[prost(int32, optional, tag = "1")]
+        pub current_power: Option<i32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbExerciseSamples {
+        #[prost(message, optional, tag = "1")]
+        pub recording_interval: Option<PbDuration>,
+        #[prost(uint32, repeated, tag = "2")]
+        pub heart_rate_samples: Vec<u32>,
+        // km/h
+        #[prost(float, repeated, tag = "9")]
+        pub speed_samples: Vec<f32>,
+        #[prost(message, repeated, tag = "22")]
+        pub left_pedal_power_samples: Vec<PbPowerMeasurements>,
+        #[prost(message, repeated, tag = "24")]
+        pub right_pedal_power_samples: Vec<PbPowerMeasurements>,
+    }
+}
+
+// Samples are taken every interval from the start of the exercise. 0 means no value, like when
+// the sensor lost contact
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    // When each lap ended, from the start of the exercise
+    pub laps: Vec<Duration>,
+    pub interval: Duration,
+    pub heart_rate: Vec<u32>,
+    // km/h
+    pub speed: Vec<f32>,
+    // Watts
+    pub power: Vec<u32>,
+}
+
+fn read(path: &Path) -> Result<Option<Vec<u8>>, PolarError> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(PolarError::new(format!(
+            "Failed to read '{}'\n\t{:?}",
+            path.display(),
+            error
+        ))),
+    }
+}
+
+fn decode_error(path: &Path, error: impl std::fmt::Debug) -> PolarError {
+    PolarError::new(format!(
+        "Failed to decode '{}'\n\t{:?}",
+        path.display(),
+        error
+    ))
+}
+
+// SAMPLES.GZB is gzipped
+pub fn gunzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut result = vec![];
+    flate2::read::GzDecoder::new(data).read_to_end(&mut result)?;
+
+    Ok(result)
+}
+
+// The directory with the files, exercise can be the synced directory or its 00/ subdirectory
+fn files_directory(exercise: &Path) -> PathBuf {
+    let inner = exercise.join("00");
+    if inner.is_dir() {
+        inner
+    } else {
+        exercise.to_path_buf()
+    }
+}
+
+impl Recording {
+    pub fn decode_laps(data: &[u8]) -> Result<Vec<Duration>, prost::DecodeError> {
+        let laps = proto::PbLaps::decode(data)?;
+
+        Ok(laps
+            .laps
+            .iter()
+            .filter_map(|lap| lap.header.as_ref()?.split_time.as_ref())
+            .map(duration_from)
+            .collect())
+    }
+
+    // data is the decompressed contents of SAMPLES.GZB
+    pub fn decode_samples(&mut self, data: &[u8]) -> Result<(), prost::DecodeError> {
+        let samples = proto::PbExerciseSamples::decode(data)?;
+
+        self.interval = samples
+            .recording_interval
+            .as_ref()
+            .map(duration_from)
+            .unwrap_or_else(|| Duration::from_secs(1));
+        self.heart_rate = samples.heart_rate_samples;
+        self.speed = samples.speed_samples;
+
+        let watts = |measurement: &proto::PbPowerMeasurements| {
+            measurement.current_power.unwrap_or(0).max(0) as u32
+        };
+        self.power = samples.left_pedal_power_samples.iter().map(watts).collect();
+        for (total, right) in self
+            .power
+            .iter_mut()
+            .zip(samples.right_pedal_power_samples.iter())
+        {
+            *total += watts(right);
+        }
+
+        Ok(())
+    }
+
+    // Missing files give an empty recording, so exercises without laps can still be compared
+    pub fn load<P: AsRef<Path>>(exercise: P) -> Result<Recording, PolarError> {
+        let directory = files_directory(exercise.as_ref());
+        let mut recording = Recording {
+            interval: Duration::from_secs(1),
+            ..Recording::default()
+        };
+
+        let path = directory.join(LAPS_NAME);
+        if let Some(data) = read(&path)? {
+            recording.laps =
+                Recording::decode_laps(&data).map_err(|error| decode_error(&path, error))?;
+        }
+
+        let path = directory.join(SAMPLES_NAME);
+        if let Some(data) = read(&path)? {
+            let samples = gunzip(&data).map_err(|error| decode_error(&path, error))?;
+
+            recording
+                .decode_samples(&samples)
+                .map_err(|error| decode_error(&path, error))?;
+        }
+
+        Ok(recording)
+    }
+
+    // Offset of sample index
+    pub fn offset(&self, index: usize) -> Duration {
+        self.interval * index as u32
+    }
+
+    // Indexes of the samples taken between start and end
+    pub fn samples_between(&self, start: Duration, end: Duration) -> std::ops::Range<usize> {
+        let interval = self.interval.as_secs_f64();
+        if interval <= 0.0 {
+            return 0..0;
+        }
+
+        let first = (start.as_secs_f64() / interval).ceil() as usize;
+        let last = (end.as_secs_f64() / interval).ceil() as usize;
+        first..last.max(first)
+    }
+}
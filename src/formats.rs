@@ -0,0 +1,607 @@
+// Converts workouts from other applications into sessions the watch understands. Every format is
+// read into a list of blocks, which are then turned into phases
+pub mod erg;
+pub mod fit;
+pub mod tcx;
+pub mod text;
+pub mod timeline;
+mod xml;
+pub mod zwo;
+
+use crate::polar_watch::{encode, Message, PolarError};
+use crate::zones::Athlete;
+use polar::data::pb_phase_intensity::{IntensityZone, SpeedZone};
+use polar::data::PbTrainingSessionTarget;
+use polar::data::{PbExerciseTarget, PbPhase, PbPhaseGoal, PbPhaseIntensity, PbPhases};
+use polar::types::{PbDuration, PbMultiLineText, PbOneLineText, PbSportIdentifier};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+pub const SPORT_RUNNING: u64 = 1;
+pub const SPORT_CYCLING: u64 = 2;
+
+// Exercise target with phases, like the sessions created in Polar Flow
+const TARGET_TYPE_PHASED: i32 = 2;
+
+const CHANGE_MANUAL: i32 = 0;
+const CHANGE_AUTOMATIC: i32 = 1;
+
+const GOAL_MANUAL: i32 = 0;
+const GOAL_TIME: i32 = 1;
+const GOAL_DISTANCE: i32 = 2;
+
+const INTENSITY_FREE: i32 = 0;
+const INTENSITY_SPORT_ZONE: i32 = 1;
+const INTENSITY_SPEED_ZONE: i32 = 2;
+const INTENSITY_POWER_ZONE: i32 = 3;
+
+// Upper limits of the power zones 1 to 4 as a fraction of FTP, anything above is zone 5
+const POWER_ZONE_LIMITS: [f32; 4] = [0.55, 0.75, 0.90, 1.05];
+// Top of power zone 5, in % of FTP
+const POWER_ZONE_TOP: u32 = 150;
+
+// Sport zone 1 starts at 50% of max heart rate, every zone is 10% wide
+const HEART_RATE_ZONE_START: u32 = 50;
+const HEART_RATE_ZONE_WIDTH: u32 = 10;
+
+// Settings for turning other formats into sessions
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportOptions {
+    // Turns zones, bpm and watts of other formats into watch zones, and its FTP is used for
+    // workouts with absolute power
+    pub athlete: Athlete,
+}
+
+// When a phase ends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Goal {
+    Time(Duration),
+    // In meters
+    Distance(f32),
+    // Until the lap button is pressed
+    Manual,
+}
+
+// What the watch guides towards during a phase
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Free,
+    // Sport zones, 1 to 5
+    HeartRateZone { lower: u32, upper: u32 },
+    // In km/h
+    Speed { lower: f32, upper: f32 },
+    // Power zones, 1 to 5
+    PowerZone { lower: u32, upper: u32 },
+}
+
+impl fmt::Display for Goal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Goal::Time(duration) => write!(f, "{}", crate::human_duration(*duration)),
+            Goal::Distance(meters) => write!(f, "{} m", meters),
+            Goal::Manual => write!(f, "lap button"),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Free => write!(f, "free"),
+            Target::HeartRateZone { lower, upper } if lower == upper => {
+                write!(f, "HR zone {}", lower)
+            }
+            Target::HeartRateZone { lower, upper } => write!(f, "HR zones {}-{}", lower, upper),
+            Target::Speed { lower, upper } => write!(f, "{:.1}-{:.1} km/h", lower, upper),
+            Target::PowerZone { lower, upper } if lower == upper => {
+                write!(f, "power zone {}", lower)
+            }
+            Target::PowerZone { lower, upper } => write!(f, "power zones {}-{}", lower, upper),
+        }
+    }
+}
+
+// free, hr:4, hr:2-3, power:5, power:3-4 or speed:12-14 (km/h)
+impl std::str::FromStr for Target {
+    type Err = PolarError;
+
+    fn from_str(value: &str) -> Result<Target, PolarError> {
+        let invalid = || {
+            PolarError::new(format!(
+                "Unknown target '{}', use free, hr:<zones>, power:<zones> or speed:<km/h>",
+                value
+            ))
+        };
+
+        if value == "free" {
+            return Ok(Target::Free);
+        }
+
+        let (kind, range) = value.split_once(':').ok_or_else(invalid)?;
+        let (lower, upper) = range.split_once('-').unwrap_or((range, range));
+        let zone = |zone: &str| match zone.trim().parse() {
+            Ok(zone) if (1..=5).contains(&zone) => Ok(zone),
+            _ => Err(invalid()),
+        };
+        let speed = |speed: &str| speed.trim().parse::<f32>().map_err(|_| invalid());
+
+        match kind {
+            "hr" => Ok(Target::HeartRateZone {
+                lower: zone(lower)?,
+                upper: zone(upper)?,
+            }),
+            "power" => Ok(Target::PowerZone {
+                lower: zone(lower)?,
+                upper: zone(upper)?,
+            }),
+            "speed" => Ok(Target::Speed {
+                lower: speed(lower)?,
+                upper: speed(upper)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub name: String,
+    pub goal: Goal,
+    pub target: Target,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Block {
+    Step(Step),
+    Repeat { times: u32, blocks: Vec<Block> },
+}
+
+impl Block {
+    // Time of the fixed time phases with every repeat, distance and manual phases count as nothing
+    pub fn duration(&self) -> Duration {
+        match self {
+            Block::Step(Step {
+                goal: Goal::Time(duration),
+                ..
+            }) => *duration,
+            Block::Step(_) => Duration::from_secs(0),
+            Block::Repeat { times, blocks } => blocks
+                .iter()
+                .fold(Duration::from_secs(0), |total, block| {
+                    total.saturating_add(block.duration())
+                })
+                .checked_mul(*times)
+                .unwrap_or(Duration::MAX),
+        }
+    }
+}
+
+// Power zone for a fraction of FTP, like 0.8 for 80%
+pub fn power_zone(ftp_fraction: f32) -> u32 {
+    1 + POWER_ZONE_LIMITS
+        .iter()
+        .filter(|limit| ftp_fraction >= **limit)
+        .count() as u32
+}
+
+// km/h as minutes per km, like 5:30
+pub fn pace(speed: f32) -> String {
+    if speed <= 0.0 {
+        return "0:00".to_string();
+    }
+
+    let seconds = (3600.0 / speed).round() as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// Lower or upper end of a power zone, in % of FTP
+pub fn power_percent(zone: u32, upper: bool) -> u32 {
+    let zone = zone.clamp(1, 5) as usize;
+    let limit = if upper { zone } else { zone - 1 };
+
+    match limit {
+        0 => 0,
+        5 => POWER_ZONE_TOP,
+        _ => (POWER_ZONE_LIMITS[limit - 1] * 100.0).round() as u32,
+    }
+}
+
+// Lower or upper end of a sport zone, in % of max heart rate
+pub fn heart_rate_percent(zone: u32, upper: bool) -> u32 {
+    let zone = zone.clamp(1, 5);
+    let start = if upper { zone } else { zone - 1 };
+
+    HEART_RATE_ZONE_START + HEART_RATE_ZONE_WIDTH * start
+}
+
+// Sport zone that starts, or ends when upper is set, at a % of max heart rate
+pub fn heart_rate_zone(percent: u32, upper: bool) -> u32 {
+    let percent = percent.saturating_sub(HEART_RATE_ZONE_START);
+    let zone = if upper {
+        percent.div_ceil(HEART_RATE_ZONE_WIDTH)
+    } else {
+        percent / HEART_RATE_ZONE_WIDTH + 1
+    };
+
+    zone.clamp(1, 5)
+}
+
+// Longest phase a PbDuration can hold, its hours are a u32
+pub const MAX_DURATION: Duration = Duration::from_secs(u32::MAX as u64 * 3600);
+
+// Seconds read from a file or typed by the user, None for negative, non-finite or too long values
+pub fn seconds(value: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(value)
+        .ok()
+        .filter(|duration| *duration <= MAX_DURATION)
+}
+
+pub fn duration_from(duration: &PbDuration) -> Duration {
+    Duration::from_millis(duration.millis.unwrap_or(0).into())
+        + Duration::from_secs(duration.seconds.unwrap_or(0).into())
+        + Duration::from_secs(60 * u64::from(duration.minutes.unwrap_or(0)))
+        + Duration::from_secs(3600 * u64::from(duration.hours.unwrap_or(0)))
+}
+
+pub fn pb_duration(duration: Duration) -> PbDuration {
+    let seconds = duration.as_secs();
+
+    PbDuration {
+        hours: Some((seconds / 3600) as u32),
+        minutes: Some((seconds % 3600 / 60) as u32),
+        seconds: Some((seconds % 60) as u32),
+        millis: Some(duration.subsec_millis()),
+    }
+}
+
+impl Step {
+    pub fn to_phase(&self) -> PbPhase {
+        let (change, goal) = match self.goal {
+            Goal::Time(duration) => (
+                CHANGE_AUTOMATIC,
+                PbPhaseGoal {
+                    goal_type: GOAL_TIME,
+                    duration: Some(pb_duration(duration)),
+                    distance: None,
+                    heart_rate: None,
+                },
+            ),
+            Goal::Distance(meters) => (
+                CHANGE_AUTOMATIC,
+                PbPhaseGoal {
+                    goal_type: GOAL_DISTANCE,
+                    duration: None,
+                    distance: Some(meters),
+                    heart_rate: None,
+                },
+            ),
+            Goal::Manual => (
+                CHANGE_MANUAL,
+                PbPhaseGoal {
+                    goal_type: GOAL_MANUAL,
+                    duration: None,
+                    distance: None,
+                    heart_rate: None,
+                },
+            ),
+        };
+
+        let mut intensity = PbPhaseIntensity {
+            intensity_type: INTENSITY_FREE,
+            heart_rate_zone: None,
+            speed_zone: None,
+            power_zone: None,
+        };
+
+        match self.target {
+            Target::Free => (),
+            Target::HeartRateZone { lower, upper } => {
+                intensity.intensity_type = INTENSITY_SPORT_ZONE;
+                intensity.heart_rate_zone = Some(IntensityZone { lower, upper });
+            }
+            Target::Speed { lower, upper } => {
+                intensity.intensity_type = INTENSITY_SPEED_ZONE;
+                intensity.speed_zone = Some(SpeedZone { lower, upper });
+            }
+            Target::PowerZone { lower, upper } => {
+                intensity.intensity_type = INTENSITY_POWER_ZONE;
+                intensity.power_zone = Some(IntensityZone { lower, upper });
+            }
+        }
+
+        PbPhase {
+            name: PbOneLineText {
+                text: self.name.clone(),
+            },
+            change,
+            goal,
+            intensity,
+            repeat_count: None,
+            jump_index: None,
+        }
+    }
+
+    // Jumps are ignored here, from_phases takes care of them
+    pub fn from_phase(phase: &PbPhase) -> Result<Step, PolarError> {
+        let goal = match phase.goal.goal_type {
+            GOAL_MANUAL => Goal::Manual,
+            GOAL_TIME => Goal::Time(
+                phase
+                    .goal
+                    .duration
+                    .as_ref()
+                    .map(duration_from)
+                    .unwrap_or_default(),
+            ),
+            GOAL_DISTANCE => Goal::Distance(phase.goal.distance.unwrap_or(0.0)),
+            goal_type => {
+                return Err(PolarError::new(format!(
+                    "Phase '{}' has a goal type ({}) that can't be converted",
+                    phase.name.text, goal_type
+                )))
+            }
+        };
+
+        let intensity = &phase.intensity;
+        let target = match intensity.intensity_type {
+            INTENSITY_SPORT_ZONE => {
+                intensity
+                    .heart_rate_zone
+                    .as_ref()
+                    .map(|zone| Target::HeartRateZone {
+                        lower: zone.lower,
+                        upper: zone.upper,
+                    })
+            }
+            INTENSITY_SPEED_ZONE => intensity.speed_zone.as_ref().map(|zone| Target::Speed {
+                lower: zone.lower,
+                upper: zone.upper,
+            }),
+            INTENSITY_POWER_ZONE => intensity.power_zone.as_ref().map(|zone| Target::PowerZone {
+                lower: zone.lower,
+                upper: zone.upper,
+            }),
+            _ => None,
+        };
+
+        Ok(Step {
+            name: phase.name.text.clone(),
+            goal,
+            target: target.unwrap_or(Target::Free),
+        })
+    }
+}
+
+fn add_phases(phases: &mut Vec<PbPhase>, blocks: &[Block]) -> Result<(), PolarError> {
+    for block in blocks {
+        match block {
+            Block::Step(step) => phases.push(step.to_phase()),
+            Block::Repeat { times, blocks } => {
+                if *times == 0 {
+                    continue;
+                }
+
+                // Phases are numbered from 1
+                let first = phases.len() + 1;
+                add_phases(phases, blocks)?;
+
+                if *times == 1 || phases.len() < first {
+                    continue;
+                }
+
+                // The last phase of the block jumps back to the first one
+                let last = phases.last_mut().unwrap();
+                if last.jump_index.is_some() {
+                    return Err(PolarError::new(
+                        "A repeat can't end with another repeat, add a phase after the inner one",
+                    ));
+                }
+
+                last.jump_index = Some(first as u32);
+                last.repeat_count = Some(times - 1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn to_phases(blocks: &[Block]) -> Result<Vec<PbPhase>, PolarError> {
+    let mut phases = vec![];
+    add_phases(&mut phases, blocks)?;
+
+    Ok(phases)
+}
+
+// Blocks that start at a phase, phases are numbered from 1
+struct Started {
+    first: usize,
+    block: Block,
+}
+
+// Rebuilds the repeats from the jumps, the opposite of to_phases
+pub fn from_phases(phases: &[PbPhase]) -> Result<Vec<Block>, PolarError> {
+    let mut blocks: Vec<Started> = vec![];
+
+    for (index, phase) in phases.iter().enumerate() {
+        blocks.push(Started {
+            first: index + 1,
+            block: Block::Step(Step::from_phase(phase)?),
+        });
+
+        let jump = match phase.jump_index {
+            Some(jump) => jump as usize,
+            None => continue,
+        };
+
+        let split = match blocks.iter().position(|started| started.first == jump) {
+            Some(split) => split,
+            None => {
+                return Err(PolarError::new(format!(
+                    "Phase {} jumps to phase {}, which doesn't start a block",
+                    index + 1,
+                    jump
+                )))
+            }
+        };
+
+        let repeated = blocks.split_off(split);
+        blocks.push(Started {
+            first: jump,
+            block: Block::Repeat {
+                times: phase.repeat_count.unwrap_or(0) + 1,
+                blocks: repeated.into_iter().map(|started| started.block).collect(),
+            },
+        });
+    }
+
+    Ok(blocks.into_iter().map(|started| started.block).collect())
+}
+
+// Sport of the first exercise target, None when the session doesn't say
+pub fn session_sport(session: &PbTrainingSessionTarget) -> Option<u64> {
+    session
+        .exercise_target
+        .first()
+        .and_then(|target| target.sport_id.as_ref())
+        .or(session.sport_id.as_ref())
+        .map(|sport| sport.value)
+}
+
+pub fn session_blocks(session: &PbTrainingSessionTarget) -> Result<Vec<Block>, PolarError> {
+    match session
+        .exercise_target
+        .first()
+        .and_then(|target| target.phases.as_ref())
+    {
+        Some(phases) => from_phases(&phases.phase),
+        None => Err(PolarError::new(format!(
+            "Session '{}' has no phases",
+            session.name.text
+        ))),
+    }
+}
+
+pub fn session(
+    name: &str,
+    description: &str,
+    sport: Option<u64>,
+    blocks: &[Block],
+) -> Result<PbTrainingSessionTarget, PolarError> {
+    let description = match description.trim() {
+        "" => None,
+        text => Some(PbMultiLineText {
+            text: text.to_string(),
+        }),
+    };
+
+    Ok(PbTrainingSessionTarget {
+        sport_id: None,
+        name: PbOneLineText {
+            text: name.to_string(),
+        },
+        description,
+        duration: None,
+        exercise_target: vec![PbExerciseTarget {
+            target_type: TARGET_TYPE_PHASED,
+            sport_id: sport.map(|value| PbSportIdentifier { value }),
+            volume_target: None,
+            phases: Some(PbPhases {
+                phase: to_phases(blocks)?,
+            }),
+            route: None,
+            steady_race_pace: None,
+            strava_segment_target: None,
+        }],
+        start_time: None,
+        target_done: None,
+        event_id: None,
+        training_program_id: None,
+    })
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, PolarError> {
+    std::fs::read(path).map_err(|error| {
+        PolarError::new(format!(
+            "Failed to read file '{}'\n\t{:?}",
+            path.display(),
+            error
+        ))
+    })
+}
+
+fn read_text(path: &Path) -> Result<String, PolarError> {
+    std::fs::read_to_string(path).map_err(|error| {
+        PolarError::new(format!(
+            "Failed to read file '{}'\n\t{:?}",
+            path.display(),
+            error
+        ))
+    })
+}
+
+// Name for sessions whose file doesn't have one
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// Reads a session in any of the supported formats and returns it as the contents of a .BPB file
+pub fn read_session<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, PolarError> {
+    read_session_with(path, &ImportOptions::default())
+}
+
+pub fn read_session_with<P: AsRef<Path>>(
+    path: P,
+    options: &ImportOptions,
+) -> Result<Vec<u8>, PolarError> {
+    let path = path.as_ref();
+    let name = file_stem(path);
+
+    let session = match extension(path).as_str() {
+        "zwo" => zwo::parse(&read_text(path)?, &name)?,
+        "fit" => fit::parse(&read_file(path)?, &name, options)?,
+        "erg" => erg::parse(&read_text(path)?, &name, false, options)?,
+        "mrc" => erg::parse(&read_text(path)?, &name, true, options)?,
+        _ => return read_file(path),
+    };
+
+    encode(session)
+        .map_err(|error| PolarError::new(format!("Failed to encode session: {:?}", error)))
+}
+
+// The contents of a .BPB file
+pub fn decode_session(data: &[u8]) -> Result<PbTrainingSessionTarget, PolarError> {
+    PbTrainingSessionTarget::decode(data)
+        .map_err(|error| PolarError::new(format!("Not a training session: {:?}", error)))
+}
+
+// Writes the contents of a .BPB file in the format given by the extension of path
+pub fn write_session<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<(), PolarError> {
+    let path = path.as_ref();
+
+    let decode = || decode_session(data);
+
+    let data = match extension(path).as_str() {
+        "fit" => fit::write(&decode()?)?,
+        "tcx" => tcx::write(&decode()?)?.into_bytes(),
+        "txt" => text::write(&decode()?)?.into_bytes(),
+        _ => data.to_vec(),
+    };
+
+    std::fs::write(path, data).map_err(|error| {
+        PolarError::new(format!(
+            "Failed to write '{}'\n\t{:?}",
+            path.display(),
+            error
+        ))
+    })
+}
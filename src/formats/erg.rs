@@ -0,0 +1,229 @@
+// ERG and MRC trainer files: a table of minutes against watts (.erg) or % of FTP (.mrc), where power
+// changes linearly between rows:
+//
+//     [COURSE HEADER]
+//     VERSION = 2
+//     UNITS = ENGLISH
+//     DESCRIPTION = Sweet spot
+//     FILE NAME = sweet-spot.mrc
+//     MINUTES PERCENT
+//     [END COURSE HEADER]
+//     [COURSE DATA]
+//     0.00    50
+//     10.00   75
+//     10.00   90
+//     30.00   90
+//     [END COURSE DATA]
+use super::{power_zone, session, Block, Goal, ImportOptions, Step, Target, SPORT_CYCLING};
+use crate::polar_watch::PolarError;
+use polar::data::PbTrainingSessionTarget;
+use std::time::Duration;
+
+// Ramps are split into steps of this length, each with the power at its middle
+const RAMP_STEP_SECONDS: f64 = 60.0;
+// Longest course we turn into phases, a day
+const MAX_COURSE_SECONDS: f64 = 24.0 * 3600.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Units {
+    Watts,
+    Percent,
+}
+
+fn error(line: usize, message: &str) -> PolarError {
+    PolarError::new(format!("Invalid ERG/MRC file, line {}: {}", line, message))
+}
+
+// Time in seconds and power as a fraction of FTP
+struct Point {
+    seconds: f64,
+    power: f64,
+}
+
+struct Profile {
+    name: Option<String>,
+    description: String,
+    units: Option<Units>,
+    ftp: Option<f64>,
+    // Time in minutes and power in the units of the file, with the line they come from
+    rows: Vec<(usize, f64, f64)>,
+}
+
+fn parse_profile(text: &str) -> Result<Profile, PolarError> {
+    let mut profile = Profile {
+        name: None,
+        description: String::new(),
+        units: None,
+        ftp: None,
+        rows: vec![],
+    };
+
+    let mut in_data = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            in_data = line.eq_ignore_ascii_case("[COURSE DATA]");
+            continue;
+        }
+
+        if in_data {
+            let values: Vec<&str> = line.split_whitespace().collect();
+            let parse = |value: &str| -> Result<f64, PolarError> {
+                match value.parse::<f64>() {
+                    Ok(value) if value.is_finite() && value >= 0.0 => Ok(value),
+                    _ => Err(error(number, &format!("invalid number '{}'", value))),
+                }
+            };
+
+            match values.as_slice() {
+                [minutes, power, ..] => profile.rows.push((number, parse(minutes)?, parse(power)?)),
+                _ => return Err(error(number, "expected minutes and power")),
+            }
+            continue;
+        }
+
+        match line.find('=') {
+            Some(split) => {
+                let key = line[..split].trim().to_uppercase();
+                let value = line[split + 1..].trim();
+
+                match key.as_str() {
+                    "DESCRIPTION" => profile.description = value.to_string(),
+                    "FILE NAME" => {
+                        let name = value.rsplit_once('.').map_or(value, |(stem, _)| stem);
+                        profile.name = Some(name.to_string());
+                    }
+                    "FTP" => match value.parse::<f64>() {
+                        Ok(ftp) if ftp.is_finite() && ftp > 0.0 => profile.ftp = Some(ftp),
+                        _ => return Err(error(number, &format!("invalid FTP '{}'", value))),
+                    },
+                    _ => (),
+                }
+            }
+            // The column names, like MINUTES WATTS
+            None => {
+                let columns = line.to_uppercase();
+                if columns.contains("PERCENT") {
+                    profile.units = Some(Units::Percent);
+                } else if columns.contains("WATTS") {
+                    profile.units = Some(Units::Watts);
+                }
+            }
+        }
+    }
+
+    Ok(profile)
+}
+
+// One phase per step of constant power zone. Neighbours in the same zone are merged, so constant
+// segments and gentle ramps become a single phase
+fn profile_blocks(points: &[Point]) -> Vec<Block> {
+    // Zone and seconds
+    let mut pieces: Vec<(u32, f64)> = vec![];
+    let mut add = |power: f64, seconds: f64| {
+        let zone = power_zone(power as f32);
+        match pieces.last_mut() {
+            Some((last, length)) if *last == zone => *length += seconds,
+            _ => pieces.push((zone, seconds)),
+        }
+    };
+
+    for pair in points.windows(2) {
+        let (start, end) = (&pair[0], &pair[1]);
+        let length = end.seconds - start.seconds;
+        if length <= 0.0 {
+            continue;
+        }
+
+        if (end.power - start.power).abs() < 1e-6 {
+            add(start.power, length);
+            continue;
+        }
+
+        let steps = (length / RAMP_STEP_SECONDS).ceil();
+        let step = length / steps;
+        for index in 0..steps as usize {
+            let middle = step * (index as f64 + 0.5);
+            add(
+                start.power + (end.power - start.power) * middle / length,
+                step,
+            );
+        }
+    }
+
+    pieces
+        .into_iter()
+        .map(|(zone, seconds)| {
+            Block::Step(Step {
+                name: format!("Zone {}", zone),
+                goal: Goal::Time(Duration::from_millis((seconds * 1000.0).round() as u64)),
+                target: Target::PowerZone {
+                    lower: zone,
+                    upper: zone,
+                },
+            })
+        })
+        .collect()
+}
+
+// Files without column names are in % of FTP when percent_by_default is set, like .mrc files, and
+// in watts otherwise. The FTP in options wins over the one in the file
+pub fn parse(
+    text: &str,
+    default_name: &str,
+    percent_by_default: bool,
+    options: &ImportOptions,
+) -> Result<PbTrainingSessionTarget, PolarError> {
+    let profile = parse_profile(text)?;
+
+    let units = profile.units.unwrap_or(if percent_by_default {
+        Units::Percent
+    } else {
+        Units::Watts
+    });
+
+    let ftp = options.athlete.ftp.map(f64::from).or(profile.ftp);
+    let scale = match (units, ftp) {
+        (Units::Percent, _) => 1.0 / 100.0,
+        (Units::Watts, Some(ftp)) => 1.0 / ftp,
+        (Units::Watts, None) => return Err(PolarError::new(
+            "The workout is in watts, give your FTP with --ftp=<watts> to turn it into power zones",
+        )),
+    };
+
+    let mut points: Vec<Point> = vec![];
+    for (line, minutes, power) in &profile.rows {
+        let seconds = minutes * 60.0;
+        if points.last().is_some_and(|last| seconds < last.seconds) {
+            return Err(error(*line, "time goes backwards"));
+        }
+        if seconds > MAX_COURSE_SECONDS {
+            return Err(error(*line, "the course is longer than a day"));
+        }
+
+        points.push(Point {
+            seconds,
+            power: power * scale,
+        });
+    }
+
+    if points.len() < 2 {
+        return Err(PolarError::new(
+            "Invalid ERG/MRC file: the course needs at least two rows",
+        ));
+    }
+
+    session(
+        profile.name.as_deref().unwrap_or(default_name),
+        &profile.description,
+        Some(SPORT_CYCLING),
+        &profile_blocks(&points),
+    )
+}
@@ -0,0 +1,787 @@
+// Garmin FIT workout files: a file_id message, one workout message and one workout_step message per
+// step. Repeats are steps that jump back to an earlier step, like the jumps between phases
+use super::{
+    heart_rate_percent, heart_rate_zone, power_percent, power_zone, session, session_blocks,
+    session_sport, Block, Goal, ImportOptions, Step, Target, SPORT_CYCLING, SPORT_RUNNING,
+};
+use crate::polar_watch::PolarError;
+use crate::zones::{Athlete, Intensity};
+use log::warn;
+use polar::data::PbTrainingSessionTarget;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+const HEADER_SIZE: u8 = 14;
+const PROTOCOL_VERSION: u8 = 0x20;
+const PROFILE_VERSION: u16 = 2132;
+
+const MESSAGE_FILE_ID: u16 = 0;
+const MESSAGE_WORKOUT: u16 = 26;
+const MESSAGE_WORKOUT_STEP: u16 = 27;
+
+const FILE_TYPE_WORKOUT: u32 = 5;
+const MANUFACTURER_DEVELOPMENT: u32 = 255;
+
+const FIT_SPORT_GENERIC: u32 = 0;
+const FIT_SPORT_RUNNING: u32 = 1;
+const FIT_SPORT_CYCLING: u32 = 2;
+
+// Workout field with the Polar sport id of sports FIT has no value for. It isn't in the FIT
+// profile, so other readers skip it and see a generic workout
+const FIELD_POLAR_SPORT: u8 = 200;
+
+const DURATION_TIME: u32 = 0;
+const DURATION_DISTANCE: u32 = 1;
+const DURATION_OPEN: u32 = 5;
+const DURATION_REPEAT_UNTIL_STEPS_COMPLETE: u32 = 6;
+
+const TARGET_SPEED: u32 = 0;
+const TARGET_HEART_RATE: u32 = 1;
+const TARGET_OPEN: u32 = 2;
+const TARGET_POWER: u32 = 4;
+
+// Custom heart rate targets above this are in bpm + 100, below it in % of max heart rate
+const HEART_RATE_BPM_OFFSET: u32 = 100;
+// Custom power targets above this are in watts + 1000, below it in % of FTP
+const POWER_WATTS_OFFSET: u32 = 1000;
+
+const BASE_ENUM: u8 = 0x00;
+const BASE_STRING: u8 = 0x07;
+const BASE_UINT16: u8 = 0x84;
+const BASE_UINT32: u8 = 0x86;
+
+// All bits set is the invalid value of FIT integers, so the largest value is one below
+const MAX_UINT16: u64 = 0xfffe;
+const MAX_UINT32: u64 = 0xffff_fffe;
+
+const CRC_TABLE: [u16; 16] = [
+    0x0000, 0xcc01, 0xd801, 0x1400, 0xf001, 0x3c00, 0x2800, 0xe401, 0xa001, 0x6c00, 0x7800, 0xb401,
+    0x5000, 0x9c01, 0x8801, 0x4400,
+];
+
+pub fn crc(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+
+    for byte in data {
+        for nibble in &[byte & 0xf, byte >> 4] {
+            let tmp = CRC_TABLE[(crc & 0xf) as usize];
+            crc = (crc >> 4) & 0x0fff;
+            crc = crc ^ tmp ^ CRC_TABLE[*nibble as usize];
+        }
+    }
+
+    crc
+}
+
+fn error<S: Into<String>>(message: S) -> PolarError {
+    PolarError::new(format!("Invalid FIT file: {}", message.into()))
+}
+
+// value when it fits a FIT integer of at most max, an error naming what doesn't fit otherwise
+fn fit_value(value: u64, max: u64, what: &str) -> Result<u32, PolarError> {
+    match u32::try_from(value) {
+        Ok(value) if u64::from(value) <= max => Ok(value),
+        _ => Err(PolarError::new(format!(
+            "{} doesn't fit in a FIT file, at most {} can be written",
+            what, max
+        ))),
+    }
+}
+
+fn fit_float(value: f64, what: &str) -> Result<u32, PolarError> {
+    if value.is_finite() && value >= 0.0 {
+        fit_value(value.round() as u64, MAX_UINT32, what)
+    } else {
+        Err(PolarError::new(format!(
+            "{} can't be written to a FIT file",
+            what
+        )))
+    }
+}
+
+fn step_index(steps: &[Vec<Field>]) -> Result<u32, PolarError> {
+    fit_value(steps.len() as u64, MAX_UINT16, "The number of steps")
+}
+
+// Field number, base type and value
+struct Field {
+    number: u8,
+    base_type: u8,
+    data: Vec<u8>,
+}
+
+struct Record {
+    global: u16,
+    fields: Vec<Field>,
+}
+
+fn is_invalid(base_type: u8, data: &[u8]) -> bool {
+    // The z types use 0 for invalid values, everything else all ones, or 0x7f... when signed
+    match base_type & 0x1f {
+        0x0a | 0x0b | 0x0c | 0x10 => data.iter().all(|byte| *byte == 0),
+        0x01 | 0x03 | 0x05 | 0x0e => {
+            let (last, rest) = data.split_last().unwrap_or((&0x7f, &[]));
+            *last == 0x7f && rest.iter().all(|byte| *byte == 0xff)
+        }
+        _ => data.iter().all(|byte| *byte == 0xff),
+    }
+}
+
+impl Record {
+    fn field(&self, number: u8) -> Option<&Field> {
+        self.fields.iter().find(|field| field.number == number)
+    }
+
+    // Unsigned integers of up to 4 bytes, None when missing or invalid
+    fn uint(&self, number: u8) -> Option<u32> {
+        let field = self.field(number)?;
+        if field.data.is_empty() || field.data.len() > 4 || is_invalid(field.base_type, &field.data)
+        {
+            return None;
+        }
+
+        Some(
+            field
+                .data
+                .iter()
+                .rev()
+                .fold(0, |value, byte| (value << 8) | u32::from(*byte)),
+        )
+    }
+
+    fn string(&self, number: u8) -> Option<String> {
+        let data = &self.field(number)?.data;
+        let end = data
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(data.len());
+
+        match String::from_utf8_lossy(&data[..end]).trim() {
+            "" => None,
+            text => Some(text.to_string()),
+        }
+    }
+}
+
+struct Definition {
+    global: u16,
+    big_endian: bool,
+    // Field number, size and base type
+    fields: Vec<(u8, usize, u8)>,
+    developer_size: usize,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], PolarError> {
+        if self.data.len() - self.position < size {
+            return Err(error("truncated record"));
+        }
+
+        let result = &self.data[self.position..self.position + size];
+        self.position += size;
+        Ok(result)
+    }
+
+    fn byte(&mut self) -> Result<u8, PolarError> {
+        Ok(self.take(1)?[0])
+    }
+}
+
+// All the data messages in the file, values are converted to little endian
+fn read_records(data: &[u8]) -> Result<Vec<Record>, PolarError> {
+    if data.len() < 12 || &data[8..12] != b".FIT" {
+        return Err(error("missing .FIT signature"));
+    }
+
+    let header_size = data[0] as usize;
+    let data_size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let end = header_size + data_size;
+
+    if header_size < 12 || data.len() < end + 2 {
+        return Err(error("file is shorter than its header says"));
+    }
+
+    let stored = u16::from_le_bytes([data[end], data[end + 1]]);
+    if stored != 0 && crc(&data[..end]) != stored {
+        return Err(error("wrong checksum"));
+    }
+
+    let mut reader = Reader {
+        data: &data[..end],
+        position: header_size,
+    };
+    let mut definitions: HashMap<u8, Definition> = HashMap::new();
+    let mut records = vec![];
+
+    while reader.position < end {
+        let header = reader.byte()?;
+
+        // Compressed timestamp header, always a data message
+        let (local, is_definition, has_developer_data) = if header & 0x80 != 0 {
+            ((header >> 5) & 0x3, false, false)
+        } else {
+            (header & 0xf, header & 0x40 != 0, header & 0x20 != 0)
+        };
+
+        if is_definition {
+            reader.take(1)?;
+            let big_endian = reader.byte()? == 1;
+            let global = reader.take(2)?;
+            let global = if big_endian {
+                u16::from_be_bytes([global[0], global[1]])
+            } else {
+                u16::from_le_bytes([global[0], global[1]])
+            };
+
+            let count = reader.byte()?;
+            let mut fields = vec![];
+            for _ in 0..count {
+                let field = reader.take(3)?;
+                fields.push((field[0], field[1] as usize, field[2]));
+            }
+
+            let mut developer_size = 0;
+            if has_developer_data {
+                for _ in 0..reader.byte()? {
+                    developer_size += reader.take(3)?[1] as usize;
+                }
+            }
+
+            definitions.insert(
+                local,
+                Definition {
+                    global,
+                    big_endian,
+                    fields,
+                    developer_size,
+                },
+            );
+        } else {
+            let definition = definitions
+                .get(&local)
+                .ok_or_else(|| error(format!("data for undefined message type {}", local)))?;
+
+            let mut fields = vec![];
+            for (number, size, base_type) in &definition.fields {
+                let mut data = reader.take(*size)?.to_vec();
+                // Only the multi byte types have an endianness
+                if definition.big_endian && base_type & 0x80 != 0 {
+                    data.reverse();
+                }
+
+                fields.push(Field {
+                    number: *number,
+                    base_type: *base_type,
+                    data,
+                });
+            }
+            reader.take(definition.developer_size)?;
+
+            records.push(Record {
+                global: definition.global,
+                fields,
+            });
+        }
+    }
+
+    Ok(records)
+}
+
+// Zones are resolved with the athlete's heart rate model, and bpm and watts need the athlete's max
+// heart rate and FTP
+fn target(record: &Record, athlete: &Athlete) -> Target {
+    let target_type = record.uint(3).unwrap_or(TARGET_OPEN);
+    let value = record.uint(4).unwrap_or(0);
+    let low = record.uint(5).unwrap_or(0);
+    let high = record.uint(6).unwrap_or(0);
+
+    match target_type {
+        TARGET_OPEN => Target::Free,
+        TARGET_HEART_RATE if value > 0 => Intensity::HeartRateZone {
+            lower: value,
+            upper: value,
+        }
+        .resolve(athlete)
+        .unwrap_or(Target::HeartRateZone {
+            lower: value.min(5),
+            upper: value.min(5),
+        }),
+        TARGET_HEART_RATE if high <= HEART_RATE_BPM_OFFSET => {
+            let lower = heart_rate_zone(low, false);
+            Target::HeartRateZone {
+                lower,
+                upper: heart_rate_zone(high, true).max(lower),
+            }
+        }
+        TARGET_SPEED if value == 0 => Target::Speed {
+            // From mm/s
+            lower: low as f32 * 3.6 / 1000.0,
+            upper: high as f32 * 3.6 / 1000.0,
+        },
+        TARGET_POWER if value > 0 => Target::PowerZone {
+            lower: value.min(5),
+            upper: value.min(5),
+        },
+        TARGET_POWER if high <= POWER_WATTS_OFFSET => {
+            let lower = power_zone(low as f32 / 100.0);
+            Target::PowerZone {
+                lower,
+                upper: power_zone(high.saturating_sub(1) as f32 / 100.0).max(lower),
+            }
+        }
+        TARGET_HEART_RATE if athlete.max_heart_rate.is_some() => athlete
+            .heart_rate_target(
+                low.saturating_sub(HEART_RATE_BPM_OFFSET),
+                high - HEART_RATE_BPM_OFFSET,
+            )
+            .unwrap_or(Target::Free),
+        TARGET_POWER if athlete.ftp.is_some() => athlete
+            .power_target(
+                low.saturating_sub(POWER_WATTS_OFFSET) as f32,
+                (high - POWER_WATTS_OFFSET) as f32,
+            )
+            .unwrap_or(Target::Free),
+        _ => {
+            warn!(
+                "FIT target type {} with value {} ({}-{}) has no Polar zone, using a free target",
+                target_type, value, low, high
+            );
+            Target::Free
+        }
+    }
+}
+
+// Blocks that start at a step, with the index of that step
+struct Started {
+    first: u32,
+    block: Block,
+}
+
+fn workout_blocks(steps: &[&Record], athlete: &Athlete) -> Result<Vec<Block>, PolarError> {
+    let mut blocks: Vec<Started> = vec![];
+
+    for (position, record) in steps.iter().enumerate() {
+        let index = record.uint(254).unwrap_or(position as u32);
+        let duration_type = record.uint(1).unwrap_or(DURATION_OPEN);
+        let duration_value = record.uint(2);
+
+        let goal = match duration_type {
+            DURATION_TIME => Goal::Time(Duration::from_millis(duration_value.unwrap_or(0).into())),
+            // From cm
+            DURATION_DISTANCE => Goal::Distance(duration_value.unwrap_or(0) as f32 / 100.0),
+            DURATION_OPEN => Goal::Manual,
+            DURATION_REPEAT_UNTIL_STEPS_COMPLETE => {
+                let from = duration_value.unwrap_or(0);
+                let split = blocks
+                    .iter()
+                    .position(|started| started.first == from)
+                    .ok_or_else(|| {
+                        error(format!(
+                            "step {} repeats from step {}, which doesn't start a block",
+                            index, from
+                        ))
+                    })?;
+
+                let repeated = blocks.split_off(split);
+                blocks.push(Started {
+                    first: from,
+                    block: Block::Repeat {
+                        times: record.uint(4).unwrap_or(1),
+                        blocks: repeated.into_iter().map(|started| started.block).collect(),
+                    },
+                });
+                continue;
+            }
+            _ => {
+                return Err(error(format!(
+                    "step {} has a duration type ({}) that can't be converted",
+                    index, duration_type
+                )))
+            }
+        };
+
+        blocks.push(Started {
+            first: index,
+            block: Block::Step(Step {
+                name: record.string(0).unwrap_or_default(),
+                goal,
+                target: target(record, athlete),
+            }),
+        });
+    }
+
+    Ok(blocks.into_iter().map(|started| started.block).collect())
+}
+
+// default_name is used when the workout has no name
+pub fn parse(
+    data: &[u8],
+    default_name: &str,
+    options: &ImportOptions,
+) -> Result<PbTrainingSessionTarget, PolarError> {
+    let records = read_records(data)?;
+
+    if let Some(file_type) = records
+        .iter()
+        .find(|record| record.global == MESSAGE_FILE_ID)
+        .and_then(|record| record.uint(0))
+    {
+        if file_type != FILE_TYPE_WORKOUT {
+            return Err(error(format!(
+                "file type is {}, not a workout ({})",
+                file_type, FILE_TYPE_WORKOUT
+            )));
+        }
+    }
+
+    let workout = records
+        .iter()
+        .find(|record| record.global == MESSAGE_WORKOUT)
+        .ok_or_else(|| error("no workout message"))?;
+
+    let mut steps: Vec<&Record> = records
+        .iter()
+        .filter(|record| record.global == MESSAGE_WORKOUT_STEP)
+        .collect();
+    steps.sort_by_key(|record| record.uint(254));
+
+    let sport = match workout.uint(4) {
+        Some(FIT_SPORT_RUNNING) => Some(SPORT_RUNNING),
+        Some(FIT_SPORT_CYCLING) => Some(SPORT_CYCLING),
+        _ => workout.uint(FIELD_POLAR_SPORT).map(u64::from),
+    };
+
+    session(
+        &workout
+            .string(8)
+            .unwrap_or_else(|| default_name.to_string()),
+        &workout.string(17).unwrap_or_default(),
+        sport,
+        &workout_blocks(&steps, &options.athlete)?,
+    )
+}
+
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    // Every message gets its own definition, so strings can have any length
+    fn message(&mut self, global: u16, fields: &[Field]) {
+        self.data.extend_from_slice(&[0x40, 0, 0]);
+        self.data.extend_from_slice(&global.to_le_bytes());
+        self.data.push(fields.len() as u8);
+        for field in fields {
+            self.data
+                .extend_from_slice(&[field.number, field.data.len() as u8, field.base_type]);
+        }
+
+        self.data.push(0);
+        for field in fields {
+            self.data.extend_from_slice(&field.data);
+        }
+    }
+}
+
+fn enum_field(number: u8, value: u32) -> Field {
+    Field {
+        number,
+        base_type: BASE_ENUM,
+        data: vec![value as u8],
+    }
+}
+
+fn uint16_field(number: u8, value: u32) -> Field {
+    Field {
+        number,
+        base_type: BASE_UINT16,
+        data: (value as u16).to_le_bytes().to_vec(),
+    }
+}
+
+fn uint32_field(number: u8, value: u32) -> Field {
+    Field {
+        number,
+        base_type: BASE_UINT32,
+        data: value.to_le_bytes().to_vec(),
+    }
+}
+
+// Null terminated, and cut to fit the one byte field size
+fn string_field(number: u8, text: &str) -> Field {
+    let mut data = text.as_bytes().to_vec();
+    let mut end = data.len().min(254);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    data.truncate(end);
+    data.push(0);
+
+    Field {
+        number,
+        base_type: BASE_STRING,
+        data,
+    }
+}
+
+fn target_fields(target: &Target) -> Result<Vec<Field>, PolarError> {
+    let (target_type, value, custom) = match *target {
+        Target::Free => (TARGET_OPEN, 0, None),
+        Target::HeartRateZone { lower, upper } if lower == upper => {
+            (TARGET_HEART_RATE, lower, None)
+        }
+        Target::HeartRateZone { lower, upper } => (
+            TARGET_HEART_RATE,
+            0,
+            Some((
+                heart_rate_percent(lower, false),
+                heart_rate_percent(upper, true),
+            )),
+        ),
+        // To mm/s
+        Target::Speed { lower, upper } => (
+            TARGET_SPEED,
+            0,
+            Some((
+                fit_float(f64::from(lower) / 3.6 * 1000.0, "The speed")?,
+                fit_float(f64::from(upper) / 3.6 * 1000.0, "The speed")?,
+            )),
+        ),
+        Target::PowerZone { lower, upper } if lower == upper => (TARGET_POWER, lower, None),
+        Target::PowerZone { lower, upper } => (
+            TARGET_POWER,
+            0,
+            Some((power_percent(lower, false), power_percent(upper, true))),
+        ),
+    };
+
+    let mut fields = vec![enum_field(3, target_type), uint32_field(4, value)];
+    if let Some((low, high)) = custom {
+        fields.push(uint32_field(5, low));
+        fields.push(uint32_field(6, high));
+    }
+
+    Ok(fields)
+}
+
+fn add_steps(steps: &mut Vec<Vec<Field>>, blocks: &[Block]) -> Result<(), PolarError> {
+    for block in blocks {
+        match block {
+            Block::Step(step) => {
+                let (duration_type, value) = match step.goal {
+                    // In ms and cm
+                    Goal::Time(duration) => (
+                        DURATION_TIME,
+                        Some(fit_value(
+                            u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+                            MAX_UINT32,
+                            &format!("The duration of '{}'", step.name),
+                        )?),
+                    ),
+                    Goal::Distance(meters) => (
+                        DURATION_DISTANCE,
+                        Some(fit_float(
+                            f64::from(meters) * 100.0,
+                            &format!("The distance of '{}'", step.name),
+                        )?),
+                    ),
+                    Goal::Manual => (DURATION_OPEN, None),
+                };
+
+                let mut fields = vec![uint16_field(254, step_index(steps)?)];
+                if !step.name.is_empty() {
+                    fields.push(string_field(0, &step.name));
+                }
+                fields.push(enum_field(1, duration_type));
+                if let Some(value) = value {
+                    fields.push(uint32_field(2, value));
+                }
+                fields.extend(target_fields(&step.target)?);
+
+                steps.push(fields);
+            }
+            Block::Repeat { times, blocks } => {
+                if *times == 0 {
+                    continue;
+                }
+
+                let first = step_index(steps)?;
+                add_steps(steps, blocks)?;
+
+                let index = step_index(steps)?;
+                if *times > 1 && index > first {
+                    steps.push(vec![
+                        uint16_field(254, index),
+                        enum_field(1, DURATION_REPEAT_UNTIL_STEPS_COMPLETE),
+                        uint32_field(2, first),
+                        enum_field(3, TARGET_OPEN),
+                        uint32_field(4, fit_value(u64::from(*times), MAX_UINT32, "A repeat")?),
+                    ]);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The session as a FIT workout file
+pub fn write(session: &PbTrainingSessionTarget) -> Result<Vec<u8>, PolarError> {
+    let mut steps = vec![];
+    add_steps(&mut steps, &session_blocks(session)?)?;
+    let step_count = step_index(&steps)?;
+
+    let (sport, polar_sport) = match session_sport(session) {
+        Some(SPORT_RUNNING) => (FIT_SPORT_RUNNING, None),
+        Some(SPORT_CYCLING) => (FIT_SPORT_CYCLING, None),
+        None => (FIT_SPORT_GENERIC, None),
+        Some(sport) => {
+            warn!(
+                "Polar sport {} has no FIT sport, other apps will see a generic workout",
+                sport
+            );
+            (FIT_SPORT_GENERIC, u32::try_from(sport).ok())
+        }
+    };
+
+    let mut writer = Writer { data: vec![] };
+    writer.message(
+        MESSAGE_FILE_ID,
+        &[
+            enum_field(0, FILE_TYPE_WORKOUT),
+            uint16_field(1, MANUFACTURER_DEVELOPMENT),
+            uint16_field(2, 0),
+        ],
+    );
+
+    let mut workout = vec![
+        enum_field(4, sport),
+        uint16_field(6, step_count),
+        string_field(8, &session.name.text),
+    ];
+    if let Some(description) = &session.description {
+        workout.push(string_field(17, &description.text));
+    }
+    if let Some(polar_sport) = polar_sport {
+        workout.push(uint32_field(FIELD_POLAR_SPORT, polar_sport));
+    }
+    writer.message(MESSAGE_WORKOUT, &workout);
+
+    for step in &steps {
+        writer.message(MESSAGE_WORKOUT_STEP, step);
+    }
+
+    let mut result = vec![HEADER_SIZE, PROTOCOL_VERSION];
+    result.extend_from_slice(&PROFILE_VERSION.to_le_bytes());
+    result.extend_from_slice(&(writer.data.len() as u32).to_le_bytes());
+    result.extend_from_slice(b".FIT");
+    let header_crc = crc(&result);
+    result.extend_from_slice(&header_crc.to_le_bytes());
+
+    result.extend_from_slice(&writer.data);
+    let file_crc = crc(&result);
+    result.extend_from_slice(&file_crc.to_le_bytes());
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zones::HeartRateModel;
+
+    #[test]
+    fn values_that_dont_fit_are_an_error() {
+        let step = |goal| {
+            Block::Step(Step {
+                name: "Long".to_string(),
+                goal,
+                target: Target::Free,
+            })
+        };
+
+        let mut steps = vec![];
+        add_steps(&mut steps, &[step(Goal::Time(Duration::from_secs(86_400)))]).unwrap();
+
+        // 50 days is more ms than a uint32 has
+        let days = Duration::from_secs(50 * 86_400);
+        assert!(add_steps(&mut vec![], &[step(Goal::Time(days))]).is_err());
+        assert!(add_steps(&mut vec![], &[step(Goal::Distance(f32::INFINITY))]).is_err());
+
+        // Step indexes and the step count are uint16
+        let many = vec![step(Goal::Manual); MAX_UINT16 as usize + 2];
+        assert!(add_steps(&mut vec![], &many).is_err());
+        assert!(step_index(&(0..MAX_UINT16).map(|_| vec![]).collect::<Vec<_>>()).is_ok());
+        assert!(step_index(&(0..=MAX_UINT16).map(|_| vec![]).collect::<Vec<_>>()).is_err());
+
+        let fast = Target::Speed {
+            lower: 10.0,
+            upper: f32::NAN,
+        };
+        assert!(target_fields(&fast).is_err());
+    }
+
+    fn step_record(target_type: u32, value: u32, low: u32, high: u32) -> Record {
+        Record {
+            global: MESSAGE_WORKOUT_STEP,
+            fields: vec![
+                enum_field(3, target_type),
+                uint32_field(4, value),
+                uint32_field(5, low),
+                uint32_field(6, high),
+            ],
+        }
+    }
+
+    #[test]
+    fn bpm_and_watts_need_the_athlete() {
+        let heart_rate = step_record(TARGET_HEART_RATE, 0, 240, 260);
+        let power = step_record(TARGET_POWER, 0, 1200, 1250);
+
+        let nobody = Athlete::default();
+        assert_eq!(target(&heart_rate, &nobody), Target::Free);
+        assert_eq!(target(&power, &nobody), Target::Free);
+
+        // 140-160 bpm of 200 and 200-250 W of 250
+        let athlete = Athlete {
+            max_heart_rate: Some(200),
+            ftp: Some(250.0),
+            ..Athlete::default()
+        };
+        assert_eq!(
+            target(&heart_rate, &athlete),
+            Target::HeartRateZone { lower: 3, upper: 3 }
+        );
+        assert_eq!(
+            target(&power, &athlete),
+            Target::PowerZone { lower: 3, upper: 4 }
+        );
+    }
+
+    #[test]
+    fn zones_follow_the_heart_rate_model() {
+        let zone = step_record(TARGET_HEART_RATE, 3, 0, 0);
+        assert_eq!(
+            target(&zone, &Athlete::default()),
+            Target::HeartRateZone { lower: 3, upper: 3 }
+        );
+
+        let athlete = Athlete {
+            max_heart_rate: Some(190),
+            resting_heart_rate: Some(50),
+            heart_rate_model: HeartRateModel::Karvonen,
+            ..Athlete::default()
+        };
+        assert_eq!(
+            target(&zone, &athlete),
+            Target::HeartRateZone { lower: 3, upper: 4 }
+        );
+    }
+}
@@ -0,0 +1,135 @@
+// Garmin Training Center workouts. Repeats become Repeat_t steps with the repeated steps as their
+// children. TCX has no power targets, those steps are written without a target
+use super::xml::escape;
+use super::{
+    heart_rate_percent, session_blocks, session_sport, Block, Goal, Step, Target, SPORT_CYCLING,
+    SPORT_RUNNING,
+};
+use crate::polar_watch::PolarError;
+use polar::data::PbTrainingSessionTarget;
+
+// Workout and step names are RestrictedToken_t, longer ones are refused by the devices
+const NAME_LENGTH: usize = 15;
+// Repeat_t only allows 2 to 99 repetitions
+const MAX_REPETITIONS: u32 = 99;
+
+fn short_name(name: &str) -> String {
+    escape(&name.trim().chars().take(NAME_LENGTH).collect::<String>())
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+fn duration(goal: &Goal) -> String {
+    match goal {
+        Goal::Time(duration) => format!(
+            "<Duration xsi:type=\"Time_t\"><Seconds>{}</Seconds></Duration>",
+            duration.as_secs_f64().round() as u64
+        ),
+        Goal::Distance(meters) => format!(
+            "<Duration xsi:type=\"Distance_t\"><Meters>{}</Meters></Duration>",
+            meters.round() as u64
+        ),
+        Goal::Manual => "<Duration xsi:type=\"UserInitiated_t\"/>".to_string(),
+    }
+}
+
+fn target(target: &Target) -> String {
+    match *target {
+        Target::HeartRateZone { lower, upper } if lower == upper => format!(
+            "<Target xsi:type=\"HeartRate_t\"><HeartRateZone xsi:type=\"PredefinedHeartRateZone_t\"><Number>{}</Number></HeartRateZone></Target>",
+            lower
+        ),
+        Target::HeartRateZone { lower, upper } => format!(
+            "<Target xsi:type=\"HeartRate_t\"><HeartRateZone xsi:type=\"CustomHeartRateZone_t\"><Low xsi:type=\"HeartRateAsPercentOfMax_t\"><Value>{}</Value></Low><High xsi:type=\"HeartRateAsPercentOfMax_t\"><Value>{}</Value></High></HeartRateZone></Target>",
+            heart_rate_percent(lower, false),
+            heart_rate_percent(upper, true)
+        ),
+        Target::Speed { lower, upper } => format!(
+            "<Target xsi:type=\"Speed_t\"><SpeedZone xsi:type=\"CustomSpeedZone_t\"><ViewAs>Speed</ViewAs><LowInMetersPerSecond>{:.3}</LowInMetersPerSecond><HighInMetersPerSecond>{:.3}</HighInMetersPerSecond></SpeedZone></Target>",
+            lower / 3.6,
+            upper / 3.6
+        ),
+        Target::Free | Target::PowerZone { .. } => "<Target xsi:type=\"None_t\"/>".to_string(),
+    }
+}
+
+struct Writer {
+    text: String,
+    next_id: u32,
+}
+
+impl Writer {
+    // element is Step for the workout's own steps and Child inside repeats
+    fn step(&mut self, element: &str, step: &Step, depth: usize) {
+        let tabs = indent(depth);
+        self.text += &format!("{}<{} xsi:type=\"Step_t\">\n", tabs, element);
+        self.text += &format!("{}  <StepId>{}</StepId>\n", tabs, self.next_id);
+        self.next_id += 1;
+
+        if !step.name.trim().is_empty() {
+            self.text += &format!("{}  <Name>{}</Name>\n", tabs, short_name(&step.name));
+        }
+        self.text += &format!("{}  {}\n", tabs, duration(&step.goal));
+        self.text += &format!("{}  <Intensity>Active</Intensity>\n", tabs);
+        self.text += &format!("{}  {}\n", tabs, target(&step.target));
+        self.text += &format!("{}</{}>\n", tabs, element);
+    }
+
+    fn blocks(&mut self, element: &str, blocks: &[Block], depth: usize) -> Result<(), PolarError> {
+        for block in blocks {
+            match block {
+                Block::Step(step) => self.step(element, step, depth),
+                Block::Repeat { times: 0, .. } => (),
+                Block::Repeat { times: 1, blocks } => self.blocks(element, blocks, depth)?,
+                Block::Repeat { times, blocks } => {
+                    if *times > MAX_REPETITIONS {
+                        return Err(PolarError::new(format!(
+                            "TCX workouts can't repeat more than {} times, this one repeats {}",
+                            MAX_REPETITIONS, times
+                        )));
+                    }
+
+                    let tabs = indent(depth);
+                    self.text += &format!("{}<{} xsi:type=\"Repeat_t\">\n", tabs, element);
+                    self.text += &format!("{}  <StepId>{}</StepId>\n", tabs, self.next_id);
+                    self.next_id += 1;
+                    self.text += &format!("{}  <Repetitions>{}</Repetitions>\n", tabs, times);
+                    self.blocks("Child", blocks, depth + 1)?;
+                    self.text += &format!("{}</{}>\n", tabs, element);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn write(session: &PbTrainingSessionTarget) -> Result<String, PolarError> {
+    let sport = match session_sport(session) {
+        Some(SPORT_RUNNING) => "Running",
+        Some(SPORT_CYCLING) => "Biking",
+        _ => "Other",
+    };
+
+    let mut writer = Writer {
+        text: String::new(),
+        next_id: 1,
+    };
+
+    writer.text += "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+    writer.text += "<TrainingCenterDatabase xmlns=\"http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n";
+    writer.text += "  <Workouts>\n";
+    writer.text += &format!("    <Workout Sport=\"{}\">\n", sport);
+    writer.text += &format!("      <Name>{}</Name>\n", short_name(&session.name.text));
+    writer.blocks("Step", &session_blocks(session)?, 3)?;
+    if let Some(description) = &session.description {
+        writer.text += &format!("      <Notes>{}</Notes>\n", escape(&description.text));
+    }
+    writer.text += "    </Workout>\n";
+    writer.text += "  </Workouts>\n";
+    writer.text += "</TrainingCenterDatabase>\n";
+
+    Ok(writer.text)
+}
@@ -0,0 +1,126 @@
+// The plain text workouts of online planners like intervals.icu: one "- " line per step, and
+// repeats as a "4x" line followed by the repeated steps:
+//
+//     - Warm up 10m Z2 HR
+//
+//     4x
+//     - On 2m Z5
+//     - Off 1m Z1
+//
+// Repeats can't be nested, the inner ones are written out in full
+use super::{heart_rate_percent, pace, power_percent, session_blocks, Block, Goal, Step, Target};
+use crate::polar_watch::PolarError;
+use polar::data::PbTrainingSessionTarget;
+use std::time::Duration;
+
+fn duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64().round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+
+    let mut result = String::new();
+    if hours > 0 {
+        result += &format!("{}h", hours);
+    }
+    if minutes > 0 {
+        result += &format!("{}m", minutes);
+    }
+    if seconds > 0 || result.is_empty() {
+        result += &format!("{}s", seconds);
+    }
+
+    result
+}
+
+fn distance(meters: f32) -> String {
+    if meters >= 1000.0 {
+        let kilometers = format!("{:.2}", meters / 1000.0);
+        format!(
+            "{}km",
+            kilometers.trim_end_matches('0').trim_end_matches('.')
+        )
+    } else {
+        format!("{}mtr", meters.round() as u32)
+    }
+}
+
+fn target(target: &Target) -> Option<String> {
+    match *target {
+        Target::Free => None,
+        Target::HeartRateZone { lower, upper } if lower == upper => Some(format!("Z{} HR", lower)),
+        Target::HeartRateZone { lower, upper } => Some(format!(
+            "{}-{}% HR",
+            heart_rate_percent(lower, false),
+            heart_rate_percent(upper, true)
+        )),
+        // The slower end first
+        Target::Speed { lower, upper } => Some(format!("{}-{}/km Pace", pace(upper), pace(lower))),
+        Target::PowerZone { lower, upper } if lower == upper => Some(format!("Z{}", lower)),
+        Target::PowerZone { lower, upper } => Some(format!(
+            "{}-{}%",
+            power_percent(lower, false),
+            power_percent(upper, true)
+        )),
+    }
+}
+
+fn step_line(step: &Step) -> String {
+    let mut parts = vec!["-".to_string()];
+
+    if !step.name.trim().is_empty() {
+        parts.push(step.name.trim().to_string());
+    }
+
+    parts.push(match step.goal {
+        Goal::Time(time) => duration(time),
+        Goal::Distance(meters) => distance(meters),
+        Goal::Manual => "press lap".to_string(),
+    });
+
+    if let Some(target) = target(&step.target) {
+        parts.push(target);
+    }
+
+    parts.join(" ") + "\n"
+}
+
+// Steps of a repeat, with the inner repeats written out
+fn flat_lines(blocks: &[Block], lines: &mut String) {
+    for block in blocks {
+        match block {
+            Block::Step(step) => *lines += &step_line(step),
+            Block::Repeat { times, blocks } => {
+                for _ in 0..*times {
+                    flat_lines(blocks, lines);
+                }
+            }
+        }
+    }
+}
+
+pub fn write(session: &PbTrainingSessionTarget) -> Result<String, PolarError> {
+    let mut sections = vec![];
+    let mut steps = String::new();
+
+    for block in session_blocks(session)? {
+        match block {
+            Block::Step(step) => steps += &step_line(&step),
+            Block::Repeat { times: 0, .. } => (),
+            Block::Repeat { times: 1, blocks } => flat_lines(&blocks, &mut steps),
+            Block::Repeat { times, blocks } => {
+                if !steps.is_empty() {
+                    sections.push(std::mem::take(&mut steps));
+                }
+
+                let mut repeated = format!("{}x\n", times);
+                flat_lines(&blocks, &mut repeated);
+                sections.push(repeated);
+            }
+        }
+    }
+
+    if !steps.is_empty() {
+        sections.push(steps);
+    }
+
+    Ok(sections.join("\n"))
+}
@@ -0,0 +1,112 @@
+// The phases in the order the watch plays them, with every repeat written out. Offsets are only
+// known until the first phase that doesn't end after a fixed time
+use super::{Block, Goal, Target};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineEntry {
+    // From the start of the session, None after a distance or manual phase
+    pub start: Option<Duration>,
+    pub end: Option<Duration>,
+    // Index of the phase in the session, from 1
+    pub phase: usize,
+    pub name: String,
+    // Repetition, counting from 1, and times of each repeat around the phase, outermost first
+    pub repetitions: Vec<(u32, u32)>,
+    pub goal: Goal,
+    pub target: Target,
+}
+
+struct Frame<'a> {
+    blocks: &'a [Block],
+    position: usize,
+    repetition: u32,
+    times: u32,
+    // Phase index before the first phase of the frame
+    first_phase: usize,
+}
+
+pub struct Timeline<'a> {
+    stack: Vec<Frame<'a>>,
+    phase: usize,
+    elapsed: Option<Duration>,
+}
+
+impl<'a> Timeline<'a> {
+    pub fn new(blocks: &'a [Block]) -> Timeline<'a> {
+        Timeline {
+            stack: vec![Frame {
+                blocks,
+                position: 0,
+                repetition: 1,
+                times: 1,
+                first_phase: 0,
+            }],
+            phase: 0,
+            elapsed: Some(Duration::from_secs(0)),
+        }
+    }
+}
+
+impl<'a> Iterator for Timeline<'a> {
+    type Item = TimelineEntry;
+
+    fn next(&mut self) -> Option<TimelineEntry> {
+        loop {
+            let frame = self.stack.last_mut()?;
+
+            if frame.position == frame.blocks.len() {
+                if frame.repetition < frame.times {
+                    frame.repetition += 1;
+                    frame.position = 0;
+                    self.phase = frame.first_phase;
+                } else {
+                    self.stack.pop();
+                }
+                continue;
+            }
+
+            let block = &frame.blocks[frame.position];
+            frame.position += 1;
+
+            match block {
+                // Repeats done zero times have no phases in the session
+                Block::Repeat { times: 0, .. } => (),
+                Block::Repeat { times, blocks } => self.stack.push(Frame {
+                    blocks,
+                    position: 0,
+                    repetition: 1,
+                    times: *times,
+                    first_phase: self.phase,
+                }),
+                Block::Step(step) => {
+                    self.phase += 1;
+
+                    let start = self.elapsed;
+                    let end = match step.goal {
+                        Goal::Time(duration) => start.map(|start| start + duration),
+                        _ => None,
+                    };
+                    self.elapsed = end;
+
+                    return Some(TimelineEntry {
+                        start,
+                        end,
+                        phase: self.phase,
+                        name: step.name.clone(),
+                        repetitions: self.stack[1..]
+                            .iter()
+                            .map(|frame| (frame.repetition, frame.times))
+                            .collect(),
+                        goal: step.goal,
+                        target: step.target,
+                    });
+                }
+            }
+        }
+    }
+}
+
+pub fn timeline(blocks: &[Block]) -> Timeline<'_> {
+    Timeline::new(blocks)
+}
@@ -0,0 +1,234 @@
+// Just enough XML for workout files: elements, attributes and text. Comments, processing
+// instructions and doctypes are skipped, namespaces are kept as part of the names
+use crate::polar_watch::PolarError;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    // Attribute names are compared ignoring case, files written by hand aren't consistent
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children
+            .iter()
+            .find(|child| child.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|child| child.text.trim())
+    }
+}
+
+fn error<S: Into<String>>(message: S) -> PolarError {
+    PolarError::new(format!("Invalid XML: {}", message.into()))
+}
+
+pub fn unescape(text: &str) -> Result<String, PolarError> {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        result += &rest[..start];
+        rest = &rest[start..];
+
+        let end = rest.find(';').ok_or_else(|| error("entity without ';'"))?;
+        let entity = &rest[1..end];
+
+        let character = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(decimal) = entity.strip_prefix('#') {
+                    decimal.parse().ok()
+                } else {
+                    None
+                };
+
+                code.and_then(std::char::from_u32)
+                    .ok_or_else(|| error(format!("unknown entity '&{};'", entity)))?
+            }
+        };
+
+        result.push(character);
+        rest = &rest[end + 1..];
+    }
+
+    result += rest;
+    Ok(result)
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    // Moves past the next occurrence of pattern
+    fn skip_past(&mut self, pattern: &str) -> Result<&'a str, PolarError> {
+        let rest = self.rest();
+        match rest.find(pattern) {
+            Some(index) => {
+                self.position += index + pattern.len();
+                Ok(&rest[..index])
+            }
+            None => Err(error(format!("missing '{}'", pattern))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn name(&mut self) -> Result<String, PolarError> {
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/' || c == '=')
+            .unwrap_or(rest.len());
+
+        if end == 0 {
+            return Err(error("expected a name"));
+        }
+
+        self.position += end;
+        Ok(rest[..end].to_string())
+    }
+
+    // Skips comments, processing instructions, doctypes and whitespace between elements
+    fn skip_misc(&mut self) -> Result<(), PolarError> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+
+            if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!") && !rest.starts_with("<![CDATA[") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn element(&mut self) -> Result<Element, PolarError> {
+        if !self.rest().starts_with('<') {
+            return Err(error("expected an element"));
+        }
+        self.position += 1;
+
+        let mut element = Element {
+            name: self.name()?,
+            ..Element::default()
+        };
+
+        // Attributes
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+
+            if rest.starts_with("/>") {
+                self.position += 2;
+                return Ok(element);
+            }
+
+            if rest.starts_with('>') {
+                self.position += 1;
+                break;
+            }
+
+            let key = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(error(format!("attribute '{}' without a value", key)));
+            }
+            self.position += 1;
+            self.skip_whitespace();
+
+            let quote = match self.rest().chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => quote,
+                _ => return Err(error(format!("value of '{}' isn't quoted", key))),
+            };
+            self.position += 1;
+
+            let value = self.skip_past(&quote.to_string())?;
+            element.attributes.push((key, unescape(value)?));
+        }
+
+        // Content
+        loop {
+            let rest = self.rest();
+
+            if rest.starts_with("</") {
+                self.position += 2;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(error(format!("'{}' closed with '{}'", element.name, name)));
+                }
+
+                self.skip_past(">")?;
+                return Ok(element);
+            } else if rest.starts_with("<![CDATA[") {
+                self.position += "<![CDATA[".len();
+                element.text += self.skip_past("]]>")?;
+            } else if rest.starts_with("<!--") || rest.starts_with("<?") {
+                self.skip_misc()?;
+            } else if rest.starts_with('<') {
+                let child = self.element()?;
+                element.children.push(child);
+            } else if rest.is_empty() {
+                return Err(error(format!("'{}' is never closed", element.name)));
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                element.text += &unescape(&rest[..end])?;
+                self.position += end;
+            }
+        }
+    }
+}
+
+// Parses a document and returns its root element
+pub fn parse(text: &str) -> Result<Element, PolarError> {
+    let mut parser = Parser {
+        text: text.trim_start_matches('\u{feff}'),
+        position: 0,
+    };
+
+    parser.skip_misc()?;
+    let root = parser.element()?;
+    parser.skip_misc()?;
+
+    if !parser.rest().is_empty() {
+        return Err(error("content after the root element"));
+    }
+
+    Ok(root)
+}
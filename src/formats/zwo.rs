@@ -0,0 +1,155 @@
+// Zwift workouts. Powers are fractions of FTP and durations are in seconds:
+//
+//     <workout_file>
+//         <name>Over unders</name>
+//         <sportType>bike</sportType>
+//         <workout>
+//             <Warmup Duration="600" PowerLow="0.4" PowerHigh="0.75"/>
+//             <IntervalsT Repeat="4" OnDuration="120" OffDuration="60" OnPower="1.05" OffPower="0.55"/>
+//             <Cooldown Duration="300" PowerLow="0.6" PowerHigh="0.3"/>
+//         </workout>
+//     </workout_file>
+use super::xml::{self, Element};
+use super::{
+    power_zone, seconds, session, Block, Goal, Step, Target, SPORT_CYCLING, SPORT_RUNNING,
+};
+use crate::polar_watch::PolarError;
+use polar::data::PbTrainingSessionTarget;
+
+fn error(step: &Element, message: &str) -> PolarError {
+    PolarError::new(format!("Invalid .zwo step <{}>: {}", step.name, message))
+}
+
+fn number(step: &Element, name: &str) -> Result<f32, PolarError> {
+    let value = step
+        .attribute(name)
+        .ok_or_else(|| error(step, &format!("missing {}", name)))?;
+
+    match value.trim().parse::<f32>() {
+        Ok(number) if number.is_finite() && number >= 0.0 => Ok(number),
+        _ => Err(error(step, &format!("invalid {} '{}'", name, value))),
+    }
+}
+
+fn duration(step: &Element, name: &str) -> Result<Goal, PolarError> {
+    let value = number(step, name)?;
+    seconds(value.into())
+        .map(Goal::Time)
+        .ok_or_else(|| error(step, &format!("{} of {} seconds is too long", name, value)))
+}
+
+fn repeat(step: &Element) -> Result<u32, PolarError> {
+    let value = number(step, "Repeat")?;
+    if value.fract() != 0.0 || value > u32::MAX as f32 {
+        return Err(error(step, &format!("invalid Repeat '{}'", value)));
+    }
+
+    Ok(value as u32)
+}
+
+fn zone(power: f32) -> Target {
+    let zone = power_zone(power);
+    Target::PowerZone {
+        lower: zone,
+        upper: zone,
+    }
+}
+
+// Ramps become one phase spanning the zones of both ends
+fn ramp(step: &Element) -> Result<Target, PolarError> {
+    let low = power_zone(number(step, "PowerLow")?);
+    let high = power_zone(number(step, "PowerHigh")?);
+
+    Ok(Target::PowerZone {
+        lower: low.min(high),
+        upper: low.max(high),
+    })
+}
+
+fn step_block(step: &Element) -> Result<Option<Block>, PolarError> {
+    let single = |name: &str, goal, target| {
+        Ok(Some(Block::Step(Step {
+            name: name.to_string(),
+            goal,
+            target,
+        })))
+    };
+
+    match step.name.as_str() {
+        "Warmup" => single("Warm up", duration(step, "Duration")?, ramp(step)?),
+        "Cooldown" => single("Cool down", duration(step, "Duration")?, ramp(step)?),
+        "Ramp" => single("Ramp", duration(step, "Duration")?, ramp(step)?),
+        "SteadyState" => single(
+            "Steady",
+            duration(step, "Duration")?,
+            zone(number(step, "Power")?),
+        ),
+        "FreeRide" | "MaxEffort" => single("Free", duration(step, "Duration")?, Target::Free),
+        "IntervalsT" => {
+            let times = repeat(step)?;
+
+            Ok(Some(Block::Repeat {
+                times,
+                blocks: vec![
+                    Block::Step(Step {
+                        name: "On".to_string(),
+                        goal: duration(step, "OnDuration")?,
+                        target: zone(number(step, "OnPower")?),
+                    }),
+                    Block::Step(Step {
+                        name: "Off".to_string(),
+                        goal: duration(step, "OffDuration")?,
+                        target: zone(number(step, "OffPower")?),
+                    }),
+                ],
+            }))
+        }
+        // Text shown on screen in Zwift, nothing the watch can use
+        "textevent" | "TextEvent" => Ok(None),
+        _ => Err(error(step, "unsupported step")),
+    }
+}
+
+// Blocks of the <workout> element
+pub fn blocks(workout: &Element) -> Result<Vec<Block>, PolarError> {
+    let mut blocks = vec![];
+    for step in &workout.children {
+        if let Some(block) = step_block(step)? {
+            blocks.push(block);
+        }
+    }
+
+    Ok(blocks)
+}
+
+// default_name is used when the file has no <name>
+pub fn parse(text: &str, default_name: &str) -> Result<PbTrainingSessionTarget, PolarError> {
+    let root = xml::parse(text)?;
+    if !root.name.eq_ignore_ascii_case("workout_file") {
+        return Err(PolarError::new(format!(
+            "Not a .zwo workout, the root element is <{}>",
+            root.name
+        )));
+    }
+
+    let workout = root
+        .child("workout")
+        .ok_or_else(|| PolarError::new("The .zwo file has no <workout>"))?;
+
+    let name = match root.child_text("name") {
+        Some(name) if !name.is_empty() => name,
+        _ => default_name,
+    };
+
+    let sport = match root.child_text("sportType") {
+        Some(sport) if sport.eq_ignore_ascii_case("run") => Some(SPORT_RUNNING),
+        _ => Some(SPORT_CYCLING),
+    };
+
+    session(
+        name,
+        root.child_text("description").unwrap_or_default(),
+        sport,
+        &blocks(workout)?,
+    )
+}
@@ -1,15 +1,30 @@
+pub mod backup;
+pub mod compliance;
+pub mod config;
+pub mod exercise;
+pub mod formats;
 pub mod polar_watch;
+pub mod progress;
+pub mod shell;
+pub mod sync;
+pub mod templates;
+pub mod zones;
 
 extern crate nfd;
 extern crate polar_prost as polar;
 
 #[allow(unused_imports)]
-use log::{error, info};
+use log::{error, info, warn};
 
+use backup::RestoreStep;
 use polar_prost::Message;
-use polar_watch::{PolarError, PolarWatch};
+use polar_watch::{
+    human_size, DirEntry, PolarError, PolarWatch, UsbConfig, Verification, DISK_FULL,
+};
+use progress::TransferProgress;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+pub const FAVOURITES_PATH: &str = "/U/0/FAV/";
 
 pub fn read_bytes<S>(path: S) -> std::io::Result<Vec<u8>>
 where
@@ -34,37 +49,237 @@ pub fn describe_favourite(data: Vec<u8>) -> Option<String> {
         _ => return None,
     };
 
-    let phases = match &session.exercise_target[0].phases {
-        Some(phases) => phases,
-        _ => return None,
-    };
-
-    match TrainingBlock::new(&phases.phase) {
-        Some(session) => Some(session.describe()),
+    match formats::session_blocks(&session) {
+        Ok(blocks) => Some(describe_repeat(1, &blocks)),
         _ => None,
     }
 }
 
-pub fn upload_favourites(paths: Vec<String>) -> Result<(), PolarError> {
-    let mut files = Vec::new();
-    for path in paths.clone() {
-        match read_bytes(path.clone()) {
-            Ok(data) => {
-                files.push(data.clone());
+// The whole session is described as a repeat done once
+fn describe_repeat(times: u32, blocks: &[formats::Block]) -> String {
+    let duration = blocks.iter().fold(Duration::from_secs(0), |total, block| {
+        total.saturating_add(block.duration())
+    });
+    let mut result = format!("Repeat x{} [{}]", times, human_duration(duration));
+
+    for block in blocks {
+        let description = match block {
+            formats::Block::Step(step) => {
+                format!("{} {}", step.name, human_duration(block.duration()))
             }
-            error => {
+            formats::Block::Repeat { times, blocks } => describe_repeat(*times, blocks),
+        };
+
+        result += &("\n\t".to_string() + &description.replace("\n", "\n\t"));
+    }
+
+    result
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    pub verification: Verification,
+    pub usb: UsbConfig,
+    // Wait for a watch to be connected instead of failing when there is none
+    pub wait_for_device: bool,
+    // How long to wait, forever if None
+    pub wait_timeout: Option<Duration>,
+    // Directory with one numbered directory per favourite, ending with /
+    pub favourites_path: String,
+    // How workouts from other applications are converted
+    pub import: formats::ImportOptions,
+    // Fail before uploading favourites for a sport without a profile. Turning it off saves
+    // reading every sport profile on the watch
+    pub check_sports: bool,
+}
+
+impl Default for UploadOptions {
+    fn default() -> UploadOptions {
+        UploadOptions {
+            verification: Verification::default(),
+            usb: UsbConfig::default(),
+            wait_for_device: false,
+            wait_timeout: None,
+            favourites_path: FAVOURITES_PATH.to_string(),
+            import: formats::ImportOptions::default(),
+            check_sports: true,
+        }
+    }
+}
+
+pub fn read_favourites(
+    paths: &[String],
+    import: &formats::ImportOptions,
+) -> Result<Vec<Vec<u8>>, PolarError> {
+    // Workouts from other applications are converted, anything else is uploaded as it is
+    paths
+        .iter()
+        .map(|path| formats::read_session_with(path, import))
+        .collect()
+}
+
+pub fn upload_favourites(paths: Vec<String>, options: UploadOptions) -> Result<(), PolarError> {
+    let files = read_favourites(&paths, &options.import)?;
+
+    let mut context = rusb::Context::new()?;
+    let mut watch = if options.wait_for_device {
+        println!("Waiting for a watch to be connected...");
+        PolarWatch::wait_for_device(&mut context, &options.usb, options.wait_timeout)?
+    } else {
+        PolarWatch::find_one_with(&mut context, &options.usb)?
+    };
+
+    upload_favourites_to(&mut watch, &paths, &files, &options)
+}
+
+// Favourites can only be started under a sport that has a profile on the watch. Watches whose
+// profiles can't be listed are not checked
+fn check_sports(
+    watch: &mut PolarWatch,
+    paths: &[String],
+    files: &[Vec<u8>],
+) -> Result<(), PolarError> {
+    let installed: Vec<u64> = match watch.sport_profiles() {
+        Ok(profiles) => profiles
+            .iter()
+            .filter_map(|profile| profile.sport_id)
+            .collect(),
+        Err(error) => {
+            info!(
+                "Not checking sports, failed to list the profiles: {:?}",
+                error
+            );
+            return Ok(());
+        }
+    };
+
+    check_installed_sports(&installed, paths, files)
+}
+
+// Files that aren't training sessions are not checked, and neither is anything when no profile
+// says which sport it is for
+fn check_installed_sports(
+    installed: &[u64],
+    paths: &[String],
+    files: &[Vec<u8>],
+) -> Result<(), PolarError> {
+    if installed.is_empty() {
+        return Ok(());
+    }
+
+    for (path, file) in paths.iter().zip(files) {
+        let session = match formats::decode_session(file) {
+            Ok(session) => session,
+            Err(_) => continue,
+        };
+
+        let sports = session
+            .exercise_target
+            .iter()
+            .filter_map(|target| target.sport_id.as_ref())
+            .chain(session.sport_id.as_ref())
+            .map(|sport| sport.value);
+
+        for sport in sports {
+            if !installed.contains(&sport) {
                 return Err(PolarError::new(format!(
-                    "Failed to read file '{}'\n\t{:?}",
-                    path, error
-                )))
+                    "'{}' is for sport {}, which has no profile on the watch. Add one with 'sports add {}'",
+                    path, sport, sport
+                )));
             }
         }
     }
 
-    let mut context = rusb::Context::new()?;
-    let mut watch = PolarWatch::find_one(&mut context)?;
+    Ok(())
+}
+
+// Replaces the favourites on an already opened watch, putting the old ones back if anything fails
+pub fn upload_favourites_to(
+    watch: &mut PolarWatch,
+    paths: &[String],
+    files: &[Vec<u8>],
+    options: &UploadOptions,
+) -> Result<(), PolarError> {
+    watch.set_verification(options.verification);
+    if options.check_sports {
+        check_sports(watch, paths, files)?;
+    }
+
+    // Keep a copy of the current favourites, so that we can put them back if anything fails
+    let favourites_path = options.favourites_path.as_str();
+    let snapshot = backup::snapshot(watch, favourites_path)?;
+    info!(
+        "Saved {} entries from the current favourites",
+        snapshot.len()
+    );
+
+    let mut results = vec![];
+    let result = write_favourites(watch, favourites_path, paths, files, &mut results)
+        .and_then(|_| verify_favourites(watch, favourites_path, files));
+
+    print_upload_summary(paths, files, &results);
+
+    if let Err(error) = result {
+        error!("Upload failed, rolling back: {:?}", error);
+        println!("\nUpload failed, putting back the favourites that were on the watch before...");
+
+        match restore_favourites(watch, favourites_path, &snapshot) {
+            Ok(()) => println!("Rollback finished, the watch has its old favourites back"),
+            Err(rollback_error) => {
+                error!("Rollback failed: {:?}", rollback_error);
+                println!(
+                    "Rollback failed as well, some favourites might be missing\n\t{:?}",
+                    rollback_error
+                );
+            }
+        }
+
+        if error.device_code() == Some(DISK_FULL) {
+            warn_disk_full(watch, files);
+        }
+
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+// The watch can't tell us how much space is left, but what it is using helps deciding what to
+// delete. Only measured after a "disk full" error, since it lists every directory on the watch
+fn warn_disk_full(watch: &mut PolarWatch, files: &[Vec<u8>]) {
+    let upload_size: usize = files.iter().map(|file| file.len()).sum();
+    let usage = match watch.disk_usage("/U/0/") {
+        Ok(usage) => usage.to_string(),
+        Err(error) => {
+            info!("Failed to compute disk usage: {:?}", error);
+            "an unknown amount".to_string()
+        }
+    };
+
+    warn!(
+        "Watch is full, using {}, uploading {}",
+        usage,
+        human_size(upload_size as u64)
+    );
+    println!(
+        "\nWarning: the watch is full. It is using {} and the favourites need {}. Sync and delete old exercises to make room",
+        usage,
+        human_size(upload_size as u64)
+    );
+}
+
+fn write_favourites(
+    watch: &mut PolarWatch,
+    favourites_path: &str,
+    paths: &[String],
+    files: &[Vec<u8>],
+    results: &mut Vec<Result<(), String>>,
+) -> Result<(), PolarError> {
+    info!("Deleting old favorite files in {}", favourites_path);
+    watch.recursive_delete(favourites_path)?;
 
-    watch.delete_all_favorites()?;
+    let total: usize = files.iter().map(|file| file.len()).sum();
+    let mut progress = TransferProgress::new(total);
 
     for slot in 0..files.len() {
         println!("Uploading {}:", paths[slot]);
@@ -74,37 +289,135 @@ pub fn upload_favourites(paths: Vec<String>) -> Result<(), PolarError> {
             _ => println!("Failed to parse file, trying to upload anyway\n"),
         }
 
-        let watch_path = format!("/U/0/FAV/{:02}", slot);
+        let watch_path = format!("{}{:02}", favourites_path, slot);
 
         // It's okay if this fails, directory might already exist
         let _ = watch.mkdir(watch_path.clone());
 
-        watch.send_file(watch_path + "/TST.BPB", files[slot].as_slice())?;
+        let size = files[slot].len();
+        let result = watch.send_file_with_progress(
+            watch_path + "/TST.BPB",
+            files[slot].as_slice(),
+            &mut |done, _| progress.update(done, size),
+        );
+
+        if let Err(error) = result {
+            // Leave the progress line where it stopped
+            println!();
+            results.push(Err(error_message(&error)));
+            return Err(error);
+        }
+
+        progress.finish_file(size);
+        results.push(Ok(()));
+    }
+
+    Ok(())
+}
+
+// Checks that every slot ended up with a file of the right size
+fn verify_favourites(
+    watch: &mut PolarWatch,
+    favourites_path: &str,
+    files: &[Vec<u8>],
+) -> Result<(), PolarError> {
+    for (slot, file) in files.iter().enumerate() {
+        let slot_path = format!("{}{:02}/", favourites_path, slot);
+        let entries = watch.dir(slot_path.clone())?;
+
+        match entries.iter().find(|entry| entry.name == "TST.BPB") {
+            Some(entry) if entry.size == file.len() as u64 => (),
+            Some(entry) => {
+                return Err(PolarError::new(format!(
+                    "{}TST.BPB has {} bytes instead of {}",
+                    slot_path,
+                    entry.size,
+                    file.len()
+                )))
+            }
+            None => return Err(PolarError::new(format!("{}TST.BPB is missing", slot_path))),
+        }
     }
 
     Ok(())
 }
 
-fn split_at<I>(vector: Vec<I>, picker: &dyn Fn(&I) -> bool) -> (Vec<I>, Vec<I>) {
-    let mut first = vec![];
-    let mut second = vec![];
+// Puts back what snapshot had. The slot directories made by the failed upload are deleted too, so
+// the watch doesn't show them as empty favourites
+fn restore_favourites(
+    watch: &mut PolarWatch,
+    favourites_path: &str,
+    snapshot: &[RestoreStep],
+) -> Result<(), PolarError> {
+    let entries = watch
+        .walk(favourites_path)
+        .collect::<Result<Vec<DirEntry>, PolarError>>()?;
+
+    watch.recursive_delete(favourites_path)?;
+    for path in backup::new_directories(snapshot, &entries) {
+        watch.delete_directory(path)?;
+    }
 
-    let mut use_second = false;
-    for item in vector {
-        use_second = use_second || picker(&item);
+    backup::restore(watch, snapshot)
+}
 
-        if use_second {
-            second.push(item);
-        } else {
-            first.push(item);
-        }
+fn error_message(error: &PolarError) -> String {
+    match error {
+        PolarError::LibusbError { error } => format!("{:?}", error),
+        PolarError::PolarError { error } => error.clone(),
+        PolarError::DeviceError { error, .. } => error.clone(),
+        PolarError::MismatchError { error } => error.to_string(),
+    }
+}
+
+// Table with one line per slot, saying whether its file made it to the watch
+fn print_upload_summary(paths: &[String], files: &[Vec<u8>], results: &[Result<(), String>]) {
+    println!("\n{:<6}{:<40}{:>12}  Status", "Slot", "File", "Size");
+
+    for slot in 0..paths.len() {
+        let status = match results.get(slot) {
+            Some(Ok(())) => "ok".to_string(),
+            Some(Err(error)) => format!("failed: {}", error),
+            None => "not sent".to_string(),
+        };
+
+        let name = std::path::Path::new(&paths[slot])
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| paths[slot].clone());
+
+        println!(
+            "{:<6}{:<40}{:>12}  {}",
+            format!("{:02}", slot),
+            name,
+            human_size(files[slot].len() as u64),
+            status
+        );
+    }
+}
+
+// Classic hex dump with 16 bytes per line: offset, bytes in hex and printable characters
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut result = String::new();
+
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = chunk
+            .iter()
+            .map(|byte| match byte {
+                0x20..=0x7e => *byte as char,
+                _ => '.',
+            })
+            .collect();
+
+        result += &format!("{:08x}  {:<47}  |{}|\n", line * 16, hex.join(" "), text);
     }
 
-    (first, second)
+    result
 }
 
 use std::time::Duration;
-fn human_duration(duration: Duration) -> String {
+pub fn human_duration(duration: Duration) -> String {
     let seconds = duration.as_secs();
 
     let hours = seconds / 3600;
@@ -128,159 +441,45 @@ fn human_duration(duration: Duration) -> String {
     result
 }
 
-#[derive(Debug)]
-enum TrainingBlock {
-    Repeat {
-        id: u32,
-        times: u32,
-        block: Vec<TrainingBlock>,
-    },
-    Phase {
-        id: u32,
-        name: String,
-        duration: Duration,
-    },
-}
-
-impl TrainingBlock {
-    fn new(phases: &std::vec::Vec<polar::data::PbPhase>) -> Option<TrainingBlock> {
-        let mut result = vec![];
-
-        for (id, phase) in phases.iter().enumerate() {
-            result.push(TrainingBlock::from(id as u32, phase.clone()));
-
-            match phase.jump_index {
-                Some(jump_id) => {
-                    let (new_result, block) = split_at(result, &|phase| phase.id() == jump_id);
-
-                    let times = match phase.repeat_count {
-                        Some(value) => value + 1,
-                        None => 0,
-                    };
-
-                    result = new_result;
-                    match block.first() {
-                        Some(phase) => result.push(TrainingBlock::Repeat {
-                            id: phase.id(),
-                            times,
-                            block,
-                        }),
-                        None => return None,
-                    };
-                }
-                None => (),
-            }
-        }
-
-        Some(TrainingBlock::Repeat {
-            id: 0,
-            times: 1,
-            block: result,
-        })
-    }
-
-    fn from(id: u32, phase: polar::data::PbPhase) -> TrainingBlock {
-        let id = id + 1;
-        let duration = match phase.goal.duration {
-            Some(dur) => {
-                Duration::from_millis(dur.millis.unwrap_or(0).into())
-                    + Duration::from_secs(dur.seconds.unwrap_or(0).into())
-                    + Duration::from_secs((60 * dur.minutes.unwrap_or(0)).into())
-                    + Duration::from_secs((3600 * dur.hours.unwrap_or(0)).into())
-            }
-            None => Duration::from_secs(0),
-        };
-
-        TrainingBlock::Phase {
-            id,
-            name: phase.name.text,
-            duration,
-        }
-    }
-
-    fn id(&self) -> u32 {
-        match self {
-            TrainingBlock::Phase {
-                id,
-                name: _,
-                duration: _,
-            } => id,
-            TrainingBlock::Repeat {
-                id,
-                times: _,
-                block: _,
-            } => id,
-        }
-        .clone()
-    }
-
-    fn describe(&self) -> String {
-        match self {
-            TrainingBlock::Phase {
-                id: _,
-                name,
-                duration,
-            } => format!("{} {}", name, human_duration(duration.clone())),
-            TrainingBlock::Repeat {
-                id: _,
-                times,
-                block,
-            } => {
-                let duration = self.inner_duration();
-                let mut result = format!("Repeat x{} [{}]", times, human_duration(duration));
-
-                for item in block {
-                    result += &("\n\t".to_string() + &item.describe().replace("\n", "\n\t"));
-                }
-
-                result.to_string()
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{Block, Goal, Step, Target};
+    use crate::polar_watch::encode;
+
+    #[test]
+    fn favourites_need_a_profile_for_their_sport() {
+        let blocks = vec![Block::Step(Step {
+            name: "Run".to_string(),
+            goal: Goal::Time(Duration::from_secs(600)),
+            target: Target::Free,
+        })];
+        let file = |sport| encode(formats::session("Run", "", sport, &blocks).unwrap());
+        let paths = vec!["run.BPB".to_string(), "notes.txt".to_string()];
+        let files = vec![file(Some(1)).unwrap(), b"not a session".to_vec()];
+
+        assert!(check_installed_sports(&[1, 2], &paths, &files).is_ok());
+        assert!(check_installed_sports(&[], &paths, &files).is_ok());
+        assert!(check_installed_sports(&[2], &paths, &files).is_err());
+
+        let files = vec![file(None).unwrap(), b"not a session".to_vec()];
+        assert!(check_installed_sports(&[2], &paths, &files).is_ok());
+
+        assert!(UploadOptions::default().check_sports);
     }
 
-    fn duration(&self) -> Duration {
-        match self {
-            TrainingBlock::Phase {
-                id: _,
-                name: _,
-                duration,
-            } => duration.clone(),
-            TrainingBlock::Repeat {
-                id: _,
-                times,
-                block,
-            } => {
-                let mut result = Duration::from_secs(0);
-
-                for phase in block {
-                    result += phase.duration();
-                }
-
-                result * times.clone()
-            }
-        }
-    }
-
-    fn inner_duration(&self) -> Duration {
-        match self {
-            TrainingBlock::Phase {
-                id: _,
-                name: _,
-                duration,
-            } => duration.clone(),
-            TrainingBlock::Repeat {
-                id: _,
-                times: _,
-                block,
-            } => {
-                let mut result = Duration::from_secs(0);
-
-                for phase in block {
-                    result += phase.duration();
-                }
-
-                result
-            }
-        }
+    #[test]
+    fn hex_dump_shows_offsets_bytes_and_text() {
+        assert_eq!(hex_dump(&[]), "");
+        assert_eq!(
+            hex_dump(b"Polar\x00\x01"),
+            format!("00000000  {:<47}  |Polar..|\n", "50 6f 6c 61 72 00 01")
+        );
+
+        let dump = hex_dump(&[b'a'; 17]);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(&format!("|{}|", "a".repeat(16))));
+        assert!(lines[1].starts_with("00000010  61 "));
     }
 }
@@ -1,20 +1,236 @@
 pub extern crate polar_prost as polar;
+#[cfg(feature = "async")]
+mod async_watch;
+mod dir_entry;
+mod hotplug;
+mod physical_data;
 mod polar_usb;
+mod sport_profiles;
+mod wire;
 
+#[cfg(feature = "async")]
+pub use async_watch::AsyncPolarWatch;
+
+pub use dir_entry::{human_size, DirEntry, DiskUsage, Timestamp, Walk};
+pub use hotplug::DeviceMonitor;
+pub use physical_data::{Date, Gender, PhysicalData, PHYSICAL_DATA_PATH};
 pub use polar::{encode, Message};
-pub use polar_usb::polar_error::PolarError;
+pub use polar_usb::notification::{Notification, NotificationHandler};
+pub use polar_usb::pcap::{lua_dissector, FrameField, PcapWriter, FRAME_FIELDS};
+pub use polar_usb::polar_error::{Mismatch, PolarError, DISK_FULL, NO_SUCH_FILE};
+pub use polar_usb::trace::{describe_frame, describe_request, Direction, FrameHeader};
+pub use polar_usb::{Device, Progress, UsbConfig};
+pub use sport_profiles::{copy_for_sport, with_heart_rate_zones, with_position};
+pub use sport_profiles::{AutoLap, HeartRateView, HeartRateZones, SportProfile};
+pub use sport_profiles::{PROFILE_NAME, SPORT_PROFILES_PATH};
 
 use log::{debug, info};
-use polar_usb::{Device, PolarUsb};
+use polar_usb::PolarUsb;
 use rusb::UsbContext;
+use sport_profiles::{find_profile, free_profile_path, sort_profiles};
+use std::io::{Read, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Passes everything through except the last byte, which marks the end of a file
+struct WithoutTrailer<W: Write> {
+    inner: W,
+    last: Option<u8>,
+}
+
+impl<W: Write> Write for WithoutTrailer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (last, rest) = match buf.split_last() {
+            Some(split) => split,
+            None => return Ok(0),
+        };
+
+        if let Some(previous) = self.last.take() {
+            self.inner.write_all(&[previous])?;
+        }
+
+        self.inner.write_all(rest)?;
+        self.last = Some(*last);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// FNV-1a of everything that goes through it, to verify streamed files without keeping them
+struct Checksum<T> {
+    inner: T,
+    hash: u64,
+    len: usize,
+}
+
+impl<T> Checksum<T> {
+    fn new(inner: T) -> Checksum<T> {
+        Checksum {
+            inner,
+            hash: 0xcbf2_9ce4_8422_2325,
+            len: 0,
+        }
+    }
+
+    fn add(&mut self, data: &[u8]) {
+        for byte in data {
+            self.hash = (self.hash ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3);
+        }
+        self.len += data.len();
+    }
+}
+
+impl<R: Read> Read for Checksum<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.add(&buf[..read]);
+
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Checksum<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.add(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// How to check that a file arrived on the watch after sending it
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Verification {
+    None,
+    // Compare the size in the directory listing, which is cheap
+    Size,
+    // Download the file again and compare every byte, which doubles the transfer time
+    #[default]
+    Full,
+}
+
+impl std::str::FromStr for Verification {
+    type Err = PolarError;
+
+    fn from_str(value: &str) -> Result<Verification, PolarError> {
+        match value {
+            "none" => Ok(Verification::None),
+            "size" => Ok(Verification::Size),
+            "full" => Ok(Verification::Full),
+            _ => Err(PolarError::new(format!(
+                "Unknown verification '{}', use none, size or full",
+                value
+            ))),
+        }
+    }
+}
 
 pub struct PolarWatch {
     handle: PolarUsb,
+    verification: Verification,
 }
 
 impl PolarWatch {
+    const VENDOR_ID: u16 = 0x0da4;
+    const PRODUCT_ID: u16 = 0x0008;
+
     pub fn new(handle: PolarUsb) -> PolarWatch {
-        PolarWatch { handle: handle }
+        PolarWatch {
+            handle: handle,
+            verification: Verification::default(),
+        }
+    }
+
+    // Used by send_file and send_file_from from now on
+    pub fn set_verification(&mut self, verification: Verification) {
+        self.verification = verification;
+    }
+
+    // Setting the flag stops the current operation before its next request
+    pub fn set_cancel_flag(&mut self, cancel: Option<Arc<AtomicBool>>) {
+        self.handle.set_cancel_flag(cancel);
+    }
+
+    // The handler sees every notification the watch sends, replacing the previous handler
+    pub fn set_notification_handler(&mut self, handler: Option<NotificationHandler>) {
+        self.handle.set_notification_handler(handler);
+    }
+
+    // Same as set_notification_handler, but the notifications are sent to the returned channel
+    pub fn notifications(&mut self) -> Receiver<Notification> {
+        let (sender, receiver) = channel();
+        self.set_notification_handler(Some(Box::new(move |notification| {
+            let _ = sender.send(notification.clone());
+        })));
+
+        receiver
+    }
+
+    // The watch only gets to send notifications while we read from it. Call this when idle to get
+    // them anyway, it returns None if nothing arrived before timeout
+    pub fn poll_notification(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<Notification>, PolarError> {
+        self.handle.poll_notification(timeout)
+    }
+
+    // Logs every usb frame from now on, see UsbConfig::trace
+    pub fn set_trace(&mut self, trace: bool) {
+        self.handle.set_trace(trace);
+    }
+
+    // Writes every usb frame to a pcap file from now on, see UsbConfig::capture
+    pub fn start_capture<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), PolarError> {
+        self.handle.start_capture(path.as_ref())
+    }
+
+    pub fn stop_capture(&mut self) {
+        self.handle.stop_capture();
+    }
+
+    // Wireshark Lua dissector for the frames of this watch model
+    pub fn lua_dissector() -> String {
+        lua_dissector(PolarWatch::VENDOR_ID, PolarWatch::PRODUCT_ID)
+    }
+
+    // Tells watches apart, None if the watch doesn't report one
+    pub fn serial_number(&self) -> Option<String> {
+        match self.handle.serial_number() {
+            Ok(serial) if !serial.trim().is_empty() => Some(serial.trim().to_string()),
+            Ok(_) => None,
+            Err(error) => {
+                debug!("Failed to read serial number: {:?}", error);
+                None
+            }
+        }
+    }
+
+    // Like "Polar M430", None if the watch doesn't report it
+    pub fn product_name(&self) -> Option<String> {
+        match self.handle.product_name() {
+            Ok(name) if !name.trim().is_empty() => Some(name.trim().to_string()),
+            Ok(_) => None,
+            Err(error) => {
+                debug!("Failed to read product name: {:?}", error);
+                None
+            }
+        }
+    }
+
+    // Changes the usb settings of an open watch, see PolarUsb::set_config
+    pub fn set_usb_config(&mut self, config: &UsbConfig) -> Result<(), PolarError> {
+        self.handle.set_config(config)
     }
 
     fn find_compatible_devices(context: &mut rusb::Context) -> Result<Vec<Device>, PolarError> {
@@ -22,7 +238,9 @@ impl PolarWatch {
 
         for device in context.devices()?.iter() {
             let descriptor = device.device_descriptor()?;
-            if descriptor.vendor_id() == 0x0da4 && descriptor.product_id() == 0x0008 {
+            if descriptor.vendor_id() == PolarWatch::VENDOR_ID
+                && descriptor.product_id() == PolarWatch::PRODUCT_ID
+            {
                 devices.push(device)
             }
         }
@@ -31,50 +249,201 @@ impl PolarWatch {
         Ok(devices)
     }
 
+    fn open_once(device: Device, config: &UsbConfig) -> Result<PolarWatch, PolarError> {
+        Ok(PolarWatch::new(PolarUsb::with_config(device, config)?))
+    }
+
     pub fn find_one(context: &mut rusb::Context) -> Result<PolarWatch, PolarError> {
+        PolarWatch::find_one_with(context, &UsbConfig::default())
+    }
+
+    pub fn find_one_with(
+        context: &mut rusb::Context,
+        config: &UsbConfig,
+    ) -> Result<PolarWatch, PolarError> {
         let mut devices = PolarWatch::find_compatible_devices(context)?;
         match devices.pop() {
-            Some(device) => Ok(PolarWatch::new(PolarUsb::new(device)?)),
+            Some(device) => PolarWatch::open_once(device, config),
             _ => Err(PolarError::new("Watch not found")),
         }
     }
 
+    // Returns the watch with this serial number if it is connected, or any other watch if not
+    pub fn find_preferred(
+        context: &mut rusb::Context,
+        config: &UsbConfig,
+        serial: &str,
+    ) -> Result<PolarWatch, PolarError> {
+        let mut fallback = None;
+
+        for device in PolarWatch::find_compatible_devices(context)? {
+            let watch = PolarWatch::open_once(device, config)?;
+
+            if watch.serial_number().as_deref() == Some(serial) {
+                return Ok(watch);
+            }
+
+            if fallback.is_none() {
+                fallback = Some(watch);
+            }
+        }
+
+        match fallback {
+            Some(watch) => {
+                info!("Watch {} not found, using another one", serial);
+                Ok(watch)
+            }
+            None => Err(PolarError::new("Watch not found")),
+        }
+    }
+
     pub fn find_all(context: &mut rusb::Context) -> Result<Vec<PolarWatch>, PolarError> {
+        PolarWatch::find_all_with(context, &UsbConfig::default())
+    }
+
+    pub fn find_all_with(
+        context: &mut rusb::Context,
+        config: &UsbConfig,
+    ) -> Result<Vec<PolarWatch>, PolarError> {
         let mut watches = Vec::new();
 
         for device in PolarWatch::find_compatible_devices(context)? {
-            let watch = PolarWatch::new(PolarUsb::new(device)?);
-            watches.push(watch);
+            watches.push(PolarWatch::open_once(device, config)?);
         }
 
         Ok(watches)
     }
 
     pub fn send_file<S>(&mut self, path: S, data: &[u8]) -> Result<(), PolarError>
+    where
+        S: Into<String>,
+    {
+        self.send_file_with_progress(path, data, &mut |_, _| ())
+    }
+
+    pub fn send_file_with_progress<S>(
+        &mut self,
+        path: S,
+        data: &[u8],
+        progress: Progress,
+    ) -> Result<(), PolarError>
     where
         S: Into<String>,
     {
         let path: String = path.into();
 
         info!("Uploading to {} data: {:?} ", path, data);
-        let path_len = polar_usb::tail_bits(path.len());
+        self.upload(&path, &mut &data[..], data.len(), progress)?;
+
+        self.verify_file(path, data, self.verification)
+    }
+
+    // Uploads size bytes read from data without keeping them in memory. Since the data can't be
+    // read again, a full verification compares a checksum of what was sent with one of the file
+    // downloaded again
+    pub fn send_file_from<S, R>(
+        &mut self,
+        path: S,
+        data: R,
+        size: usize,
+        progress: Progress,
+    ) -> Result<(), PolarError>
+    where
+        S: Into<String>,
+        R: Read,
+    {
+        let path: String = path.into();
+        let mut data = Checksum::new(data);
+
+        info!("Uploading {} bytes to {}", size, path);
+        self.upload(&path, &mut data, size, progress)?;
+
+        match self.verification {
+            Verification::None => Ok(()),
+            Verification::Size => self.verify_size(&path, size),
+            Verification::Full => {
+                let mut found = Checksum::new(std::io::sink());
+                self.get_file_to(path.clone(), &mut found, Some(size), &mut |_, _| ())?;
+
+                if found.len == data.len && found.hash == data.hash {
+                    return Ok(());
+                }
+
+                Err(Mismatch {
+                    path,
+                    sent_len: data.len,
+                    found_len: found.len,
+                    first_difference: None,
+                }
+                .into())
+            }
+        }
+    }
 
-        let mut packet: Vec<u8> = vec![path_len + 4, 0x0, 0x8, 0x1, 0x12, path_len];
-        packet.extend_from_slice(path.as_bytes());
-        packet.extend_from_slice(data);
-        packet.push(0);
+    // Checks that the file at path has the given content
+    pub fn verify_file<S>(
+        &mut self,
+        path: S,
+        data: &[u8],
+        verification: Verification,
+    ) -> Result<(), PolarError>
+    where
+        S: Into<String>,
+    {
+        let path: String = path.into();
 
-        self.handle.request(packet.as_slice())?;
+        match verification {
+            Verification::None => Ok(()),
+            Verification::Size => self.verify_size(&path, data.len()),
+            Verification::Full => {
+                let file = self.get_file(path.clone())?;
 
-        // Check if the file is there
-        let file = self.get_file(path)?;
+                match Mismatch::compare(&path, data, &file) {
+                    Some(mismatch) => Err(mismatch.into()),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
 
-        if file != data {
-            return Err(PolarError::new(format!(
-                "Content on the watch doesn't match the one sent.    sent {:?}    got {:?}",
-                data, file
-            )));
+    fn verify_size(&mut self, path: &str, size: usize) -> Result<(), PolarError> {
+        match self.file_size(path) {
+            Some(file_size) if file_size == size as u64 => Ok(()),
+            Some(file_size) => Err(Mismatch {
+                path: path.to_string(),
+                sent_len: size,
+                found_len: file_size as usize,
+                first_difference: None,
+            }
+            .into()),
+            None => Err(PolarError::new(format!("{} is not on the watch", path))),
         }
+    }
+
+    fn upload(
+        &mut self,
+        path: &str,
+        data: &mut dyn Read,
+        size: usize,
+        progress: Progress,
+    ) -> Result<(), PolarError> {
+        let path_len = polar_usb::tail_bits(path.len());
+
+        let mut header: Vec<u8> = vec![path_len + 4, 0x0, 0x8, 0x1, 0x12, path_len];
+        header.extend_from_slice(path.as_bytes());
+
+        self.handle.trace_request(&header);
+
+        let header_len = header.len();
+        let total = header_len + size + 1;
+        let mut packet = header.as_slice().chain(data).chain(&[0u8][..]);
+
+        // Only report the progress of the file itself, not of the header around it
+        self.handle
+            .request_from(&mut packet, total, &mut |sent, _| {
+                let done = std::cmp::min(sent.saturating_sub(header_len), size);
+                progress(done, Some(size))
+            })?;
 
         Ok(())
     }
@@ -83,20 +452,76 @@ impl PolarWatch {
     where
         S: Into<String>,
     {
-        let path: String = path.into();
+        let mut answer = Vec::new();
+        self.download(&path.into(), &mut answer, None, &mut |_, _| ())?;
+        debug!("FILE {:?}", answer);
+
+        Ok(answer)
+    }
+
+    // Downloads a file into data as it arrives and returns its size. total is only reported to
+    // progress, pass the size from the directory listing when it is known
+    pub fn get_file_to<S, W>(
+        &mut self,
+        path: S,
+        mut data: W,
+        total: Option<usize>,
+        progress: Progress,
+    ) -> Result<usize, PolarError>
+    where
+        S: Into<String>,
+        W: Write,
+    {
+        self.download(&path.into(), &mut data, total, progress)
+    }
 
+    fn download(
+        &mut self,
+        path: &str,
+        data: &mut dyn Write,
+        total: Option<usize>,
+        progress: Progress,
+    ) -> Result<usize, PolarError> {
         info!("Downloading {}", path);
         let request = encode(polar::protocol::PbPFtpOperation {
             command: 0,
-            path: path.clone(),
+            path: path.to_string(),
         })
         .unwrap();
 
-        let mut answer = self.handle.simple_request(request.as_slice())?;
-        debug!("FILE {:?}", answer);
+        let mut writer = WithoutTrailer {
+            inner: data,
+            last: None,
+        };
+
+        let received = self.handle.simple_request_into(
+            request.as_slice(),
+            &mut writer,
+            &mut |received, _| match total {
+                Some(total) => progress(std::cmp::min(received, total), Some(total)),
+                None => progress(received, None),
+            },
+        )?;
+
+        if writer.last != Some(0) {
+            return Err(PolarError::new(format!(
+                "Answer for {} doesn't end with 0",
+                path
+            )));
+        }
 
-        assert!(answer.pop().unwrap() == 0);
-        Ok(answer)
+        Ok(received - 1)
+    }
+
+    // Size of a file, taken from the listing of its directory
+    fn file_size(&mut self, path: &str) -> Option<u64> {
+        let split = path.rfind("/")? + 1;
+        let (parent, name) = path.split_at(split);
+
+        let entries = self.dir(parent).ok()?;
+        let entry = entries.iter().find(|entry| entry.name == name)?;
+
+        Some(entry.size)
     }
 
     pub fn delete_file<S>(&mut self, path: S) -> Result<(), PolarError>
@@ -117,7 +542,7 @@ impl PolarWatch {
         Ok(())
     }
 
-    pub fn dir<S>(&mut self, path: S) -> Result<Vec<String>, PolarError>
+    pub fn dir<S>(&mut self, path: S) -> Result<Vec<DirEntry>, PolarError>
     where
         S: Into<String>,
     {
@@ -127,18 +552,46 @@ impl PolarWatch {
             path = path + "/";
         }
 
-        let answer = self.get_file(path)?;
+        let answer = self.get_file(path.clone())?;
+        let directory = polar::protocol::PbPFtpDirectory::decode(answer)
+            .map_err(|_| PolarError::new(format!("'{}' is not a directory", path)))?;
+        self.handle.trace_answer(&directory);
+
         let mut result = vec![];
-        for entry in polar::protocol::PbPFtpDirectory::decode(answer)
-            .unwrap()
-            .entries
-        {
-            result.push(entry.name);
+        for entry in directory.entries {
+            result.push(DirEntry::from_entry(&path, entry));
         }
 
         Ok(result)
     }
 
+    // Every file and directory under path, recursively
+    pub fn walk<S>(&mut self, path: S) -> Walk<'_>
+    where
+        S: Into<String>,
+    {
+        let mut path: String = path.into();
+
+        if !path.ends_with("/") {
+            path = path + "/";
+        }
+
+        Walk::new(self, path)
+    }
+
+    pub fn disk_usage<S>(&mut self, path: S) -> Result<DiskUsage, PolarError>
+    where
+        S: Into<String>,
+    {
+        let mut usage = DiskUsage::default();
+
+        for entry in self.walk(path) {
+            usage.add(&entry?);
+        }
+
+        Ok(usage)
+    }
+
     pub fn mkdir<S>(&mut self, path: S) -> Result<(), PolarError>
     where
         S: Into<String>,
@@ -170,12 +623,188 @@ impl PolarWatch {
             path = path + "/";
         }
 
+        for entry in self.dir(path)? {
+            if entry.is_directory {
+                self.recursive_delete(entry.path)?;
+            } else {
+                self.delete_file(entry.path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Deletes a directory and everything in it, deepest entries first, since the watch only
+    // deletes empty directories
+    pub fn delete_directory<S>(&mut self, path: S) -> Result<(), PolarError>
+    where
+        S: Into<String>,
+    {
+        let mut path: String = path.into();
+
+        if !path.ends_with("/") {
+            path = path + "/";
+        }
+
         for entry in self.dir(path.clone())? {
-            if entry.ends_with("/") {
-                self.recursive_delete(path.clone() + &entry)?;
+            if entry.is_directory {
+                self.delete_directory(entry.path)?;
             } else {
-                self.delete_file(path.clone() + &entry)?;
+                self.delete_file(entry.path)?;
+            }
+        }
+
+        self.delete_file(path)
+    }
+
+    pub fn physical_data(&mut self) -> Result<PhysicalData, PolarError> {
+        PhysicalData::decode(&self.get_file(PHYSICAL_DATA_PATH)?)
+    }
+
+    // Writes the settings of changes that are Some, the rest of the file stays as it is
+    pub fn update_physical_data(&mut self, changes: &PhysicalData) -> Result<(), PolarError> {
+        let file = self.get_file(PHYSICAL_DATA_PATH)?;
+        let data = changes.apply(&file, std::time::SystemTime::now())?;
+
+        info!("Updating physical data: {:?}", changes);
+        self.send_file(PHYSICAL_DATA_PATH, &data)
+    }
+
+    pub fn set_max_heart_rate(&mut self, bpm: u32) -> Result<(), PolarError> {
+        self.update_physical_data(&PhysicalData {
+            max_heart_rate: Some(bpm),
+            ..PhysicalData::default()
+        })
+    }
+
+    pub fn set_resting_heart_rate(&mut self, bpm: u32) -> Result<(), PolarError> {
+        self.update_physical_data(&PhysicalData {
+            resting_heart_rate: Some(bpm),
+            ..PhysicalData::default()
+        })
+    }
+
+    // The sport profiles in the order the watch lists them
+    pub fn sport_profiles(&mut self) -> Result<Vec<SportProfile>, PolarError> {
+        let mut profiles = vec![];
+
+        for entry in self.dir(SPORT_PROFILES_PATH)? {
+            if entry.is_directory {
+                let data = self.get_file(entry.path.clone() + PROFILE_NAME)?;
+                profiles.push(SportProfile::decode(&entry.path, &data)?);
+            }
+        }
+
+        sort_profiles(&mut profiles);
+        Ok(profiles)
+    }
+
+    // Adds a profile for sport at the end of the list, with the settings of the profile of
+    // template, or of the first profile
+    pub fn add_sport_profile(
+        &mut self,
+        sport_id: u64,
+        template: Option<u64>,
+    ) -> Result<SportProfile, PolarError> {
+        let profiles = self.sport_profiles()?;
+        if find_profile(&profiles, sport_id).is_ok() {
+            return Err(PolarError::new(format!(
+                "There is already a profile for sport {}",
+                sport_id
+            )));
+        }
+
+        let source = match template {
+            Some(template) => &profiles[find_profile(&profiles, template)?],
+            None => profiles.first().ok_or_else(|| {
+                PolarError::new("There are no sport profiles to copy the settings from")
+            })?,
+        };
+
+        let file = self.get_file(source.path.clone() + PROFILE_NAME)?;
+        let identifier = profiles
+            .iter()
+            .filter_map(|profile| profile.identifier)
+            .max()
+            .map_or(1, |identifier| identifier + 1);
+        let data = copy_for_sport(
+            &file,
+            identifier,
+            sport_id,
+            profiles.len() as u32,
+            std::time::SystemTime::now(),
+        )?;
+
+        let path = free_profile_path(&profiles);
+        info!("Adding a profile for sport {} in {}", sport_id, path);
+        self.mkdir(path.clone())?;
+        self.send_file(path.clone() + PROFILE_NAME, &data)?;
+
+        SportProfile::decode(&path, &data)
+    }
+
+    pub fn remove_sport_profile(&mut self, sport_id: u64) -> Result<(), PolarError> {
+        let mut profiles = self.sport_profiles()?;
+        let profile = profiles.remove(find_profile(&profiles, sport_id)?);
+
+        info!(
+            "Removing the profile for sport {} in {}",
+            sport_id, profile.path
+        );
+        self.delete_directory(profile.path)?;
+
+        self.write_sport_positions(&profiles)
+    }
+
+    // The heart rate zones of the profile of sport, None when the watch uses its default ones
+    pub fn heart_rate_zones(
+        &mut self,
+        sport_id: u64,
+    ) -> Result<Option<HeartRateZones>, PolarError> {
+        let profiles = self.sport_profiles()?;
+        let index = find_profile(&profiles, sport_id)?;
+
+        Ok(profiles[index].heart_rate_zones.clone())
+    }
+
+    pub fn set_heart_rate_zones(
+        &mut self,
+        sport_id: u64,
+        zones: &HeartRateZones,
+    ) -> Result<(), PolarError> {
+        let profiles = self.sport_profiles()?;
+        let path = profiles[find_profile(&profiles, sport_id)?].path.clone() + PROFILE_NAME;
+
+        info!(
+            "Setting the heart rate zones of sport {} to {}",
+            sport_id, zones
+        );
+        let file = self.get_file(path.clone())?;
+        let data = with_heart_rate_zones(&file, zones, std::time::SystemTime::now())?;
+        self.send_file(path, &data)
+    }
+
+    // Moves the profile of sport to position, from 0. The others keep their order
+    pub fn move_sport_profile(&mut self, sport_id: u64, position: usize) -> Result<(), PolarError> {
+        let mut profiles = self.sport_profiles()?;
+        let profile = profiles.remove(find_profile(&profiles, sport_id)?);
+        profiles.insert(position.min(profiles.len()), profile);
+
+        self.write_sport_positions(&profiles)
+    }
+
+    // Numbers the profiles in this order, only the ones that change are written
+    fn write_sport_positions(&mut self, profiles: &[SportProfile]) -> Result<(), PolarError> {
+        let now = std::time::SystemTime::now();
+
+        for (position, profile) in profiles.iter().enumerate() {
+            if profile.position == Some(position as u32) {
+                continue;
             }
+
+            let path = profile.path.clone() + PROFILE_NAME;
+            let file = self.get_file(path.clone())?;
+            self.send_file(path, &with_position(&file, position as u32, now)?)?;
         }
 
         Ok(())
@@ -187,3 +816,30 @@ impl PolarWatch {
         self.recursive_delete(favorites_path)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_ignore_how_the_data_is_split() {
+        let data: Vec<u8> = (0..200u8).collect();
+
+        let mut reader = Checksum::new(data.as_slice());
+        let mut chunk = [0u8; 7];
+        while reader.read(&mut chunk).unwrap() > 0 {}
+
+        let mut writer = Checksum::new(std::io::sink());
+        for part in data.chunks(13) {
+            writer.write_all(part).unwrap();
+        }
+
+        assert_eq!((reader.len, reader.hash), (writer.len, writer.hash));
+
+        let mut changed = Checksum::new(std::io::sink());
+        changed.write_all(&data[1..]).unwrap();
+        changed.write_all(&[0]).unwrap();
+        assert_eq!(changed.len, writer.len);
+        assert_ne!(changed.hash, writer.hash);
+    }
+}
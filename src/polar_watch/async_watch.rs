@@ -0,0 +1,139 @@
+// Async version of PolarWatch. Every operation runs the blocking USB transfers in tokio's blocking
+// thread pool, one at a time. Dropping the future of an operation cancels it before its next request
+use super::{DirEntry, DiskUsage, Notification, PolarError, PolarWatch, Verification};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Cancels the operation if its future is dropped before finishing
+struct CancelOnDrop {
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl CancelOnDrop {
+    fn disarm(&mut self) {
+        self.cancel = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(cancel) = &self.cancel {
+            cancel.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AsyncPolarWatch {
+    watch: Arc<Mutex<PolarWatch>>,
+}
+
+impl AsyncPolarWatch {
+    pub fn new(watch: PolarWatch) -> AsyncPolarWatch {
+        AsyncPolarWatch {
+            watch: Arc::new(Mutex::new(watch)),
+        }
+    }
+
+    pub async fn find_one() -> Result<AsyncPolarWatch, PolarError> {
+        let watch = tokio::task::spawn_blocking(|| {
+            let mut context = rusb::Context::new()?;
+            PolarWatch::find_one(&mut context)
+        })
+        .await
+        .map_err(|error| PolarError::new(format!("USB task failed: {}", error)))??;
+
+        Ok(AsyncPolarWatch::new(watch))
+    }
+
+    async fn run<T, F>(&self, operation: F) -> Result<T, PolarError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PolarWatch) -> Result<T, PolarError> + Send + 'static,
+    {
+        let watch = self.watch.clone();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut guard = CancelOnDrop {
+            cancel: Some(cancel.clone()),
+        };
+
+        let task = tokio::task::spawn_blocking(move || {
+            let mut watch = match watch.lock() {
+                Ok(watch) => watch,
+                Err(_) => return Err(PolarError::new("A previous operation panicked")),
+            };
+
+            watch.set_cancel_flag(Some(cancel));
+            let result = operation(&mut watch);
+            watch.set_cancel_flag(None);
+
+            result
+        });
+
+        let result = task.await;
+        guard.disarm();
+
+        match result {
+            Ok(result) => result,
+            Err(error) => Err(PolarError::new(format!("USB task failed: {}", error))),
+        }
+    }
+
+    pub async fn set_verification(&self, verification: Verification) -> Result<(), PolarError> {
+        self.run(move |watch| {
+            watch.set_verification(verification);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn send_file(&self, path: String, data: Vec<u8>) -> Result<(), PolarError> {
+        self.run(move |watch| watch.send_file(path, &data)).await
+    }
+
+    pub async fn get_file(&self, path: String) -> Result<Vec<u8>, PolarError> {
+        self.run(move |watch| watch.get_file(path)).await
+    }
+
+    pub async fn delete_file(&self, path: String) -> Result<(), PolarError> {
+        self.run(move |watch| watch.delete_file(path)).await
+    }
+
+    pub async fn dir(&self, path: String) -> Result<Vec<DirEntry>, PolarError> {
+        self.run(move |watch| watch.dir(path)).await
+    }
+
+    pub async fn mkdir(&self, path: String) -> Result<(), PolarError> {
+        self.run(move |watch| watch.mkdir(path)).await
+    }
+
+    pub async fn recursive_delete(&self, path: String) -> Result<(), PolarError> {
+        self.run(move |watch| watch.recursive_delete(path)).await
+    }
+
+    pub async fn delete_all_favorites(&self) -> Result<(), PolarError> {
+        self.run(|watch| watch.delete_all_favorites()).await
+    }
+
+    pub async fn disk_usage(&self, path: String) -> Result<DiskUsage, PolarError> {
+        self.run(move |watch| watch.disk_usage(path)).await
+    }
+
+    pub async fn poll_notification(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<Notification>, PolarError> {
+        self.run(move |watch| watch.poll_notification(timeout))
+            .await
+    }
+
+    // Runs any other blocking operation on the watch
+    pub async fn with_watch<T, F>(&self, operation: F) -> Result<T, PolarError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PolarWatch) -> Result<T, PolarError> + Send + 'static,
+    {
+        self.run(operation).await
+    }
+}
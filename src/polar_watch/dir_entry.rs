@@ -0,0 +1,203 @@
+use super::{polar, PolarError, PolarWatch};
+
+// Date and time as stored by the watch for its files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl From<polar::types::PbSystemDateTime> for Timestamp {
+    fn from(value: polar::types::PbSystemDateTime) -> Timestamp {
+        Timestamp {
+            year: value.date.year,
+            month: value.date.month,
+            day: value.date.day,
+            hour: value.time.hour,
+            minute: value.time.minute,
+            second: value.time.seconds,
+        }
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    // Full path on the watch, directories end with a "/"
+    pub path: String,
+    pub name: String,
+    pub is_directory: bool,
+    pub size: u64,
+    pub created: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+}
+
+impl DirEntry {
+    pub fn from_entry(parent: &str, entry: polar::protocol::PbPFtpEntry) -> DirEntry {
+        DirEntry {
+            path: parent.to_string() + &entry.name,
+            is_directory: entry.name.ends_with("/"),
+            name: entry.name,
+            size: entry.size,
+            created: entry.created.map(Timestamp::from),
+            modified: entry.modified.map(Timestamp::from),
+        }
+    }
+}
+
+// Lists a directory tree one directory at a time, parents before their contents
+pub struct Walk<'a> {
+    watch: &'a mut PolarWatch,
+    root: Option<String>,
+    pending: Vec<DirEntry>,
+}
+
+impl<'a> Walk<'a> {
+    pub fn new(watch: &'a mut PolarWatch, root: String) -> Walk<'a> {
+        Walk {
+            watch,
+            root: Some(root),
+            pending: vec![],
+        }
+    }
+
+    fn push_children(&mut self, path: String) -> Result<(), PolarError> {
+        let mut entries = self.watch.dir(path)?;
+        entries.reverse();
+        self.pending.append(&mut entries);
+
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Result<DirEntry, PolarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            if let Err(error) = self.push_children(root) {
+                return Some(Err(error));
+            }
+        }
+
+        let entry = self.pending.pop()?;
+
+        if entry.is_directory {
+            if let Err(error) = self.push_children(entry.path.clone()) {
+                // Stop walking, the listing we were in the middle of is incomplete
+                self.pending.clear();
+                return Some(Err(error));
+            }
+        }
+
+        Some(Ok(entry))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DiskUsage {
+    pub files: usize,
+    pub directories: usize,
+    pub bytes: u64,
+}
+
+impl DiskUsage {
+    pub fn add(&mut self, entry: &DirEntry) {
+        if entry.is_directory {
+            self.directories += 1;
+        } else {
+            self.files += 1;
+            self.bytes += entry.size;
+        }
+    }
+}
+
+impl std::fmt::Display for DiskUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} in {} files and {} directories",
+            human_size(self.bytes),
+            self.files,
+            self.directories
+        )
+    }
+}
+
+pub fn human_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: u64) -> DirEntry {
+        DirEntry {
+            path: path.to_string(),
+            name: path.rsplit('/').next().unwrap_or(path).to_string(),
+            is_directory: path.ends_with('/'),
+            size,
+            created: None,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn disk_usage_counts_files_and_directories() {
+        let mut usage = DiskUsage::default();
+        usage.add(&entry("/U/0/FAV/", 4096));
+        usage.add(&entry("/U/0/FAV/00/TST.BPB", 1000));
+        usage.add(&entry("/U/0/FAV/01/TST.BPB", 1048));
+
+        assert_eq!(
+            usage,
+            DiskUsage {
+                files: 2,
+                directories: 1,
+                bytes: 2048,
+            }
+        );
+        assert_eq!(usage.to_string(), "2.0 KiB in 2 files and 1 directories");
+    }
+
+    #[test]
+    fn sizes_are_human_readable() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024), "3.0 MiB");
+    }
+
+    #[test]
+    fn timestamps_are_padded() {
+        let timestamp = Timestamp {
+            year: 2024,
+            month: 3,
+            day: 7,
+            hour: 6,
+            minute: 5,
+            second: 9,
+        };
+        assert_eq!(timestamp.to_string(), "2024-03-07 06:05:09");
+    }
+}
@@ -0,0 +1,189 @@
+// Notices when watches are connected, with libusb hotplug events when the platform supports them
+// and by listing the devices every now and then when it doesn't
+use super::{Device, PolarError, PolarWatch, UsbConfig};
+use log::{info, warn};
+use rusb::UsbContext;
+use std::collections::{HashSet, VecDeque};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// The device is usually not ready right after it shows up
+const OPEN_ATTEMPTS: u32 = 5;
+const OPEN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+enum Event {
+    Arrived(Device),
+    Left(Device),
+}
+
+struct HotplugHandler {
+    sender: Sender<Event>,
+}
+
+impl rusb::Hotplug<rusb::Context> for HotplugHandler {
+    fn device_arrived(&mut self, device: Device) {
+        let _ = self.sender.send(Event::Arrived(device));
+    }
+
+    fn device_left(&mut self, device: Device) {
+        let _ = self.sender.send(Event::Left(device));
+    }
+}
+
+fn device_key(device: &Device) -> (u8, u8) {
+    (device.bus_number(), device.address())
+}
+
+pub struct DeviceMonitor {
+    context: rusb::Context,
+    events: Option<(Receiver<Event>, rusb::Registration<rusb::Context>)>,
+    pending: VecDeque<Device>,
+    known: HashSet<(u8, u8)>,
+}
+
+impl DeviceMonitor {
+    // Watches that are already connected are reported as arrivals too
+    pub fn new(context: &mut rusb::Context) -> Result<DeviceMonitor, PolarError> {
+        let events = if rusb::has_hotplug() {
+            let (sender, receiver) = channel();
+            let registration = context.register_callback(
+                Some(PolarWatch::VENDOR_ID),
+                Some(PolarWatch::PRODUCT_ID),
+                None,
+                Box::new(HotplugHandler { sender }),
+            )?;
+
+            info!("Waiting for watches with hotplug events");
+            Some((receiver, registration))
+        } else {
+            info!("Hotplug is not supported, waiting for watches by polling");
+            None
+        };
+
+        let pending = PolarWatch::find_compatible_devices(context)?.into();
+
+        Ok(DeviceMonitor {
+            context: context.clone(),
+            events,
+            pending,
+            known: HashSet::new(),
+        })
+    }
+
+    fn poll(&mut self) -> Result<(), PolarError> {
+        match &self.events {
+            Some((receiver, _)) => {
+                self.context.handle_events(Some(POLL_INTERVAL))?;
+
+                while let Ok(event) = receiver.try_recv() {
+                    match event {
+                        Event::Arrived(device) => self.pending.push_back(device),
+                        Event::Left(device) => {
+                            self.known.remove(&device_key(&device));
+                        }
+                    }
+                }
+            }
+            None => {
+                let devices = PolarWatch::find_compatible_devices(&mut self.context)?;
+                let present: HashSet<(u8, u8)> = devices.iter().map(device_key).collect();
+
+                self.known.retain(|key| present.contains(key));
+                self.pending.extend(devices);
+
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Waits for a watch that wasn't reported before. Returns None if timeout runs out first, or
+    // waits forever without one
+    pub fn next_arrival(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Device>, PolarError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            while let Some(device) = self.pending.pop_front() {
+                if self.known.insert(device_key(&device)) {
+                    return Ok(Some(device));
+                }
+            }
+
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    return Ok(None);
+                }
+            }
+
+            self.poll()?;
+        }
+    }
+}
+
+impl Drop for DeviceMonitor {
+    fn drop(&mut self) {
+        if let Some((_, registration)) = self.events.take() {
+            self.context.unregister_callback(registration);
+        }
+    }
+}
+
+impl PolarWatch {
+    pub fn open(device: Device, config: &UsbConfig) -> Result<PolarWatch, PolarError> {
+        let mut attempt = 1;
+
+        loop {
+            match PolarWatch::open_once(device.clone(), config) {
+                Err(error) if attempt < OPEN_ATTEMPTS => {
+                    warn!("Failed to open watch, trying again: {:?}", error);
+                    std::thread::sleep(OPEN_RETRY_DELAY);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Returns the first watch that is connected, or one that already was
+    pub fn wait_for_device(
+        context: &mut rusb::Context,
+        config: &UsbConfig,
+        timeout: Option<Duration>,
+    ) -> Result<PolarWatch, PolarError> {
+        let mut monitor = DeviceMonitor::new(context)?;
+
+        match monitor.next_arrival(timeout)? {
+            Some(device) => PolarWatch::open(device, config),
+            None => Err(PolarError::new("Watch not found, no watch was connected")),
+        }
+    }
+
+    // Calls action for every watch that gets connected, forever. Errors from action are only
+    // logged, so that one bad connection doesn't stop the others
+    pub fn for_each_connected(
+        context: &mut rusb::Context,
+        action: &mut dyn FnMut(Device) -> Result<(), PolarError>,
+    ) -> Result<(), PolarError> {
+        let mut monitor = DeviceMonitor::new(context)?;
+
+        loop {
+            if let Some(device) = monitor.next_arrival(None)? {
+                info!(
+                    "Watch connected on bus {} address {}",
+                    device.bus_number(),
+                    device.address()
+                );
+
+                if let Err(error) = action(device) {
+                    warn!("Action for connected watch failed: {:?}", error);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,396 @@
+// The user's physical data, which the watch uses for heart rate zones, calories and fitness tests.
+// Changes only replace the fields that were set, everything else in the file is written back as
+// it was read, including the fields this crate doesn't know about
+use super::wire::{message_field, replace_fields, system_date_time};
+use super::{Message, PolarError};
+use polar::types::PbDate;
+use std::fmt;
+use std::time::SystemTime;
+
+pub const PHYSICAL_DATA_PATH: &str = "/U/0/S/PHYSDATA.BPB";
+
+// Field numbers of PbUserPhysData
+const BIRTHDAY: u32 = 1;
+const GENDER: u32 = 2;
+const WEIGHT: u32 = 3;
+const HEIGHT: u32 = 4;
+const MAX_HEART_RATE: u32 = 5;
+const RESTING_HEART_RATE: u32 = 6;
+const AEROBIC_THRESHOLD: u32 = 8;
+const ANAEROBIC_THRESHOLD: u32 = 9;
+const VO2MAX: u32 = 10;
+const FTP: u32 = 15;
+const LAST_MODIFIED: u32 = 100;
+
+// PbUserHrAttribute.HrSettingSource
+const SOURCE_USER: i32 = 2;
+
+// The parts of Polar's physical_data.proto that we need. Everything is optional so that files
+// written by other firmware versions still decode
+mod proto {
+    use polar::types::{PbDate, PbSystemDateTime};
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbUserBirthday {
+        #[prost(message, optional, tag = "1")]
+        pub value: Option<PbDate>,
+        #[prost(message, optional, tag = "2")]
+        pub last_modified: Option<PbSystemDateTime>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbUserGender {
+        // 1 is male, 2 is female
+        #[prost(int32, optional, tag = "1")]
+        pub value: Option<i32>,
+        #[prost(message, optional, tag = "2")]
+        pub last_modified: Option<PbSystemDateTime>,
+    }
+
+    // Weight in kg and height in cm
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbUserMeasure {
+        #[prost(float, optional, tag = "1")]
+        pub value: Option<f32>,
+        #[prost(message, optional, tag = "2")]
+        pub last_modified: Option<PbSystemDateTime>,
+    }
+
+    // Heart rates, VO2max and FTP
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbUserAttribute {
+        #[prost(uint32, optional, tag = "1")]
+        pub value: Option<u32>,
+        #[prost(message, optional, tag = "2")]
+        pub last_modified: Option<PbSystemDateTime>,
+        #[prost(int32, optional, tag = "3")]
+        pub setting_source: Option<i32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbUserPhysData {
+        #[prost(message, optional, tag = "1")]
+        pub birthday: Option<PbUserBirthday>,
+        #[prost(message, optional, tag = "2")]
+        pub gender: Option<PbUserGender>,
+        #[prost(message, optional, tag = "3")]
+        pub weight: Option<PbUserMeasure>,
+        #[prost(message, optional, tag = "4")]
+        pub height: Option<PbUserMeasure>,
+        #[prost(message, optional, tag = "5")]
+        pub maximum_heartrate: Option<PbUserAttribute>,
+        #[prost(message, optional, tag = "6")]
+        pub resting_heartrate: Option<PbUserAttribute>,
+        #[prost(message, optional, tag = "8")]
+        pub aerobic_threshold: Option<PbUserAttribute>,
+        #[prost(message, optional, tag = "9")]
+        pub anaerobic_threshold: Option<PbUserAttribute>,
+        #[prost(message, optional, tag = "10")]
+        pub vo2max: Option<PbUserAttribute>,
+        #[prost(message, optional, tag = "15")]
+        pub functional_threshold_power: Option<PbUserAttribute>,
+        #[prost(message, optional, tag = "100")]
+        pub last_modified: Option<PbSystemDateTime>,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gender {
+    Male,
+    Female,
+}
+
+impl std::str::FromStr for Gender {
+    type Err = PolarError;
+
+    fn from_str(value: &str) -> Result<Gender, PolarError> {
+        match value {
+            "male" => Ok(Gender::Male),
+            "female" => Ok(Gender::Female),
+            _ => Err(PolarError::new(format!(
+                "Unknown gender '{}', use male or female",
+                value
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Gender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Gender::Male => write!(f, "male"),
+            Gender::Female => write!(f, "female"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Date {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+}
+
+// Like 1990-05-31
+impl std::str::FromStr for Date {
+    type Err = PolarError;
+
+    fn from_str(value: &str) -> Result<Date, PolarError> {
+        let invalid = || PolarError::new(format!("Invalid date '{}', use YYYY-MM-DD", value));
+        let parts: Vec<u32> = value
+            .split('-')
+            .map(|part| part.trim().parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+
+        match parts.as_slice() {
+            [year @ 1..=9999, month @ 1..=12, day]
+                if (1..=days_in_month(*year, *month)).contains(day) =>
+            {
+                Ok(Date {
+                    year: *year,
+                    month: *month,
+                    day: *day,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+// None is a setting missing from the file, or one that isn't changed when updating
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PhysicalData {
+    pub birthday: Option<Date>,
+    pub gender: Option<Gender>,
+    // kg
+    pub weight: Option<f32>,
+    // cm
+    pub height: Option<f32>,
+    pub max_heart_rate: Option<u32>,
+    pub resting_heart_rate: Option<u32>,
+    pub aerobic_threshold: Option<u32>,
+    pub anaerobic_threshold: Option<u32>,
+    pub vo2max: Option<u32>,
+    // Watts
+    pub ftp: Option<u32>,
+}
+
+// The names used by set_field and shown by to_text
+const FIELDS: [&str; 10] = [
+    "birthday",
+    "gender",
+    "weight",
+    "height",
+    "max_hr",
+    "resting_hr",
+    "aerobic_threshold",
+    "anaerobic_threshold",
+    "vo2max",
+    "ftp",
+];
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, PolarError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| PolarError::new(format!("Invalid value '{}' for {}", value, name)))
+}
+
+// Weights, heights, heart rates and the rest are all above 0, and NaN or inf is never a measure
+fn positive<T: std::str::FromStr + PartialOrd + Default>(
+    name: &str,
+    value: &str,
+) -> Result<T, PolarError> {
+    let parsed: T = parse(name, value)?;
+    if parsed > T::default() {
+        Ok(parsed)
+    } else {
+        Err(PolarError::new(format!(
+            "Invalid value '{}' for {}, it must be above 0",
+            value, name
+        )))
+    }
+}
+
+fn measure(name: &str, value: &str) -> Result<f32, PolarError> {
+    let parsed: f32 = positive(name, value)?;
+    if parsed.is_finite() {
+        Ok(parsed)
+    } else {
+        Err(PolarError::new(format!(
+            "Invalid value '{}' for {}",
+            value, name
+        )))
+    }
+}
+
+impl PhysicalData {
+    pub fn decode(data: &[u8]) -> Result<PhysicalData, PolarError> {
+        let data = proto::PbUserPhysData::decode(data)
+            .map_err(|error| PolarError::new(format!("Not physical data: {:?}", error)))?;
+        let attribute = |attribute: Option<proto::PbUserAttribute>| attribute?.value;
+
+        Ok(PhysicalData {
+            birthday: data
+                .birthday
+                .and_then(|birthday| birthday.value)
+                .map(|date| Date {
+                    year: date.year,
+                    month: date.month,
+                    day: date.day,
+                }),
+            gender: data
+                .gender
+                .and_then(|gender| gender.value)
+                .and_then(|value| match value {
+                    1 => Some(Gender::Male),
+                    2 => Some(Gender::Female),
+                    _ => None,
+                }),
+            weight: data.weight.and_then(|weight| weight.value),
+            height: data.height.and_then(|height| height.value),
+            max_heart_rate: attribute(data.maximum_heartrate),
+            resting_heart_rate: attribute(data.resting_heartrate),
+            aerobic_threshold: attribute(data.aerobic_threshold),
+            anaerobic_threshold: attribute(data.anaerobic_threshold),
+            vo2max: attribute(data.vo2max),
+            ftp: attribute(data.functional_threshold_power),
+        })
+    }
+
+    // The fields of file with every setting that is Some replaced, marked as changed at now
+    pub fn apply(&self, file: &[u8], now: SystemTime) -> Result<Vec<u8>, PolarError> {
+        let now = system_date_time(now);
+        let mut changes: Vec<(u32, Vec<u8>)> = vec![];
+
+        let attribute = |value: u32, setting_source: Option<i32>| proto::PbUserAttribute {
+            value: Some(value),
+            last_modified: Some(now.clone()),
+            setting_source,
+        };
+        let measure = |value: f32| proto::PbUserMeasure {
+            value: Some(value),
+            last_modified: Some(now.clone()),
+        };
+
+        if let Some(date) = self.birthday {
+            let birthday = proto::PbUserBirthday {
+                value: Some(PbDate {
+                    year: date.year,
+                    month: date.month,
+                    day: date.day,
+                }),
+                last_modified: Some(now.clone()),
+            };
+            changes.push((BIRTHDAY, message_field(BIRTHDAY, birthday)?));
+        }
+        if let Some(gender) = self.gender {
+            let gender = proto::PbUserGender {
+                value: Some(match gender {
+                    Gender::Male => 1,
+                    Gender::Female => 2,
+                }),
+                last_modified: Some(now.clone()),
+            };
+            changes.push((GENDER, message_field(GENDER, gender)?));
+        }
+        if let Some(kg) = self.weight {
+            changes.push((WEIGHT, message_field(WEIGHT, measure(kg))?));
+        }
+        if let Some(cm) = self.height {
+            changes.push((HEIGHT, message_field(HEIGHT, measure(cm))?));
+        }
+
+        let heart_rates = [
+            (MAX_HEART_RATE, self.max_heart_rate),
+            (RESTING_HEART_RATE, self.resting_heart_rate),
+            (AEROBIC_THRESHOLD, self.aerobic_threshold),
+            (ANAEROBIC_THRESHOLD, self.anaerobic_threshold),
+        ];
+        for (tag, bpm) in &heart_rates {
+            if let Some(bpm) = bpm {
+                changes.push((
+                    *tag,
+                    message_field(*tag, attribute(*bpm, Some(SOURCE_USER)))?,
+                ));
+            }
+        }
+
+        if let Some(vo2max) = self.vo2max {
+            changes.push((VO2MAX, message_field(VO2MAX, attribute(vo2max, None))?));
+        }
+        if let Some(watts) = self.ftp {
+            changes.push((FTP, message_field(FTP, attribute(watts, None))?));
+        }
+
+        if changes.is_empty() {
+            return Ok(file.to_vec());
+        }
+
+        changes.push((LAST_MODIFIED, message_field(LAST_MODIFIED, now)?));
+        replace_fields(file, changes)
+    }
+
+    // Sets a field by one of the names in FIELDS, from text like "185" or "1990-05-31"
+    pub fn set_field(&mut self, name: &str, value: &str) -> Result<(), PolarError> {
+        match name {
+            "birthday" => self.birthday = Some(value.parse()?),
+            "gender" => self.gender = Some(value.parse()?),
+            "weight" => self.weight = Some(measure(name, value)?),
+            "height" => self.height = Some(measure(name, value)?),
+            "max_hr" => self.max_heart_rate = Some(positive(name, value)?),
+            "resting_hr" => self.resting_heart_rate = Some(positive(name, value)?),
+            "aerobic_threshold" => self.aerobic_threshold = Some(positive(name, value)?),
+            "anaerobic_threshold" => self.anaerobic_threshold = Some(positive(name, value)?),
+            "vo2max" => self.vo2max = Some(positive(name, value)?),
+            "ftp" => self.ftp = Some(positive(name, value)?),
+            _ => {
+                return Err(PolarError::new(format!(
+                    "Unknown setting '{}', use one of {}",
+                    name,
+                    FIELDS.join(", ")
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    // One "name = value" line per setting, the ones missing from the file are left out
+    pub fn to_text(&self) -> String {
+        let values = [
+            self.birthday.map(|date| date.to_string()),
+            self.gender.map(|gender| gender.to_string()),
+            self.weight.map(|kg| format!("{} kg", kg)),
+            self.height.map(|cm| format!("{} cm", cm)),
+            self.max_heart_rate.map(|bpm| format!("{} bpm", bpm)),
+            self.resting_heart_rate.map(|bpm| format!("{} bpm", bpm)),
+            self.aerobic_threshold.map(|bpm| format!("{} bpm", bpm)),
+            self.anaerobic_threshold.map(|bpm| format!("{} bpm", bpm)),
+            self.vo2max.map(|vo2max| vo2max.to_string()),
+            self.ftp.map(|watts| format!("{} W", watts)),
+        ];
+
+        FIELDS
+            .iter()
+            .zip(values.iter())
+            .filter_map(|(name, value)| Some(format!("{} = {}\n", name, value.as_ref()?)))
+            .collect()
+    }
+}
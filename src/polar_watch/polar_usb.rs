@@ -1,205 +1,388 @@
 extern crate rusb;
+mod framing;
+pub mod notification;
+pub mod pcap;
 pub mod polar_error;
+pub mod trace;
 
 pub type Device = rusb::Device<rusb::Context>;
 pub type DeviceHandle = rusb::DeviceHandle<rusb::Context>;
 
 #[allow(unused_imports)]
 use log::{debug, info};
+use notification::{Notification, NotificationHandler};
+use pcap::PcapWriter;
 use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use trace::Direction;
+
+// Called with the number of bytes transferred so far and the total, when it is known
+pub type Progress<'a> = &'a mut dyn FnMut(usize, Option<usize>);
 
 pub fn tail_bits(value: usize) -> u8 {
     u8::try_from(value % 256).unwrap()
 }
 
+// USB parameters, the defaults work for the models we tested with
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsbConfig {
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    // Size of each interrupt transfer, including the 3 header bytes
+    pub packet_size: usize,
+    pub interface: u8,
+    pub in_endpoint: u8,
+    pub out_endpoint: u8,
+    pub detach_kernel_driver: bool,
+    // Logs every frame with its decoded header and a hex dump
+    pub trace: bool,
+    // Writes every frame to this pcap file
+    pub capture: Option<PathBuf>,
+}
+
+impl Default for UsbConfig {
+    fn default() -> UsbConfig {
+        UsbConfig {
+            read_timeout: Duration::from_secs(5),
+            write_timeout: Duration::from_secs(5),
+            packet_size: PolarUsb::PACKET_SIZE,
+            interface: 0,
+            in_endpoint: 1,
+            out_endpoint: 1,
+            detach_kernel_driver: true,
+            trace: false,
+            capture: None,
+        }
+    }
+}
+
+impl UsbConfig {
+    pub fn validate(&self) -> Result<(), polar_error::PolarError> {
+        // The size of each packet is sent in 6 bits, so packets can't have more than 63 bytes
+        // after the first one
+        if self.packet_size < 8 || self.packet_size > 64 {
+            return Err(polar_error::PolarError::new(format!(
+                "Packet size must be between 8 and 64, got {}",
+                self.packet_size
+            )));
+        }
+
+        Ok(())
+    }
+}
+
 // Interface to interact with Polar watches through a USB connection
 pub struct PolarUsb {
     handle: DeviceHandle,
+    config: UsbConfig,
+    cancel: Option<Arc<AtomicBool>>,
+    notification_handler: Option<NotificationHandler>,
+    capture: Option<framing::Capture<std::fs::File>>,
 }
 impl PolarUsb {
-    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
     pub const PACKET_SIZE: usize = 64;
 
     pub fn new(device: rusb::Device<rusb::Context>) -> Result<PolarUsb, rusb::Error> {
+        PolarUsb::open(device, UsbConfig::default())
+    }
+
+    pub fn with_config(
+        device: rusb::Device<rusb::Context>,
+        config: &UsbConfig,
+    ) -> Result<PolarUsb, polar_error::PolarError> {
+        config.validate()?;
+        let mut usb = PolarUsb::open(device, config.clone())?;
+
+        if let Some(path) = &config.capture {
+            usb.start_capture(path)?;
+        }
+
+        Ok(usb)
+    }
+
+    fn open(
+        device: rusb::Device<rusb::Context>,
+        config: UsbConfig,
+    ) -> Result<PolarUsb, rusb::Error> {
         let mut handle = device.open()?;
 
         // Try to detach kernel driver if it is active. Be careful because operation might not be
         // supported, and in that case don't do anything
-        debug!("Trying to detach kernel driver");
-        match handle.kernel_driver_active(0) {
-            Ok(_) => match handle.detach_kernel_driver(0) {
-                // Err(rusb::Error::NotSupported) => Ok(()),
-                Err(rusb::Error::NotFound) => Ok(()),
-                other => other,
-            },
-            Err(rusb::Error::NotSupported) => Ok(()),
-            Err(other) => Err(other),
-        }?;
+        if config.detach_kernel_driver {
+            debug!("Trying to detach kernel driver");
+            match handle.kernel_driver_active(config.interface) {
+                Ok(_) => match handle.detach_kernel_driver(config.interface) {
+                    // Err(rusb::Error::NotSupported) => Ok(()),
+                    Err(rusb::Error::NotFound) => Ok(()),
+                    other => other,
+                },
+                Err(rusb::Error::NotSupported) => Ok(()),
+                Err(other) => Err(other),
+            }?;
+        }
 
         debug!("Claiming usb interface");
-        handle.claim_interface(0)?;
-
-        Ok(PolarUsb { handle })
+        handle.claim_interface(config.interface)?;
+
+        Ok(PolarUsb {
+            handle,
+            config,
+            cancel: None,
+            notification_handler: None,
+            capture: None,
+        })
     }
 
-    // TODO: Remove one of the request types
-    pub fn simple_request(&mut self, data: &[u8]) -> Result<Vec<u8>, polar_error::PolarError> {
-        let mut packet = vec![tail_bits(data.len()), 0x0];
-        packet.extend_from_slice(data);
-        packet.push(0);
-
-        self.request(packet.as_slice())
+    // When the flag is set, no new request is sent. An answer already coming is read to the end
+    // and dropped, so the watch is ready for the next request
+    pub fn set_cancel_flag(&mut self, cancel: Option<Arc<AtomicBool>>) {
+        self.cancel = cancel;
     }
 
-    pub fn request(&mut self, data: &[u8]) -> Result<Vec<u8>, polar_error::PolarError> {
-        debug!("REQUEST {:?}", data);
+    pub fn serial_number(&self) -> Result<String, rusb::Error> {
+        let descriptor = self.handle.device().device_descriptor()?;
+        self.handle.read_serial_number_string_ascii(&descriptor)
+    }
 
-        let chunk_size = PolarUsb::PACKET_SIZE - 3;
+    pub fn set_trace(&mut self, trace: bool) {
+        self.config.trace = trace;
+    }
 
-        let packets: Vec<&[u8]> = data.chunks(chunk_size).collect();
+    fn trace_frame(&self, direction: Direction, frame: &[u8]) {
+        if self.config.trace {
+            info!("TRACE {}", trace::describe_frame(direction, frame));
+        }
+    }
 
-        for packet_id in 0..packets.len() {
-            let has_more_packets = packet_id != packets.len() - 1;
+    pub fn trace_request(&self, request: &[u8]) {
+        if self.config.trace {
+            match trace::describe_request(request) {
+                Some(description) => info!("TRACE REQUEST {}", description),
+                None => info!("TRACE REQUEST with unknown format"),
+            }
+        }
+    }
 
-            self.send_packet(packets[packet_id], packet_id, has_more_packets)?;
+    // For answers the layers above know how to decode
+    pub fn trace_answer(&self, answer: &dyn std::fmt::Debug) {
+        if self.config.trace {
+            info!("TRACE ANSWER {:?}", answer);
+        }
+    }
 
-            if has_more_packets {
-                self.usb_read()?;
+    // Replaces the file of any capture in progress
+    pub fn start_capture(&mut self, path: &Path) -> Result<(), polar_error::PolarError> {
+        let writer = std::fs::File::create(path).and_then(PcapWriter::new);
+        let device = self.handle.device();
+
+        match writer {
+            Ok(writer) => {
+                self.capture = Some(framing::Capture {
+                    writer,
+                    bus: device.bus_number(),
+                    address: device.address(),
+                    in_endpoint: self.config.in_endpoint,
+                    out_endpoint: self.config.out_endpoint,
+                    packet_size: self.config.packet_size,
+                });
+                Ok(())
             }
+            Err(error) => Err(polar_error::PolarError::new(format!(
+                "Failed to create capture '{}'\n\t{:?}",
+                path.display(),
+                error
+            ))),
         }
+    }
 
-        let answer = self.read()?;
-        debug!("ANSWER: {:?}", answer);
-
-        Ok(answer)
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
     }
 
-    fn send_packet(
-        &mut self,
-        data: &[u8],
-        packet_id: usize,
-        has_more_packets: bool,
-    ) -> Result<usize, polar_error::PolarError> {
-        debug!("SEND_PACKET {} {:?}", data.len(), data);
+    // Runs a transfer over this watch, with every packet recorded when capturing
+    fn with_capture<T>(&mut self, transfer: impl FnOnce(&mut dyn framing::Link) -> T) -> T {
+        let mut capture = self.capture.take();
+        let result = transfer(&mut framing::CapturingLink {
+            link: self,
+            capture: &mut capture,
+        });
+        self.capture = capture;
 
-        // Each packet must be exactly full, except for the last one
-        assert!(!has_more_packets || data.len() == PolarUsb::PACKET_SIZE - 3);
+        result
+    }
 
-        // This makes no sense, but that's how it works
-        let data_size = if has_more_packets {
-            data.len() + 1
-        } else {
-            data.len() + 1
-        };
+    pub fn set_notification_handler(&mut self, handler: Option<NotificationHandler>) {
+        self.notification_handler = handler;
+    }
 
-        let mut packet = vec![0x1, tail_bits(data_size) << 2, tail_bits(packet_id)];
-        packet.extend_from_slice(data);
+    // Waits up to timeout for a packet while no request is running. Returns the notification if it
+    // was one, anything else is dropped
+    pub fn poll_notification(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<Notification>, polar_error::PolarError> {
+        let packet = match self.usb_read_with(timeout) {
+            Ok(packet) => packet,
+            Err(polar_error::PolarError::LibusbError {
+                error: rusb::Error::Timeout,
+            }) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        framing::record(&mut self.capture, Direction::In, &packet);
 
-        if has_more_packets {
-            packet[1] = packet[1] | 0x01;
+        if packet[0] == 0x11 && (packet[1] & 0x2) != 0 {
+            Ok(Some(self.proccess_notification(&packet)))
+        } else {
+            debug!("Dropping unexpected packet: {:?}", packet);
+            Ok(None)
         }
+    }
 
-        let bytes_written = self.usb_write(packet)?;
-        Ok(bytes_written)
+    pub fn product_name(&self) -> Result<String, rusb::Error> {
+        let descriptor = self.handle.device().device_descriptor()?;
+        self.handle.read_product_string_ascii(&descriptor)
     }
 
-    fn read(&mut self) -> Result<Vec<u8>, polar_error::PolarError> {
-        let mut packet_id: u8 = 0;
-        let mut initial_packet = true;
-        let mut data = Vec::new();
+    // The interface, kernel driver and capture settings only take effect when opening
+    pub fn set_config(&mut self, config: &UsbConfig) -> Result<(), polar_error::PolarError> {
+        config.validate()?;
+        self.config = config.clone();
 
-        loop {
-            let packet = self.usb_read()?;
-            debug!("PACKET: {:?}", packet);
+        Ok(())
+    }
 
-            if packet[0] != 0x11 {
-                return Err(PolarUsb::proccess_error(packet));
+    fn check_cancelled(&self) -> Result<(), polar_error::PolarError> {
+        match &self.cancel {
+            Some(cancel) if cancel.load(Ordering::SeqCst) => {
+                Err(polar_error::PolarError::new("Operation cancelled"))
             }
+            _ => Ok(()),
+        }
+    }
 
-            let mut start: usize = 3;
-            let mut size: usize = usize::from(packet[1] >> 2);
-            let has_more = (packet[1] & 0x01) != 0;
-            let is_notification = (packet[1] & 0x2) != 0;
+    // TODO: Remove one of the request types
+    pub fn simple_request(&mut self, data: &[u8]) -> Result<Vec<u8>, polar_error::PolarError> {
+        let mut packet = vec![tail_bits(data.len()), 0x0];
+        packet.extend_from_slice(data);
+        packet.push(0);
 
-            if is_notification {
-                PolarUsb::proccess_notification(packet);
-                continue;
-            }
+        self.request(packet.as_slice())
+    }
 
-            if initial_packet {
-                let is_error = packet[3] != 0;
+    // Like simple_request, but the answer is written into answer as it arrives
+    pub fn simple_request_into(
+        &mut self,
+        data: &[u8],
+        answer: &mut dyn Write,
+        progress: Progress,
+    ) -> Result<usize, polar_error::PolarError> {
+        let mut packet = vec![tail_bits(data.len()), 0x0];
+        packet.extend_from_slice(data);
+        packet.push(0);
 
-                if is_error {
-                    return Err(PolarUsb::proccess_error(packet));
-                }
+        debug!("REQUEST {:?}", packet);
+        self.trace_request(&packet);
+        self.send(&mut packet.as_slice(), packet.len(), &mut |_, _| ())?;
 
-                size = std::cmp::max(size, 2) - 2;
-                start += 2;
-            }
+        self.read_into(answer, progress)
+    }
 
-            assert!(packet[2] == packet_id);
+    pub fn request(&mut self, data: &[u8]) -> Result<Vec<u8>, polar_error::PolarError> {
+        debug!("REQUEST {:?}", data);
+        self.trace_request(data);
 
-            if !has_more {
-                // Skip trailing 0x0
-                let slice_end = if size == 0 { start } else { start + size - 1 };
-                data.extend_from_slice(&packet[start..slice_end]);
-                return Ok(data);
-            } else {
-                let slice_end = if size == 0 { start } else { start + size - 1 };
-                data.extend_from_slice(&packet[start..slice_end]);
+        let mut reader = data;
+        let answer = self.request_from(&mut reader, data.len(), &mut |_, _| ())?;
+        debug!("ANSWER: {:?}", answer);
 
-                // Send ack and get the next part
-                let ack = [1, 1 << 2 | 0x1, packet_id].to_vec();
+        Ok(answer)
+    }
 
-                self.usb_write(ack)?;
+    // Like request, but the data is read as it is sent
+    pub fn request_from(
+        &mut self,
+        data: &mut dyn Read,
+        size: usize,
+        progress: Progress,
+    ) -> Result<Vec<u8>, polar_error::PolarError> {
+        self.send(data, size, progress)?;
+        self.read()
+    }
 
-                if packet_id == 0xff {
-                    packet_id = 0;
-                } else {
-                    packet_id += 1;
-                }
-            }
+    // Sends size bytes taken from data, one packet at a time, so that the whole request never
+    // has to be in memory
+    pub fn send(
+        &mut self,
+        data: &mut dyn Read,
+        size: usize,
+        progress: Progress,
+    ) -> Result<(), polar_error::PolarError> {
+        let packet_size = self.config.packet_size;
+        self.with_capture(|link| framing::send(link, data, size, packet_size, progress))
+    }
 
-            initial_packet = false;
-        }
+    fn read(&mut self) -> Result<Vec<u8>, polar_error::PolarError> {
+        let mut data = Vec::new();
+        self.read_into(&mut data, &mut |_, _| ())?;
+
+        Ok(data)
+    }
+
+    // Reads an answer, writing each packet into data as soon as it arrives
+    pub fn read_into(
+        &mut self,
+        data: &mut dyn Write,
+        progress: Progress,
+    ) -> Result<usize, polar_error::PolarError> {
+        let packet_size = self.config.packet_size;
+        self.with_capture(|link| framing::read_into(link, data, packet_size, progress))
     }
 
     fn usb_read(&mut self) -> Result<Vec<u8>, polar_error::PolarError> {
-        let mut data: [u8; PolarUsb::PACKET_SIZE] = [0; 64];
+        self.usb_read_with(self.config.read_timeout)
+    }
+
+    fn usb_read_with(&mut self, timeout: Duration) -> Result<Vec<u8>, polar_error::PolarError> {
+        let mut data = vec![0u8; self.config.packet_size];
 
         self.handle.read_interrupt(
-            1 | rusb::constants::LIBUSB_ENDPOINT_IN,
+            self.config.in_endpoint | rusb::constants::LIBUSB_ENDPOINT_IN,
             &mut data,
-            PolarUsb::TIMEOUT,
+            timeout,
         )?;
+        self.trace_frame(Direction::In, &data);
 
-        Ok(data.to_vec())
+        Ok(data)
     }
 
     fn usb_write(&mut self, mut data: Vec<u8>) -> Result<usize, rusb::Error> {
-        assert!(data.len() <= PolarUsb::PACKET_SIZE);
+        assert!(data.len() <= self.config.packet_size);
+        self.trace_frame(Direction::Out, &data);
 
-        if data.len() < PolarUsb::PACKET_SIZE {
-            data.resize(PolarUsb::PACKET_SIZE, 0);
+        if data.len() < self.config.packet_size {
+            data.resize(self.config.packet_size, 0);
         }
 
         self.handle.write_interrupt(
-            1 | rusb::constants::LIBUSB_ENDPOINT_OUT,
+            self.config.out_endpoint | rusb::constants::LIBUSB_ENDPOINT_OUT,
             &data,
-            PolarUsb::TIMEOUT,
+            self.config.write_timeout,
         )
     }
 
-    fn proccess_notification(data: Vec<u8>) {
-        match data[3] {
-            10 => info!("Notification received: push notification settings"),
-            3 => info!("Notification received: battery status: {}%", data[5]),
-            2 => info!("Notification received: device is idling"),
-            _ => info!(
-                "Notification received: unknown type {} ({:?})",
-                data[3], data
-            ),
-        };
+    fn proccess_notification(&mut self, data: &[u8]) -> Notification {
+        let notification = Notification::parse(data);
+        info!("Notification received: {}", notification);
+
+        if let Some(handler) = &mut self.notification_handler {
+            handler(&notification);
+        }
+
+        notification
     }
 
     fn proccess_error(data: Vec<u8>) -> polar_error::PolarError {
@@ -207,28 +390,57 @@ impl PolarUsb {
 
         assert!(error != 0);
 
-        match error {
-            1 => polar_error::PolarError::new("Error: rebooting"),
-            2 => polar_error::PolarError::new("Error: try again"),
-            100 => polar_error::PolarError::new("Error: unidentified host error"),
-            101 => polar_error::PolarError::new("Error: invalid command"),
-            102 => polar_error::PolarError::new("Error: invalid parameter"),
-            103 => polar_error::PolarError::new("Error: no such file or directory"),
-            104 => polar_error::PolarError::new("Error: directory exists"),
-            105 => polar_error::PolarError::new("Error: file exists"),
-            106 => polar_error::PolarError::new("Error: operation not permitted"),
-            107 => polar_error::PolarError::new("Error: no such user"),
-            108 => polar_error::PolarError::new("Error: timeout"),
-            200 => polar_error::PolarError::new("Error: unidentified device error"),
-            201 => polar_error::PolarError::new("Error: not implemented"),
-            202 => polar_error::PolarError::new("Error: system busy"),
-            203 => polar_error::PolarError::new("Error: invalid content"),
-            204 => polar_error::PolarError::new("Error: checksum failure"),
-            205 => polar_error::PolarError::new("Error: disk full"),
-            206 => polar_error::PolarError::new("Error: prerequisite not found"),
-            207 => polar_error::PolarError::new("Error: insufficient buffer"),
-            208 => polar_error::PolarError::new("Error: wait for idling"),
-            _ => polar_error::PolarError::new("Error: unknown error #{packet[3]}"),
+        let message = match error {
+            1 => "Error: rebooting",
+            2 => "Error: try again",
+            100 => "Error: unidentified host error",
+            101 => "Error: invalid command",
+            102 => "Error: invalid parameter",
+            103 => "Error: no such file or directory",
+            104 => "Error: directory exists",
+            105 => "Error: file exists",
+            106 => "Error: operation not permitted",
+            107 => "Error: no such user",
+            108 => "Error: timeout",
+            200 => "Error: unidentified device error",
+            201 => "Error: not implemented",
+            202 => "Error: system busy",
+            203 => "Error: invalid content",
+            204 => "Error: checksum failure",
+            205 => "Error: disk full",
+            206 => "Error: prerequisite not found",
+            207 => "Error: insufficient buffer",
+            208 => "Error: wait for idling",
+            _ => {
+                return polar_error::PolarError::DeviceError {
+                    code: error,
+                    error: format!("Error: unknown error #{}", error),
+                }
+            }
+        };
+
+        polar_error::PolarError::DeviceError {
+            code: error,
+            error: message.to_string(),
         }
     }
 }
+
+impl framing::Link for PolarUsb {
+    fn read_packet(&mut self) -> Result<Vec<u8>, polar_error::PolarError> {
+        self.usb_read()
+    }
+
+    fn write_packet(&mut self, packet: Vec<u8>) -> Result<(), polar_error::PolarError> {
+        self.usb_write(packet)?;
+        Ok(())
+    }
+
+    fn notification(&mut self, packet: &[u8]) {
+        self.proccess_notification(packet);
+    }
+
+    fn check_cancelled(&self) -> Result<(), polar_error::PolarError> {
+        PolarUsb::check_cancelled(self)
+    }
+}
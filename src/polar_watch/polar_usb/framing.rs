@@ -0,0 +1,638 @@
+// How requests and answers are split into packets. The packets go through Link instead of the USB
+// device, so that the framing can be tested without a watch
+use super::pcap::PcapWriter;
+use super::polar_error::PolarError;
+use super::trace::Direction;
+use super::{tail_bits, PolarUsb, Progress};
+use log::{debug, info};
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+// Both ends of the interrupt transfers, one packet at a time
+pub trait Link {
+    fn read_packet(&mut self) -> Result<Vec<u8>, PolarError>;
+    fn write_packet(&mut self, packet: Vec<u8>) -> Result<(), PolarError>;
+    fn notification(&mut self, packet: &[u8]);
+    // An error stops the transfer before the request is sent. Once the watch is answering, the
+    // rest of the answer is still read so the next request starts in step
+    fn check_cancelled(&self) -> Result<(), PolarError>;
+}
+
+// A pcap file and the usb addresses its frames are recorded under
+pub struct Capture<W: Write> {
+    pub writer: PcapWriter<W>,
+    pub bus: u8,
+    pub address: u8,
+    pub in_endpoint: u8,
+    pub out_endpoint: u8,
+    // Frames we send are padded to this size on the wire
+    pub packet_size: usize,
+}
+
+impl<W: Write> Capture<W> {
+    fn write_frame(&mut self, direction: Direction, frame: &[u8]) -> std::io::Result<()> {
+        let mut frame = frame.to_vec();
+        let endpoint = match direction {
+            Direction::Out => {
+                frame.resize(std::cmp::max(frame.len(), self.packet_size), 0);
+                self.out_endpoint
+            }
+            Direction::In => self.in_endpoint,
+        };
+
+        self.writer.write_frame(
+            direction,
+            self.bus,
+            self.address,
+            endpoint,
+            &frame,
+            SystemTime::now(),
+        )
+    }
+}
+
+// Writes frame to the capture, if there is one. A broken capture shouldn't break the transfer, so
+// it is stopped instead
+pub fn record<W: Write>(capture: &mut Option<Capture<W>>, direction: Direction, frame: &[u8]) {
+    if let Some(writer) = capture {
+        if let Err(error) = writer.write_frame(direction, frame) {
+            info!("Failed to write capture, stopping it: {:?}", error);
+            *capture = None;
+        }
+    }
+}
+
+// Records every packet going through link, so that captures can be made over any link, the watch
+// or a scripted one
+pub struct CapturingLink<'a, W: Write> {
+    pub link: &'a mut dyn Link,
+    pub capture: &'a mut Option<Capture<W>>,
+}
+
+impl<W: Write> Link for CapturingLink<'_, W> {
+    fn read_packet(&mut self) -> Result<Vec<u8>, PolarError> {
+        let packet = self.link.read_packet()?;
+        record(self.capture, Direction::In, &packet);
+        Ok(packet)
+    }
+
+    fn write_packet(&mut self, packet: Vec<u8>) -> Result<(), PolarError> {
+        record(self.capture, Direction::Out, &packet);
+        self.link.write_packet(packet)
+    }
+
+    fn notification(&mut self, packet: &[u8]) {
+        self.link.notification(packet)
+    }
+
+    fn check_cancelled(&self) -> Result<(), PolarError> {
+        self.link.check_cancelled()
+    }
+}
+
+fn request_packet(data: &[u8], packet_id: usize, has_more_packets: bool) -> Vec<u8> {
+    debug!("SEND_PACKET {} {:?}", data.len(), data);
+
+    // This makes no sense, but that's how it works
+    let data_size = data.len() + 1;
+
+    let mut packet = vec![0x1, tail_bits(data_size) << 2, tail_bits(packet_id)];
+    packet.extend_from_slice(data);
+
+    if has_more_packets {
+        packet[1] |= 0x01;
+    }
+
+    packet
+}
+
+// Sends size bytes taken from data, one packet at a time, so that the whole request never has to
+// be in memory. Each packet is full except for the last one, and nothing is sent when size is 0
+pub fn send(
+    link: &mut dyn Link,
+    data: &mut dyn Read,
+    size: usize,
+    packet_size: usize,
+    progress: Progress,
+) -> Result<(), PolarError> {
+    let chunk_size = packet_size - 3;
+    let mut chunk = vec![0u8; chunk_size];
+    let mut sent = 0;
+    let mut packet_id = 0;
+
+    // The watch expects every packet of a request once it gets the first, so the request can't
+    // stop halfway
+    link.check_cancelled()?;
+
+    while sent < size {
+        let length = std::cmp::min(chunk_size, size - sent);
+        data.read_exact(&mut chunk[..length]).map_err(|error| {
+            PolarError::new(format!("Failed to read data to send: {:?}", error))
+        })?;
+
+        sent += length;
+        let has_more_packets = sent < size;
+
+        link.write_packet(request_packet(
+            &chunk[..length],
+            packet_id,
+            has_more_packets,
+        ))?;
+        progress(sent, Some(size));
+
+        if has_more_packets {
+            link.read_packet()?;
+            packet_id += 1;
+        }
+    }
+
+    Ok(())
+}
+
+// Reads an answer, writing each packet into data as soon as it arrives. When cancelled, the rest
+// of the answer is acknowledged and dropped before returning the error. Packets are never read
+// past packet_size, whatever size they claim to have
+pub fn read_into(
+    link: &mut dyn Link,
+    data: &mut dyn Write,
+    packet_size: usize,
+    progress: Progress,
+) -> Result<usize, PolarError> {
+    let mut packet_id: u8 = 0;
+    let mut initial_packet = true;
+    let mut received = 0;
+    let mut cancelled = None;
+
+    loop {
+        if cancelled.is_none() {
+            cancelled = link.check_cancelled().err();
+        }
+
+        let packet = link.read_packet()?;
+        debug!("PACKET: {:?}", packet);
+        let available = std::cmp::min(packet.len(), packet_size);
+
+        if available < 5 {
+            return Err(PolarError::new(format!(
+                "Got a packet of {} bytes, too short for an answer",
+                available
+            )));
+        }
+
+        if packet[0] != 0x11 && packet[3] == 0 {
+            return Err(PolarError::new(format!(
+                "Got a packet of unknown type {:#x}",
+                packet[0]
+            )));
+        }
+
+        if packet[0] != 0x11 {
+            return Err(PolarUsb::proccess_error(packet));
+        }
+
+        let mut start: usize = 3;
+        let mut size: usize = usize::from(packet[1] >> 2);
+        let has_more = (packet[1] & 0x01) != 0;
+        let is_notification = (packet[1] & 0x2) != 0;
+
+        if is_notification {
+            link.notification(&packet);
+            continue;
+        }
+
+        if initial_packet {
+            let is_error = packet[3] != 0;
+
+            if is_error {
+                return Err(PolarUsb::proccess_error(packet));
+            }
+
+            size = std::cmp::max(size, 2) - 2;
+            start += 2;
+        }
+
+        if packet[2] != packet_id {
+            return Err(PolarError::new(format!(
+                "Expected packet {} of the answer, got {}",
+                packet_id, packet[2]
+            )));
+        }
+
+        // Skip trailing 0x0
+        let slice_end = if size == 0 { start } else { start + size - 1 };
+        if slice_end > available {
+            return Err(PolarError::new(format!(
+                "Packet {} of the answer claims {} bytes, only {} fit in it",
+                packet_id,
+                slice_end - start,
+                available - start
+            )));
+        }
+
+        if cancelled.is_none() {
+            data.write_all(&packet[start..slice_end])
+                .map_err(|error| PolarError::new(format!("Failed to write answer: {:?}", error)))?;
+
+            received += slice_end - start;
+            progress(received, None);
+        }
+
+        if !has_more {
+            return match cancelled {
+                Some(error) => Err(error),
+                None => Ok(received),
+            };
+        }
+
+        // Send ack and get the next part
+        link.write_packet(vec![1, 1 << 2 | 0x1, packet_id])?;
+
+        packet_id = packet_id.wrapping_add(1);
+        initial_packet = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    const PACKET_SIZE: usize = 64;
+
+    // Plays the watch: answers reads from a script and keeps everything written to it
+    #[derive(Default)]
+    struct ScriptedLink {
+        answers: VecDeque<Vec<u8>>,
+        written: Vec<Vec<u8>>,
+        notifications: usize,
+        // Cancelled once this many packets were read
+        cancel_after: Option<usize>,
+        // Every packet read, as it was read
+        read_packets: Vec<Vec<u8>>,
+    }
+
+    impl ScriptedLink {
+        fn new(answers: Vec<Vec<u8>>) -> ScriptedLink {
+            ScriptedLink {
+                answers: answers.into(),
+                ..ScriptedLink::default()
+            }
+        }
+    }
+
+    impl Link for ScriptedLink {
+        fn read_packet(&mut self) -> Result<Vec<u8>, PolarError> {
+            let mut packet = self
+                .answers
+                .pop_front()
+                .ok_or_else(|| PolarError::new("Nothing left to read"))?;
+            packet.resize(PACKET_SIZE, 0);
+            self.read_packets.push(packet.clone());
+
+            Ok(packet)
+        }
+
+        fn write_packet(&mut self, packet: Vec<u8>) -> Result<(), PolarError> {
+            assert!(packet.len() <= PACKET_SIZE);
+            self.written.push(packet);
+            Ok(())
+        }
+
+        fn notification(&mut self, _packet: &[u8]) {
+            self.notifications += 1;
+        }
+
+        fn check_cancelled(&self) -> Result<(), PolarError> {
+            if self
+                .cancel_after
+                .is_some_and(|after| self.read_packets.len() >= after)
+            {
+                Err(PolarError::new("Operation cancelled"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    // An answer split into packets the way the watch sends it: status bytes in the first one and
+    // a trailing 0 in each
+    fn answer_packets(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        let mut rest = data;
+        let mut packet_id = 0u8;
+
+        loop {
+            let first = packets.is_empty();
+            let room = PACKET_SIZE - 4 - if first { 2 } else { 0 };
+            let length = std::cmp::min(room, rest.len());
+            let has_more = length < rest.len();
+
+            let size = length + 1 + if first { 2 } else { 0 };
+            let mut packet = vec![0x11, (size << 2) as u8 | has_more as u8, packet_id];
+            if first {
+                packet.extend_from_slice(&[0, 0]);
+            }
+            packet.extend_from_slice(&rest[..length]);
+            packet.push(0);
+            packets.push(packet);
+
+            rest = &rest[length..];
+            packet_id = packet_id.wrapping_add(1);
+            if !has_more {
+                return packets;
+            }
+        }
+    }
+
+    fn data(size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn requests_are_split_into_full_packets() {
+        let request = data(130);
+        let mut link = ScriptedLink::new(vec![vec![1], vec![1]]);
+        let mut reported = vec![];
+
+        send(
+            &mut link,
+            &mut request.as_slice(),
+            request.len(),
+            PACKET_SIZE,
+            &mut |sent, total| reported.push((sent, total)),
+        )
+        .unwrap();
+
+        let headers: Vec<&[u8]> = link.written.iter().map(|packet| &packet[..3]).collect();
+        assert_eq!(
+            headers,
+            vec![
+                &[1, 62 << 2 | 1, 0][..],
+                &[1, 62 << 2 | 1, 1],
+                &[1, 9 << 2, 2]
+            ]
+        );
+
+        let sent: Vec<u8> = link
+            .written
+            .iter()
+            .flat_map(|packet| packet[3..].to_vec())
+            .collect();
+        assert_eq!(sent, request);
+        assert!(link.answers.is_empty());
+        assert_eq!(
+            reported,
+            vec![(61, Some(130)), (122, Some(130)), (130, Some(130))]
+        );
+    }
+
+    #[test]
+    fn empty_requests_send_nothing() {
+        let mut link = ScriptedLink::default();
+        send(&mut link, &mut [].as_ref(), 0, PACKET_SIZE, &mut |_, _| ()).unwrap();
+
+        assert!(link.written.is_empty());
+    }
+
+    #[test]
+    fn answers_are_joined_and_acknowledged() {
+        let answer = data(200);
+        let mut link = ScriptedLink::new(answer_packets(&answer));
+
+        // A notification in the middle doesn't belong to the answer
+        link.answers.insert(1, vec![0x11, 3 << 2 | 0x2, 0, 2, 80]);
+
+        let mut received = vec![];
+        let size = read_into(&mut link, &mut received, PACKET_SIZE, &mut |_, _| ()).unwrap();
+
+        assert_eq!(size, answer.len());
+        assert_eq!(received, answer);
+        assert_eq!(link.notifications, 1);
+        assert_eq!(
+            link.written,
+            vec![vec![1, 5, 0], vec![1, 5, 1], vec![1, 5, 2]]
+        );
+    }
+
+    fn capture<W: Write>(writer: W) -> Option<Capture<W>> {
+        Some(Capture {
+            writer: PcapWriter::new(writer).unwrap(),
+            bus: 1,
+            address: 7,
+            in_endpoint: 1,
+            out_endpoint: 1,
+            packet_size: PACKET_SIZE,
+        })
+    }
+
+    // Event, endpoint and frame of each record in a pcap file
+    fn records(pcap: &[u8]) -> Vec<(u8, u8, Vec<u8>)> {
+        let mut records = vec![];
+        let mut rest = &pcap[24..];
+
+        while !rest.is_empty() {
+            let length = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+            let usbmon = &rest[16..16 + 64];
+            records.push((usbmon[8], usbmon[10], rest[16 + 64..16 + length].to_vec()));
+            rest = &rest[16 + length..];
+        }
+
+        records
+    }
+
+    #[test]
+    fn scripted_transfers_can_be_captured() {
+        // A get with a two packet answer, then a put of two packets
+        let answer = data(80);
+        let mut answers = answer_packets(&answer);
+        answers.push(vec![1]);
+        answers.push(vec![0x11, 3 << 2, 0, 0, 0, 0]);
+        let mut link = ScriptedLink::new(answers);
+        let mut capture = capture(vec![]);
+
+        let mut capturing = CapturingLink {
+            link: &mut link,
+            capture: &mut capture,
+        };
+        send(
+            &mut capturing,
+            &mut [9, 0].as_ref(),
+            2,
+            PACKET_SIZE,
+            &mut |_, _| (),
+        )
+        .unwrap();
+        let mut received = vec![];
+        read_into(&mut capturing, &mut received, PACKET_SIZE, &mut |_, _| ()).unwrap();
+        let put = data(100);
+        send(
+            &mut capturing,
+            &mut put.as_slice(),
+            put.len(),
+            PACKET_SIZE,
+            &mut |_, _| (),
+        )
+        .unwrap();
+        read_into(&mut capturing, &mut vec![], PACKET_SIZE, &mut |_, _| ()).unwrap();
+        assert_eq!(received, answer);
+
+        let pcap = capture.unwrap().writer.into_inner();
+        let records = records(&pcap);
+        let kinds: Vec<(u8, u8)> = records
+            .iter()
+            .map(|(event, endpoint, _)| (*event, *endpoint))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (b'S', 0x01),
+                (b'C', 0x81),
+                (b'S', 0x01),
+                (b'C', 0x81),
+                (b'S', 0x01),
+                (b'C', 0x81),
+                (b'S', 0x01),
+                (b'C', 0x81),
+            ]
+        );
+
+        // What was sent, padded like on the wire, and what was read
+        let mut request = vec![1, 3 << 2, 0, 9, 0];
+        request.resize(PACKET_SIZE, 0);
+        assert_eq!(records[0].2, request);
+        assert_eq!(records[1].2, link.read_packets[0]);
+        assert_eq!(records[2].2[..3], [1, 5, 0]);
+        assert_eq!(records[4].2[3..], put[..61]);
+        assert!(records
+            .iter()
+            .all(|(_, _, frame)| frame.len() == PACKET_SIZE));
+    }
+
+    // Accepts the pcap header and fails every write after it
+    struct BrokenWriter(usize);
+
+    impl Write for BrokenWriter {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if self.0 < 24 {
+                self.0 += data.len();
+                Ok(data.len())
+            } else {
+                Err(std::io::Error::other("Disk full"))
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn broken_captures_stop_without_breaking_the_transfer() {
+        let answer = data(10);
+        let mut link = ScriptedLink::new(answer_packets(&answer));
+        let mut capture = capture(BrokenWriter(0));
+
+        let mut received = vec![];
+        let mut capturing = CapturingLink {
+            link: &mut link,
+            capture: &mut capture,
+        };
+        read_into(&mut capturing, &mut received, PACKET_SIZE, &mut |_, _| ()).unwrap();
+
+        assert_eq!(received, answer);
+        assert!(capture.is_none());
+    }
+
+    #[test]
+    fn error_statuses_become_device_errors() {
+        let mut link = ScriptedLink::new(vec![vec![0x11, 2 << 2, 0, 103, 0]]);
+
+        let error = read_into(&mut link, &mut vec![], PACKET_SIZE, &mut |_, _| ()).unwrap_err();
+        assert_eq!(error.device_code(), Some(103));
+    }
+
+    #[test]
+    fn cancelling_drains_the_answer() {
+        let first = data(150);
+        let second = data(70);
+        let mut packets = answer_packets(&first);
+        packets.extend(answer_packets(&second));
+
+        // Cancelled after the first packet of the first answer
+        let mut link = ScriptedLink::new(packets);
+        link.cancel_after = Some(1);
+
+        let mut received = vec![];
+        let error = read_into(&mut link, &mut received, PACKET_SIZE, &mut |_, _| ()).unwrap_err();
+        assert_eq!(
+            format!("{:?}", error),
+            format!("{:?}", PolarError::new("Operation cancelled"))
+        );
+        assert_eq!(received, &first[..58]);
+
+        // Every packet of the first answer was acknowledged, so the next one reads fine
+        assert_eq!(link.written, vec![vec![1, 5, 0], vec![1, 5, 1]]);
+        link.cancel_after = None;
+        let mut received = vec![];
+        read_into(&mut link, &mut received, PACKET_SIZE, &mut |_, _| ()).unwrap();
+        assert_eq!(received, second);
+        assert!(link.answers.is_empty());
+    }
+
+    #[test]
+    fn requests_are_only_cancelled_before_they_start() {
+        let request = data(130);
+        let mut link = ScriptedLink::new(vec![vec![1], vec![1]]);
+        link.cancel_after = Some(0);
+        let result = send(
+            &mut link,
+            &mut request.as_slice(),
+            130,
+            PACKET_SIZE,
+            &mut |_, _| (),
+        );
+        assert!(result.is_err());
+        assert!(link.written.is_empty());
+
+        // Cancelling halfway leaves the request whole
+        link.cancel_after = Some(1);
+        send(
+            &mut link,
+            &mut request.as_slice(),
+            130,
+            PACKET_SIZE,
+            &mut |_, _| (),
+        )
+        .unwrap();
+        assert_eq!(link.written.len(), 3);
+    }
+
+    #[test]
+    fn packets_out_of_order_are_an_error() {
+        let mut packets = answer_packets(&data(100));
+        packets[1][2] = 5;
+
+        let mut link = ScriptedLink::new(packets);
+        assert!(read_into(&mut link, &mut vec![], PACKET_SIZE, &mut |_, _| ()).is_err());
+    }
+
+    #[test]
+    fn sizes_past_the_packet_are_an_error() {
+        // 63 bytes claimed after the 5 header bytes of the first packet
+        let mut packet = vec![0x11, 63 << 2, 0, 0, 0];
+        packet.resize(PACKET_SIZE, 1);
+
+        let mut link = ScriptedLink::new(vec![packet.clone()]);
+        assert!(read_into(&mut link, &mut vec![], PACKET_SIZE, &mut |_, _| ()).is_err());
+
+        // The same when the configured packets are smaller than what was read
+        let mut link = ScriptedLink::new(answer_packets(&data(40)));
+        assert!(read_into(&mut link, &mut vec![], 32, &mut |_, _| ()).is_err());
+    }
+
+    #[test]
+    fn unknown_packets_are_an_error() {
+        let mut link = ScriptedLink::new(vec![vec![0x42, 3 << 2, 0, 0, 0]]);
+        assert!(read_into(&mut link, &mut vec![], PACKET_SIZE, &mut |_, _| ()).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+// Frames the watch sends on its own, with the notification bit set. They can arrive in the middle of
+// any answer
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    PushSettings,
+    Battery { percent: u8 },
+    Idle,
+    // Types we don't know yet, with the payload starting at the type byte
+    Unknown { code: u8, data: Vec<u8> },
+}
+
+// Called with every notification, while a request is waiting for its answer or while polling
+pub type NotificationHandler = Box<dyn FnMut(&Notification) + Send>;
+
+impl Notification {
+    // Decodes a whole usb packet: [0x11, size << 2 | flags, packet id, type, ...]. The battery
+    // level is always at byte 5, whatever the size says
+    pub fn parse(packet: &[u8]) -> Notification {
+        match (packet.get(3), packet.get(5)) {
+            (Some(10), _) => Notification::PushSettings,
+            (Some(3), Some(percent)) => Notification::Battery { percent: *percent },
+            (Some(2), _) => Notification::Idle,
+            (code, _) => {
+                let size = usize::from(packet.get(1).copied().unwrap_or(0) >> 2);
+                let end = std::cmp::min(3 + size, packet.len());
+
+                Notification::Unknown {
+                    code: code.copied().unwrap_or(0),
+                    data: packet.get(3..end).unwrap_or(&[]).to_vec(),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notification::PushSettings => write!(f, "push notification settings"),
+            Notification::Battery { percent } => write!(f, "battery status: {}%", percent),
+            Notification::Idle => write!(f, "device is idling"),
+            Notification::Unknown { code, data } => write!(f, "unknown type {} ({:?})", code, data),
+        }
+    }
+}
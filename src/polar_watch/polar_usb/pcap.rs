@@ -0,0 +1,186 @@
+// Writes the usb traffic as a pcap file that Wireshark can open, using the Linux usbmon link-layer
+// header. FRAME_FIELDS describes the Polar frame header on top of it, and lua_dissector turns that
+// into a Wireshark dissector
+use super::trace::Direction;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// LINKTYPE_USB_LINUX_MMAPPED, every packet starts with a 64 byte usbmon header
+pub const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const SNAPLEN: u32 = 65535;
+const TRANSFER_INTERRUPT: u8 = 1;
+
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    next_id: u64,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> std::io::Result<PcapWriter<W>> {
+        let mut header = vec![];
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+        writer.write_all(&header)?;
+
+        Ok(PcapWriter { writer, next_id: 0 })
+    }
+
+    // Frames we send are recorded as submitted urbs, answers as completed ones. endpoint is the
+    // endpoint number without the direction bit
+    pub fn write_frame(
+        &mut self,
+        direction: Direction,
+        bus: u8,
+        address: u8,
+        endpoint: u8,
+        frame: &[u8],
+        time: SystemTime,
+    ) -> std::io::Result<()> {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (event, endpoint) = match direction {
+            Direction::Out => (b'S', endpoint & 0x7f),
+            Direction::In => (b'C', endpoint | 0x80),
+        };
+
+        let mut usbmon = vec![];
+        usbmon.extend_from_slice(&self.next_id.to_le_bytes());
+        usbmon.push(event);
+        usbmon.push(TRANSFER_INTERRUPT);
+        usbmon.push(endpoint);
+        usbmon.push(address);
+        usbmon.extend_from_slice(&u16::from(bus).to_le_bytes());
+        // No setup packet, data present
+        usbmon.push(b'-');
+        usbmon.push(0);
+        usbmon.extend_from_slice(&(time.as_secs() as i64).to_le_bytes());
+        usbmon.extend_from_slice(&(time.subsec_micros() as i32).to_le_bytes());
+        // Status
+        usbmon.extend_from_slice(&0i32.to_le_bytes());
+        usbmon.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        usbmon.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        // Setup bytes, interval, start frame, transfer flags and iso descriptors
+        usbmon.extend_from_slice(&[0; 8]);
+        usbmon.extend_from_slice(&1i32.to_le_bytes());
+        usbmon.extend_from_slice(&0i32.to_le_bytes());
+        usbmon.extend_from_slice(&0u32.to_le_bytes());
+        usbmon.extend_from_slice(&0u32.to_le_bytes());
+
+        let length = (usbmon.len() + frame.len()) as u32;
+
+        let mut record = vec![];
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&length.to_le_bytes());
+        record.extend_from_slice(&length.to_le_bytes());
+        record.extend_from_slice(&usbmon);
+        record.extend_from_slice(frame);
+
+        self.next_id += 1;
+        self.writer.write_all(&record)?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// One field of the Polar frame header: byte at offset, masked, then shifted right
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameField {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub offset: usize,
+    pub mask: u8,
+    pub shift: u8,
+}
+
+// The header built in send_packet and read_into. The payload starts at byte 3 and has length - 1
+// bytes. Acks are frames with length 1, more set and the sequence id of the packet they answer
+pub const FRAME_FIELDS: [FrameField; 5] = [
+    FrameField {
+        name: "kind",
+        description: "0x01 from host, 0x11 from watch",
+        offset: 0,
+        mask: 0xff,
+        shift: 0,
+    },
+    FrameField {
+        name: "length",
+        description: "Payload length plus one",
+        offset: 1,
+        mask: 0xfc,
+        shift: 2,
+    },
+    FrameField {
+        name: "notification",
+        description: "Frame is a notification",
+        offset: 1,
+        mask: 0x02,
+        shift: 1,
+    },
+    FrameField {
+        name: "more",
+        description: "More packets follow",
+        offset: 1,
+        mask: 0x01,
+        shift: 0,
+    },
+    FrameField {
+        name: "sequence",
+        description: "Packet id within the transfer",
+        offset: 2,
+        mask: 0xff,
+        shift: 0,
+    },
+];
+
+// Wireshark Lua dissector for the fields above, registered for the Polar usb product id. Load it
+// with wireshark -X lua_script:polar.lua
+pub fn lua_dissector(vendor_id: u16, product_id: u16) -> String {
+    let mut result = String::from("local polar = Proto(\"polar\", \"Polar watch frames\")\n\n");
+
+    for field in FRAME_FIELDS.iter() {
+        result += &format!(
+            "local f_{0} = ProtoField.uint8(\"polar.{0}\", \"{1}\", base.HEX, nil, 0x{2:02x})\n",
+            field.name, field.description, field.mask
+        );
+    }
+    result += "local f_payload = ProtoField.bytes(\"polar.payload\", \"Payload\")\n\n";
+
+    let names: Vec<String> = FRAME_FIELDS
+        .iter()
+        .map(|field| format!("f_{}", field.name))
+        .collect();
+    result += &format!("polar.fields = {{ {}, f_payload }}\n\n", names.join(", "));
+
+    result += "function polar.dissector(buffer, pinfo, tree)\n";
+    result += "    if buffer:len() < 3 then return end\n";
+    result += "    pinfo.cols.protocol = \"POLAR\"\n";
+    result += "    local subtree = tree:add(polar, buffer(), \"Polar frame\")\n";
+
+    for field in FRAME_FIELDS.iter() {
+        result += &format!(
+            "    subtree:add(f_{}, buffer({}, 1))\n",
+            field.name, field.offset
+        );
+    }
+
+    result += "    local length = bit.rshift(bit.band(buffer(1, 1):uint(), 0xfc), 2)\n";
+    result += "    if length > 1 and buffer:len() >= 2 + length then\n";
+    result += "        subtree:add(f_payload, buffer(3, length - 1))\n";
+    result += "    end\n";
+    result += "end\n\n";
+
+    result += &format!(
+        "DissectorTable.get(\"usb.product\"):add(0x{:04x}{:04x}, polar)\n",
+        vendor_id, product_id
+    );
+
+    result
+}
@@ -1,11 +1,20 @@
 extern crate rusb;
 
+// More variants may be added, so matches outside this crate need a wildcard arm
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum PolarError {
     LibusbError { error: rusb::Error },
     PolarError { error: String },
+    MismatchError { error: Mismatch },
+    // The watch answered with an error status
+    DeviceError { code: u8, error: String },
 }
 
+// Some of the statuses the watch answers with
+pub const NO_SUCH_FILE: u8 = 103;
+pub const DISK_FULL: u8 = 205;
+
 impl PolarError {
     pub fn new<S>(message: S) -> PolarError
     where
@@ -15,6 +24,14 @@ impl PolarError {
             error: message.into(),
         }
     }
+
+    // Status the watch answered with, for errors that came from it
+    pub fn device_code(&self) -> Option<u8> {
+        match self {
+            PolarError::DeviceError { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
 }
 
 impl From<rusb::Error> for PolarError {
@@ -22,3 +39,58 @@ impl From<rusb::Error> for PolarError {
         PolarError::LibusbError { error }
     }
 }
+
+impl From<Mismatch> for PolarError {
+    fn from(error: Mismatch) -> PolarError {
+        PolarError::MismatchError { error }
+    }
+}
+
+// A file on the watch is different from the one we sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub path: String,
+    pub sent_len: usize,
+    pub found_len: usize,
+    // First byte that differs, None when only the sizes or checksums were compared
+    pub first_difference: Option<usize>,
+}
+
+impl Mismatch {
+    pub fn compare(path: &str, sent: &[u8], found: &[u8]) -> Option<Mismatch> {
+        let first_difference = sent
+            .iter()
+            .zip(found.iter())
+            .position(|(sent, found)| sent != found)
+            .or_else(|| {
+                if sent.len() != found.len() {
+                    Some(std::cmp::min(sent.len(), found.len()))
+                } else {
+                    None
+                }
+            })?;
+
+        Some(Mismatch {
+            path: path.to_string(),
+            sent_len: sent.len(),
+            found_len: found.len(),
+            first_difference: Some(first_difference),
+        })
+    }
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Content on the watch doesn't match the one sent for {}: sent {} bytes, found {} bytes",
+            self.path, self.sent_len, self.found_len
+        )?;
+
+        match self.first_difference {
+            Some(offset) => write!(f, ", first difference at byte {}", offset),
+            None if self.sent_len == self.found_len => write!(f, ", with different content"),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// Readable description of the usb frames, for debugging the framing layer. Every frame looks like
+// [kind, length << 2 | notification << 1 | more, sequence id, payload...], where length counts one
+// byte more than the payload
+use crate::hex_dump;
+use polar_prost::Message;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Out,
+    In,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Out => write!(f, "OUT"),
+            Direction::In => write!(f, "IN "),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameHeader {
+    pub direction: Direction,
+    // 0x1 for frames sent by us, 0x11 for answers
+    pub kind: u8,
+    pub length: usize,
+    pub more: bool,
+    pub notification: bool,
+    pub sequence: u8,
+}
+
+impl FrameHeader {
+    pub fn parse(direction: Direction, frame: &[u8]) -> Option<FrameHeader> {
+        if frame.len() < 3 {
+            return None;
+        }
+
+        Some(FrameHeader {
+            direction,
+            kind: frame[0],
+            length: usize::from(frame[1] >> 2),
+            more: frame[1] & 0x1 != 0,
+            notification: frame[1] & 0x2 != 0,
+            sequence: frame[2],
+        })
+    }
+
+    // The bytes after the header, without the padding up to the packet size
+    pub fn payload<'a>(&self, frame: &'a [u8]) -> &'a [u8] {
+        let end = std::cmp::min(3 + self.length.saturating_sub(1), frame.len());
+        &frame[3..end]
+    }
+}
+
+impl fmt::Display for FrameHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yes_no = |value| if value { "yes" } else { "no" };
+
+        write!(
+            f,
+            "{} kind={:#04x} seq={} len={} more={} notification={}",
+            self.direction,
+            self.kind,
+            self.sequence,
+            self.length,
+            yes_no(self.more),
+            yes_no(self.notification)
+        )
+    }
+}
+
+// Header line followed by a hex dump of the payload
+pub fn describe_frame(direction: Direction, frame: &[u8]) -> String {
+    match FrameHeader::parse(direction, frame) {
+        Some(header) => format!("{}\n{}", header, hex_dump(header.payload(frame))),
+        None => format!("{} short frame\n{}", direction, hex_dump(frame)),
+    }
+}
+
+fn command_name(command: i32) -> String {
+    match command {
+        0 => "get".to_string(),
+        1 => "put".to_string(),
+        3 => "remove".to_string(),
+        other => format!("command {}", other),
+    }
+}
+
+// Requests start with [operation length, 0x0, PbPFtpOperation...], maybe followed by the file
+pub fn describe_request(request: &[u8]) -> Option<String> {
+    let length = usize::from(*request.first()?);
+    let operation = request.get(2..2 + length)?;
+    let operation = polar::protocol::PbPFtpOperation::decode(operation).ok()?;
+
+    let mut result = format!(
+        "PbPFtpOperation {} {}",
+        command_name(operation.command),
+        operation.path
+    );
+
+    let data = request.len().saturating_sub(2 + length);
+    if data > 0 {
+        result += &format!(" + {} bytes", data);
+    }
+
+    Some(result)
+}
@@ -0,0 +1,402 @@
+// The sport profiles on the watch, one directory with a PROFILE.BPB each. Favourites can only be
+// started under a sport that has a profile. Like the physical data, changes only replace the
+// fields that were changed
+use super::wire::{message_field, replace_fields, replace_nested, system_date_time, varint_field};
+use super::{Message, PolarError};
+use polar::types::PbSportIdentifier;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+pub const SPORT_PROFILES_PATH: &str = "/U/0/SPORTPRO/";
+pub const PROFILE_NAME: &str = "PROFILE.BPB";
+
+// Field numbers of PbSportProfile
+const IDENTIFIER: u32 = 1;
+const SPORT_IDENTIFIER: u32 = 2;
+const SETTINGS: u32 = 3;
+const POSITION: u32 = 7;
+const LAST_MODIFIED: u32 = 100;
+
+// Field numbers of PbSportProfileSettings and PbZones, down to the heart rate zones
+const SETTINGS_ZONE_LIMITS: u32 = 6;
+const ZONES_HEART_RATE: u32 = 1;
+
+// PbHeartRateZoneSettingSource, zones the user set instead of the ones from the max heart rate
+const ZONE_SOURCE_FREE: i32 = 2;
+
+// The watch has 5 heart rate zones
+pub const HEART_RATE_ZONES: usize = 5;
+
+// The parts of Polar's sport_profile.proto that we need, everything else is kept as it is
+mod proto {
+    use polar::types::{PbDuration, PbSportIdentifier, PbSystemDateTime};
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbAutoLapSettings {
+        // 1 off, 2 distance, 3 duration, 4 location
+        #[prost(int32, optional, tag = "1")]
+        pub automatic_lap: Option<i32>,
+        #[prost(float, optional, tag = "2")]
+        pub automatic_lap_distance: Option<f32>,
+        #[prost(message, optional, tag = "3")]
+        pub automatic_lap_duration: Option<PbDuration>,
+    }
+
+    // Limits in bpm
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbHeartRateZone {
+        #[prost(uint32, optional, tag = "1")]
+        pub lower_limit: Option<u32>,
+        #[prost(uint32, optional, tag = "2")]
+        pub higher_limit: Option<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbHeartRateZoneSettings {
+        // 0 default, 1 threshold, 2 free
+        #[prost(int32, optional, tag = "1")]
+        pub heart_rate_setting_source: Option<i32>,
+        #[prost(message, repeated, tag = "2")]
+        pub user_heart_rate_zone: Vec<PbHeartRateZone>,
+    }
+
+    // Speed and power zones are left out
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbZones {
+        #[prost(message, optional, tag = "1")]
+        pub heart_rate_setting: Option<PbHeartRateZoneSettings>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbSportProfileSettings {
+        // 1 bpm, 2 % of heart rate reserve, 3 % of max heart rate
+        #[prost(int32, optional, tag = "4")]
+        pub heart_rate_view: Option<i32>,
+        #[prost(message, optional, tag = "6")]
+        pub zone_limits: Option<PbZones>,
+        #[prost(message, optional, tag = "10")]
+        pub autolap_settings: Option<PbAutoLapSettings>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbTrainingDisplay {
+        #[prost(uint32, repeated, tag = "1")]
+        pub item: Vec<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PbSportProfile {
+        #[prost(uint64, optional, tag = "1")]
+        pub identifier: Option<u64>,
+        #[prost(message, optional, tag = "2")]
+        pub sport_identifier: Option<PbSportIdentifier>,
+        #[prost(message, optional, tag = "3")]
+        pub settings: Option<PbSportProfileSettings>,
+        #[prost(message, repeated, tag = "6")]
+        pub training_displays: Vec<PbTrainingDisplay>,
+        #[prost(uint32, optional, tag = "7")]
+        pub sport_favourite_position: Option<u32>,
+        #[prost(message, optional, tag = "100")]
+        pub last_modified: Option<PbSystemDateTime>,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoLap {
+    Off,
+    // Meters
+    Distance(f32),
+    Duration(Duration),
+    // At the place where the exercise started
+    Location,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeartRateView {
+    Bpm,
+    PercentOfReserve,
+    PercentOfMax,
+}
+
+// Lower and upper limit of each heart rate zone in bpm, from zone 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeartRateZones(pub Vec<(u32, u32)>);
+
+// Like 95-114,114-133,133-152,152-171,171-190
+impl std::str::FromStr for HeartRateZones {
+    type Err = PolarError;
+
+    fn from_str(value: &str) -> Result<HeartRateZones, PolarError> {
+        let invalid = || {
+            PolarError::new(format!(
+                "Invalid heart rate zones '{}', use {} ranges in bpm like 95-114,114-133,...",
+                value, HEART_RATE_ZONES
+            ))
+        };
+
+        let limits = value
+            .split(',')
+            .map(|zone| {
+                let (lower, upper) = zone.split_once('-').ok_or_else(invalid)?;
+                let limit = |text: &str| text.trim().parse::<u32>().map_err(|_| invalid());
+                Ok((limit(lower)?, limit(upper)?))
+            })
+            .collect::<Result<Vec<_>, PolarError>>()?;
+
+        let zones = HeartRateZones(limits);
+        zones.validate()?;
+        Ok(zones)
+    }
+}
+
+impl fmt::Display for HeartRateZones {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let zones: Vec<String> = self
+            .0
+            .iter()
+            .map(|(lower, upper)| format!("{}-{}", lower, upper))
+            .collect();
+        write!(f, "{}", zones.join(","))
+    }
+}
+
+impl HeartRateZones {
+    // Every zone above 0 bpm, ending higher than it starts and not below the one before
+    pub fn validate(&self) -> Result<(), PolarError> {
+        if self.0.len() != HEART_RATE_ZONES {
+            return Err(PolarError::new(format!(
+                "Expected {} heart rate zones, got {}",
+                HEART_RATE_ZONES,
+                self.0.len()
+            )));
+        }
+
+        let mut previous = 0;
+        for (index, (lower, upper)) in self.0.iter().enumerate() {
+            if *lower == 0 || lower >= upper || *lower < previous {
+                return Err(PolarError::new(format!(
+                    "Heart rate zone {} goes from {} to {} bpm, it must be above 0, end higher than it starts and not overlap zone {}",
+                    index + 1,
+                    lower,
+                    upper,
+                    index
+                )));
+            }
+            previous = *upper;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SportProfile {
+    // Directory on the watch, ending with /
+    pub path: String,
+    pub identifier: Option<u64>,
+    pub sport_id: Option<u64>,
+    // Place in the watch's list of sports, from 0
+    pub position: Option<u32>,
+    // The items shown on each training view, as Polar's display item numbers
+    pub training_views: Vec<Vec<u32>>,
+    pub auto_lap: Option<AutoLap>,
+    pub heart_rate_view: Option<HeartRateView>,
+    pub heart_rate_zones: Option<HeartRateZones>,
+}
+
+impl fmt::Display for SportProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let or_unknown = |value: Option<String>| value.unwrap_or_else(|| "?".to_string());
+
+        write!(
+            f,
+            "{:>3}  sport {:<5} {} training views, auto lap {}, heart rate as {}",
+            or_unknown(self.position.map(|position| (position + 1).to_string())),
+            or_unknown(self.sport_id.map(|id| id.to_string())),
+            self.training_views.len(),
+            match self.auto_lap {
+                None | Some(AutoLap::Off) => "off".to_string(),
+                Some(AutoLap::Distance(meters)) => format!("every {} m", meters),
+                Some(AutoLap::Duration(duration)) =>
+                    format!("every {}", crate::human_duration(duration)),
+                Some(AutoLap::Location) => "at the start location".to_string(),
+            },
+            match self.heart_rate_view {
+                None | Some(HeartRateView::Bpm) => "bpm",
+                Some(HeartRateView::PercentOfReserve) => "% of reserve",
+                Some(HeartRateView::PercentOfMax) => "% of max",
+            }
+        )?;
+
+        match &self.heart_rate_zones {
+            Some(zones) => write!(f, ", zones {} bpm", zones),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SportProfile {
+    pub fn decode(path: &str, data: &[u8]) -> Result<SportProfile, PolarError> {
+        let profile = proto::PbSportProfile::decode(data).map_err(|error| {
+            PolarError::new(format!("{} is not a sport profile: {:?}", path, error))
+        })?;
+        let settings = profile.settings.unwrap_or_default();
+
+        let auto_lap = settings
+            .autolap_settings
+            .map(|lap| match lap.automatic_lap {
+                Some(2) => AutoLap::Distance(lap.automatic_lap_distance.unwrap_or(0.0)),
+                Some(3) => AutoLap::Duration(
+                    lap.automatic_lap_duration
+                        .as_ref()
+                        .map(crate::formats::duration_from)
+                        .unwrap_or_default(),
+                ),
+                Some(4) => AutoLap::Location,
+                _ => AutoLap::Off,
+            });
+
+        let heart_rate_view = settings.heart_rate_view.and_then(|view| match view {
+            1 => Some(HeartRateView::Bpm),
+            2 => Some(HeartRateView::PercentOfReserve),
+            3 => Some(HeartRateView::PercentOfMax),
+            _ => None,
+        });
+
+        let heart_rate_zones = settings
+            .zone_limits
+            .and_then(|zones| zones.heart_rate_setting)
+            .map(|setting| {
+                HeartRateZones(
+                    setting
+                        .user_heart_rate_zone
+                        .iter()
+                        .map(|zone| {
+                            (
+                                zone.lower_limit.unwrap_or(0),
+                                zone.higher_limit.unwrap_or(0),
+                            )
+                        })
+                        .collect(),
+                )
+            })
+            .filter(|zones| !zones.0.is_empty());
+
+        Ok(SportProfile {
+            path: path.to_string(),
+            identifier: profile.identifier,
+            sport_id: profile.sport_identifier.map(|sport| sport.value),
+            position: profile.sport_favourite_position,
+            training_views: profile
+                .training_displays
+                .into_iter()
+                .map(|display| display.item)
+                .collect(),
+            auto_lap,
+            heart_rate_view,
+            heart_rate_zones,
+        })
+    }
+}
+
+// file with its place in the list of sports changed
+pub fn with_position(file: &[u8], position: u32, now: SystemTime) -> Result<Vec<u8>, PolarError> {
+    replace_fields(
+        file,
+        vec![
+            (POSITION, varint_field(POSITION, position.into())),
+            (
+                LAST_MODIFIED,
+                message_field(LAST_MODIFIED, system_date_time(now))?,
+            ),
+        ],
+    )
+}
+
+// file with the heart rate zones replaced by zones, set by the user. The speed and power zones and
+// the other settings stay as they are
+pub fn with_heart_rate_zones(
+    file: &[u8],
+    zones: &HeartRateZones,
+    now: SystemTime,
+) -> Result<Vec<u8>, PolarError> {
+    zones.validate()?;
+
+    let setting = proto::PbHeartRateZoneSettings {
+        heart_rate_setting_source: Some(ZONE_SOURCE_FREE),
+        user_heart_rate_zone: zones
+            .0
+            .iter()
+            .map(|(lower, upper)| proto::PbHeartRateZone {
+                lower_limit: Some(*lower),
+                higher_limit: Some(*upper),
+            })
+            .collect(),
+    };
+
+    let file = replace_nested(
+        file,
+        &[SETTINGS, SETTINGS_ZONE_LIMITS, ZONES_HEART_RATE],
+        message_field(ZONES_HEART_RATE, setting)?,
+    )?;
+    replace_fields(
+        &file,
+        vec![(
+            LAST_MODIFIED,
+            message_field(LAST_MODIFIED, system_date_time(now))?,
+        )],
+    )
+}
+
+// A copy of file, the profile of another sport, for a new sport. The settings stay the same
+pub fn copy_for_sport(
+    file: &[u8],
+    identifier: u64,
+    sport_id: u64,
+    position: u32,
+    now: SystemTime,
+) -> Result<Vec<u8>, PolarError> {
+    replace_fields(
+        file,
+        vec![
+            (IDENTIFIER, varint_field(IDENTIFIER, identifier)),
+            (
+                SPORT_IDENTIFIER,
+                message_field(SPORT_IDENTIFIER, PbSportIdentifier { value: sport_id })?,
+            ),
+            (POSITION, varint_field(POSITION, position.into())),
+            (
+                LAST_MODIFIED,
+                message_field(LAST_MODIFIED, system_date_time(now))?,
+            ),
+        ],
+    )
+}
+
+// Profiles in the order the watch lists them
+pub fn sort_profiles(profiles: &mut [SportProfile]) {
+    profiles.sort_by(|a, b| {
+        (a.position.unwrap_or(u32::MAX), &a.path).cmp(&(b.position.unwrap_or(u32::MAX), &b.path))
+    });
+}
+
+// Index of the profile of sport
+pub fn find_profile(profiles: &[SportProfile], sport_id: u64) -> Result<usize, PolarError> {
+    profiles
+        .iter()
+        .position(|profile| profile.sport_id == Some(sport_id))
+        .ok_or_else(|| PolarError::new(format!("There is no profile for sport {}", sport_id)))
+}
+
+// Directory for a new profile, the first number that isn't used yet
+pub fn free_profile_path(profiles: &[SportProfile]) -> String {
+    let used: Vec<&str> = profiles
+        .iter()
+        .map(|profile| profile.path.as_str())
+        .collect();
+
+    (0..)
+        .map(|number| format!("{}{}/", SPORT_PROFILES_PATH, number))
+        .find(|path| !used.contains(&path.as_str()))
+        .unwrap_or_default()
+}
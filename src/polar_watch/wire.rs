@@ -0,0 +1,184 @@
+// Changes to single fields of the settings files on the watch. We only know some of the fields in
+// them, so instead of decoding and encoding the whole file, the top level fields are split without
+// decoding and only the changed ones are replaced
+use super::{encode, PolarError};
+use polar::types::{PbDate, PbSystemDateTime, PbTime};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_64_BIT: u32 = 1;
+const WIRE_LENGTH: u32 = 2;
+const WIRE_32_BIT: u32 = 5;
+
+fn read_varint(data: &[u8], position: &mut usize) -> Result<u64, PolarError> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let byte = *data
+            .get(*position)
+            .ok_or_else(|| PolarError::new("File ends in the middle of a field"))?;
+        *position += 1;
+
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(PolarError::new("File has a varint that is too long"))
+}
+
+fn write_varint(mut value: u64, data: &mut Vec<u8>) {
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+// Splits a message into its top level fields, each with its key, without decoding them
+pub fn split_fields(data: &[u8]) -> Result<Vec<(u32, &[u8])>, PolarError> {
+    let mut fields = vec![];
+    let mut position = 0;
+
+    while position < data.len() {
+        let start = position;
+        let key = read_varint(data, &mut position)?;
+        let skip = match (key & 7) as u32 {
+            WIRE_VARINT => {
+                read_varint(data, &mut position)?;
+                0
+            }
+            WIRE_64_BIT => 8,
+            WIRE_LENGTH => read_varint(data, &mut position)? as usize,
+            WIRE_32_BIT => 4,
+            wire_type => {
+                return Err(PolarError::new(format!(
+                    "File has a field of wire type {}, which can't be kept",
+                    wire_type
+                )))
+            }
+        };
+
+        position = position
+            .checked_add(skip)
+            .filter(|end| *end <= data.len())
+            .ok_or_else(|| PolarError::new("File ends in the middle of a field"))?;
+        fields.push(((key >> 3) as u32, &data[start..position]));
+    }
+
+    Ok(fields)
+}
+
+pub fn varint_field(tag: u32, value: u64) -> Vec<u8> {
+    let mut field = vec![];
+    write_varint(u64::from(tag << 3 | WIRE_VARINT), &mut field);
+    write_varint(value, &mut field);
+
+    field
+}
+
+pub fn message_field<M: prost::Message>(tag: u32, message: M) -> Result<Vec<u8>, PolarError> {
+    let body = encode(message)
+        .map_err(|error| PolarError::new(format!("Failed to encode field {}: {:?}", tag, error)))?;
+
+    Ok(length_field(tag, &body))
+}
+
+fn length_field(tag: u32, body: &[u8]) -> Vec<u8> {
+    let mut field = vec![];
+    write_varint(u64::from(tag << 3 | WIRE_LENGTH), &mut field);
+    write_varint(body.len() as u64, &mut field);
+    field.extend_from_slice(body);
+
+    field
+}
+
+// The contents of a field from split_fields, when it is a nested message
+fn message_body(tag: u32, field: &[u8]) -> Result<&[u8], PolarError> {
+    let mut position = 0;
+    let key = read_varint(field, &mut position)?;
+    if (key & 7) as u32 != WIRE_LENGTH {
+        return Err(PolarError::new(format!("Field {} is not a message", tag)));
+    }
+    read_varint(field, &mut position)?;
+
+    Ok(&field[position..])
+}
+
+// The fields of file with the ones in changes, by tag, replaced. Changed fields take the place of
+// the old ones, new ones go at the end
+pub fn replace_fields(
+    file: &[u8],
+    mut changes: Vec<(u32, Vec<u8>)>,
+) -> Result<Vec<u8>, PolarError> {
+    let mut result = vec![];
+
+    for (tag, field) in split_fields(file)? {
+        match changes.iter_mut().find(|(changed, _)| *changed == tag) {
+            Some((_, new)) => result.append(new),
+            None => result.extend_from_slice(field),
+        }
+    }
+    for (_, new) in changes {
+        result.extend(new);
+    }
+
+    Ok(result)
+}
+
+// Like replace_fields for a field inside nested messages, path has the tag of each message down to
+// the one of field. Messages missing on the way are added, the other fields in them are kept
+pub fn replace_nested(file: &[u8], path: &[u32], field: Vec<u8>) -> Result<Vec<u8>, PolarError> {
+    match path {
+        [] => Err(PolarError::new("No field to replace")),
+        [tag] => replace_fields(file, vec![(*tag, field)]),
+        [tag, inner @ ..] => {
+            let body = match split_fields(file)?
+                .into_iter()
+                .find(|(found, _)| found == tag)
+            {
+                Some((_, old)) => message_body(*tag, old)?,
+                None => &[],
+            };
+            let body = replace_nested(body, inner, field)?;
+
+            replace_fields(file, vec![(*tag, length_field(*tag, &body))])
+        }
+    }
+}
+
+// Days since 1970-01-01 to a civil date, from Howard Hinnant's date algorithms
+fn civil_date(days: i64) -> (u32, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year as u32, month as u32, day as u32)
+}
+
+// UTC, as the watch stores modification times
+pub fn system_date_time(time: SystemTime) -> PbSystemDateTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_date((seconds / 86400) as i64);
+    let seconds = seconds % 86400;
+
+    PbSystemDateTime {
+        date: PbDate { year, month, day },
+        time: PbTime {
+            hour: (seconds / 3600) as u32,
+            minute: (seconds % 3600 / 60) as u32,
+            seconds: (seconds % 60) as u32,
+            millis: Some(since_epoch.subsec_millis()),
+        },
+        trusted: true,
+    }
+}
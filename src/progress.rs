@@ -0,0 +1,120 @@
+// Progress line for uploads, showing the current file, the overall progress, speed and ETA
+use crate::human_duration;
+use crate::polar_watch::human_size;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 20;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct TransferProgress {
+    started: Instant,
+    total: usize,
+    finished_bytes: usize,
+    last_draw: Option<Instant>,
+}
+
+fn bar(done: usize, total: usize) -> String {
+    let filled = (done * BAR_WIDTH).checked_div(total).unwrap_or(BAR_WIDTH);
+
+    format!("[{}{}]", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled))
+}
+
+fn percent(done: usize, total: usize) -> usize {
+    (done * 100).checked_div(total).unwrap_or(100)
+}
+
+impl TransferProgress {
+    // total is the size of all the files that will be sent
+    pub fn new(total: usize) -> TransferProgress {
+        TransferProgress {
+            started: Instant::now(),
+            total,
+            finished_bytes: 0,
+            last_draw: None,
+        }
+    }
+
+    pub fn line(&self, done: usize, file_total: usize) -> String {
+        let overall = self.finished_bytes + done;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 {
+            overall as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let eta = if speed > 0.0 {
+            let seconds = (self.total.saturating_sub(overall)) as f64 / speed;
+            human_duration(Duration::from_secs(seconds.ceil() as u64))
+        } else {
+            "?".to_string()
+        };
+
+        format!(
+            "{} {:>3}%   total {:>3}%   {}/s   ETA {}",
+            bar(done, file_total),
+            percent(done, file_total),
+            percent(overall, self.total),
+            human_size(speed as u64),
+            eta
+        )
+    }
+
+    // Redraws the progress line, every packet would be too often to read
+    pub fn update(&mut self, done: usize, file_total: usize) {
+        let now = Instant::now();
+        let is_due = match self.last_draw {
+            Some(last_draw) => now.duration_since(last_draw) >= REDRAW_INTERVAL,
+            None => true,
+        };
+
+        if is_due || done == file_total {
+            let mut stdout = std::io::stdout();
+            let _ = write!(stdout, "\r{:<80}", self.line(done, file_total));
+            let _ = stdout.flush();
+
+            self.last_draw = Some(now);
+        }
+    }
+
+    pub fn finish_file(&mut self, file_total: usize) {
+        self.update(file_total, file_total);
+        println!();
+
+        self.finished_bytes += file_total;
+        self.last_draw = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bar_and_percent_handle_empty_files() {
+        assert_eq!(bar(0, 100), format!("[{}]", " ".repeat(20)));
+        assert_eq!(
+            bar(50, 100),
+            format!("[{}{}]", "#".repeat(10), " ".repeat(10))
+        );
+        assert_eq!(bar(0, 0), format!("[{}]", "#".repeat(20)));
+        assert_eq!(percent(1, 3), 33);
+        assert_eq!(percent(3, 3), 100);
+        assert_eq!(percent(0, 0), 100);
+    }
+
+    #[test]
+    fn line_shows_file_and_overall_progress() {
+        let mut progress = TransferProgress::new(2048);
+        assert!(progress.line(0, 1024).ends_with("ETA ?"));
+
+        progress.finished_bytes = 1024;
+        progress.started = Instant::now().checked_sub(Duration::from_secs(2)).unwrap();
+
+        let line = progress.line(512, 1024);
+        assert!(line.starts_with(&format!("{}  50%   total  75%", bar(512, 1024))));
+        assert!(line.contains(" B/s   ETA "));
+        assert!(!line.ends_with("ETA ?"));
+    }
+}
@@ -0,0 +1,352 @@
+// Interactive shell to browse and change the files on the watch by hand
+use crate::exercise::{gunzip, Recording, LAPS_NAME, SAMPLES_NAME};
+use crate::polar_watch::{polar, Message, PhysicalData, PolarError, PolarWatch, SportProfile};
+use crate::polar_watch::{PHYSICAL_DATA_PATH, PROFILE_NAME};
+use crate::{describe_favourite, hex_dump, human_duration};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const COMMANDS: [&str; 14] = [
+    "ls", "cd", "pwd", "get", "put", "rm", "mkdir", "cat", "hexdump", "du", "trace", "help",
+    "exit", "quit",
+];
+
+const HELP: &str = "Commands:
+    ls [-l] [path]         list a directory, with sizes and dates when using -l
+    cd <path>              change the current directory
+    pwd                    print the current directory
+    get <path> [local]     download a file
+    put <local> [path]     upload a file
+    rm [-r] <path>         delete a file, or a directory and everything in it
+    mkdir <path>           create a directory
+    cat <path>             print a file, decoding it if its format is known
+    hexdump <path>         print a file as hex
+    du [path]              show how much space a directory uses
+    trace <on|off>         log every usb frame, decoded, to the log file
+    exit                   leave the shell";
+
+struct ShellState {
+    watch: RefCell<PolarWatch>,
+    cwd: RefCell<String>,
+}
+
+// Turns a path typed by the user into an absolute watch path, handling "." and ".."
+fn resolve(cwd: &str, path: &str) -> String {
+    let full = if path.starts_with("/") {
+        path.to_string()
+    } else {
+        cwd.to_string() + path
+    };
+
+    let mut parts: Vec<&str> = vec![];
+    for part in full.split("/") {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    let mut result = "/".to_string() + &parts.join("/");
+    if full.ends_with("/") && result != "/" {
+        result += "/";
+    }
+
+    result
+}
+
+impl ShellState {
+    fn resolve(&self, path: &str) -> String {
+        resolve(&self.cwd.borrow(), path)
+    }
+
+    fn resolve_dir(&self, path: &str) -> String {
+        let mut path = self.resolve(path);
+        if !path.ends_with("/") {
+            path += "/";
+        }
+
+        path
+    }
+}
+
+struct ShellHelper {
+    state: Rc<ShellState>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(' ').map(|index| index + 1).unwrap_or(0);
+        let word = &line[start..pos];
+
+        if start == 0 {
+            let commands = COMMANDS.iter().filter(|command| command.starts_with(word));
+            return Ok((start, commands.map(|command| command.to_string()).collect()));
+        }
+
+        // Complete the last path component using the listing of its parent directory
+        let (parent, partial) = match word.rfind('/') {
+            Some(index) => (&word[..index + 1], &word[index + 1..]),
+            None => ("", word),
+        };
+
+        let directory = self.state.resolve_dir(parent);
+        let entries = match self.state.watch.borrow_mut().dir(directory) {
+            Ok(entries) => entries,
+            Err(_) => return Ok((start, vec![])),
+        };
+
+        let candidates = entries
+            .into_iter()
+            .filter(|entry| entry.name.starts_with(partial))
+            .map(|entry| parent.to_string() + &entry.name)
+            .collect();
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+// Returns a readable version of the file if we know how to decode it
+pub fn decode_known_file(path: &str, data: &[u8]) -> Option<String> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+
+    match name {
+        "TST.BPB" => {
+            let session = polar::data::PbTrainingSessionTarget::decode(data).ok()?;
+            let description = describe_favourite(data.to_vec()).unwrap_or_default();
+            Some(format!("{}\n\n{:#?}", description, session))
+        }
+        _ if path == PHYSICAL_DATA_PATH => Some(PhysicalData::decode(data).ok()?.to_text()),
+        PROFILE_NAME => Some(SportProfile::decode(path, data).ok()?.to_string()),
+        LAPS_NAME => {
+            let laps = Recording::decode_laps(data).ok()?;
+            let lines: Vec<String> = laps
+                .iter()
+                .enumerate()
+                .map(|(index, end)| format!("Lap {} ends at {}", index + 1, human_duration(*end)))
+                .collect();
+
+            Some(lines.join("\n"))
+        }
+        SAMPLES_NAME => {
+            let mut recording = Recording::default();
+            recording.decode_samples(&gunzip(data).ok()?).ok()?;
+
+            Some(format!(
+                "A sample every {}: {} heart rate, {} speed and {} power samples",
+                human_duration(recording.interval),
+                recording.heart_rate.len(),
+                recording.speed.len(),
+                recording.power.len()
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn local_name(path: &str) -> &str {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(path)
+}
+
+fn execute(state: &ShellState, command: &str, args: &[&str]) -> Result<bool, PolarError> {
+    let argument = |index: usize| -> Result<&str, PolarError> {
+        match args.get(index) {
+            Some(arg) => Ok(*arg),
+            None => Err(PolarError::new(format!(
+                "{}: missing argument, type 'help' for usage",
+                command
+            ))),
+        }
+    };
+
+    let mut watch = state.watch.borrow_mut();
+
+    match command {
+        "ls" => {
+            let long = args.first() == Some(&"-l");
+            let path = match args.iter().find(|arg| **arg != "-l") {
+                Some(path) => state.resolve_dir(path),
+                None => state.resolve_dir(""),
+            };
+
+            for entry in watch.dir(path)? {
+                if long {
+                    let modified = match entry.modified {
+                        Some(modified) => modified.to_string(),
+                        None => "-".to_string(),
+                    };
+                    println!("{:>10}  {:19}  {}", entry.size, modified, entry.name);
+                } else {
+                    println!("{}", entry.name);
+                }
+            }
+        }
+        "cd" => {
+            let path = state.resolve_dir(argument(0)?);
+
+            // Make sure the directory exists before moving into it
+            watch.dir(path.clone())?;
+            *state.cwd.borrow_mut() = path;
+        }
+        "pwd" => println!("{}", state.cwd.borrow()),
+        "get" => {
+            let path = state.resolve(argument(0)?);
+            let local = args.get(1).copied().unwrap_or(local_name(&path));
+            let data = watch.get_file(path.clone())?;
+
+            std::fs::write(local, &data).map_err(|error| {
+                PolarError::new(format!("Failed to write '{}'\n\t{:?}", local, error))
+            })?;
+            println!("{} -> {} ({} bytes)", path, local, data.len());
+        }
+        "put" => {
+            let local = argument(0)?;
+            let path = match args.get(1) {
+                Some(path) => state.resolve(path),
+                None => state.resolve(local_name(local)),
+            };
+
+            let data = std::fs::read(local).map_err(|error| {
+                PolarError::new(format!("Failed to read '{}'\n\t{:?}", local, error))
+            })?;
+            watch.send_file(path.clone(), &data)?;
+            println!("{} -> {} ({} bytes)", local, path, data.len());
+        }
+        "rm" => {
+            if args.first() == Some(&"-r") {
+                watch.delete_directory(state.resolve_dir(argument(1)?))?;
+            } else {
+                watch.delete_file(state.resolve(argument(0)?))?;
+            }
+        }
+        "mkdir" => watch.mkdir(state.resolve_dir(argument(0)?))?,
+        "cat" => {
+            let path = state.resolve(argument(0)?);
+            let data = watch.get_file(path.clone())?;
+
+            match decode_known_file(&path, &data) {
+                Some(description) => println!("{}", description),
+                None => match std::str::from_utf8(&data) {
+                    Ok(text) => println!("{}", text),
+                    Err(_) => println!(
+                        "{} bytes of binary data, use hexdump to see them",
+                        data.len()
+                    ),
+                },
+            }
+        }
+        "hexdump" => {
+            let data = watch.get_file(state.resolve(argument(0)?))?;
+            print!("{}", hex_dump(&data));
+        }
+        "du" => {
+            let path = state.resolve_dir(args.first().unwrap_or(&""));
+            println!("{}: {}", path, watch.disk_usage(path.clone())?);
+        }
+        "trace" => match argument(0)? {
+            "on" => watch.set_trace(true),
+            "off" => watch.set_trace(false),
+            other => println!("trace: expected on or off, got '{}'", other),
+        },
+        "help" => println!("{}", HELP),
+        "exit" | "quit" => return Ok(false),
+        _ => println!("Unknown command '{}', type 'help' for a list", command),
+    }
+
+    Ok(true)
+}
+
+pub fn run_shell(watch: PolarWatch) -> Result<(), PolarError> {
+    let state = Rc::new(ShellState {
+        watch: RefCell::new(watch),
+        cwd: RefCell::new("/".to_string()),
+    });
+
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper {
+        state: state.clone(),
+    }));
+
+    println!("Connected to watch, type 'help' for a list of commands");
+
+    loop {
+        let prompt = format!("{}> ", state.cwd.borrow());
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => return Ok(()),
+            Err(error) => return Err(PolarError::new(format!("{:?}", error))),
+        };
+
+        editor.add_history_entry(line.as_str());
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => continue,
+        };
+
+        match execute(&state, command, args) {
+            Ok(true) => (),
+            Ok(false) => return Ok(()),
+            Err(PolarError::LibusbError { error }) => println!("USB error: {:?}", error),
+            Err(PolarError::PolarError { error }) => println!("{}", error),
+            Err(PolarError::DeviceError { error, .. }) => println!("{}", error),
+            Err(PolarError::MismatchError { error }) => println!("{}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_resolve_against_the_current_directory() {
+        assert_eq!(resolve("/U/0/", "FAV"), "/U/0/FAV");
+        assert_eq!(resolve("/U/0/", "FAV/"), "/U/0/FAV/");
+        assert_eq!(resolve("/U/0/FAV/", ".."), "/U/0");
+        assert_eq!(
+            resolve("/U/0/FAV/", "../S/./PHYSDATA.BPB"),
+            "/U/0/S/PHYSDATA.BPB"
+        );
+        assert_eq!(resolve("/U/0/", "/SYS/"), "/SYS/");
+        assert_eq!(resolve("/", "../../"), "/");
+        assert_eq!(resolve("/U/0/", ""), "/U/0/");
+        assert_eq!(local_name("/U/0/FAV/00/"), "00");
+        assert_eq!(local_name("/U/0/FAV/00/TST.BPB"), "TST.BPB");
+    }
+
+    #[test]
+    fn unknown_files_are_not_decoded() {
+        assert_eq!(decode_known_file("/U/0/README.TXT", b"hello"), None);
+        assert_eq!(decode_known_file("/U/0/LAPS.BPB", &[0xff]), None);
+    }
+}
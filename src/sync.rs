@@ -0,0 +1,198 @@
+// Downloads the exercises recorded on the watch into a local archive, skipping the ones that were
+// synced before. Exercises live in /U/0/<date>/E/<time>/ on the watch and end up in
+// <archive>/<serial>/<date>/<time>/ locally
+use crate::backup::io_error;
+use crate::polar_watch::{DirEntry, PolarError, PolarWatch, NO_SUCH_FILE};
+use log::info;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+pub const EXERCISES_ROOT: &str = "/U/0/";
+pub const STATE_NAME: &str = "SYNCED";
+
+// Used instead of the serial number for watches that don't report one
+const UNKNOWN_SERIAL: &str = "unknown";
+
+// Ids of the exercises already in the archive, one per line like "<serial>/<date>/<time>"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncState {
+    pub synced: BTreeSet<String>,
+}
+
+impl SyncState {
+    pub fn parse(text: &str) -> SyncState {
+        SyncState {
+            synced: text
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        self.synced.iter().map(|id| id.clone() + "\n").collect()
+    }
+
+    // A missing file means nothing was synced yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SyncState, PolarError> {
+        match std::fs::read_to_string(path.as_ref()) {
+            Ok(text) => Ok(SyncState::parse(&text)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(SyncState::default()),
+            Err(error) => Err(io_error("read", path, error)),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PolarError> {
+        std::fs::write(path.as_ref(), self.to_text())
+            .map_err(|error| io_error("write", path, error))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exercise {
+    // "<date>/<time>", unique on one watch
+    pub id: String,
+    // Directory on the watch, ending with /
+    pub path: String,
+}
+
+fn is_date(name: &str) -> bool {
+    let name = name.trim_end_matches('/');
+    name.len() == 8 && name.chars().all(|c| c.is_ascii_digit())
+}
+
+// Lists the directories inside path, treating a missing directory as an empty one
+fn list_directories(watch: &mut PolarWatch, path: &str) -> Result<Vec<DirEntry>, PolarError> {
+    match watch.dir(path) {
+        Ok(entries) => Ok(entries
+            .into_iter()
+            .filter(|entry| entry.is_directory)
+            .collect()),
+        Err(error) if error.device_code() == Some(NO_SUCH_FILE) => {
+            info!("Skipping {}, it doesn't exist", path);
+            Ok(vec![])
+        }
+        Err(error) => Err(error),
+    }
+}
+
+pub fn find_exercises(watch: &mut PolarWatch) -> Result<Vec<Exercise>, PolarError> {
+    let mut exercises = vec![];
+
+    for day in list_directories(watch, EXERCISES_ROOT)? {
+        if !is_date(&day.name) {
+            continue;
+        }
+
+        for exercise in list_directories(watch, &format!("{}E/", day.path))? {
+            exercises.push(Exercise {
+                id: format!(
+                    "{}/{}",
+                    day.name.trim_end_matches('/'),
+                    exercise.name.trim_end_matches('/')
+                ),
+                path: exercise.path,
+            });
+        }
+    }
+
+    Ok(exercises)
+}
+
+// Keeps only characters that are safe in a directory name
+fn archive_name(serial: Option<String>) -> String {
+    let name: String = serial
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+
+    if name.is_empty() {
+        UNKNOWN_SERIAL.to_string()
+    } else {
+        name
+    }
+}
+
+// Downloads into a temporary directory first, so that an unplugged watch doesn't leave half an
+// exercise that looks complete
+fn download_exercise(
+    watch: &mut PolarWatch,
+    exercise: &Exercise,
+    destination: &Path,
+) -> Result<(), PolarError> {
+    let partial = destination.with_extension("partial");
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial).map_err(|error| io_error("remove", &partial, error))?;
+    }
+
+    let entries = watch
+        .walk(exercise.path.clone())
+        .collect::<Result<Vec<DirEntry>, PolarError>>()?;
+
+    std::fs::create_dir_all(&partial).map_err(|error| io_error("create", &partial, error))?;
+
+    for entry in entries {
+        let mut local = partial.clone();
+        for part in entry.path[exercise.path.len()..]
+            .split('/')
+            .filter(|part| !part.is_empty())
+        {
+            local.push(part);
+        }
+
+        if entry.is_directory {
+            std::fs::create_dir_all(&local).map_err(|error| io_error("create", &local, error))?;
+        } else {
+            let data = watch.get_file(entry.path.clone())?;
+            std::fs::write(&local, data).map_err(|error| io_error("write", &local, error))?;
+        }
+    }
+
+    // Left over from a sync whose state file was lost
+    if destination.exists() {
+        std::fs::remove_dir_all(destination)
+            .map_err(|error| io_error("remove", destination, error))?;
+    }
+
+    std::fs::rename(&partial, destination).map_err(|error| io_error("rename", &partial, error))
+}
+
+// Downloads every exercise on the watch that isn't in the state file yet and returns their ids.
+// The state file is updated after each exercise, so an interrupted sync continues where it stopped
+pub fn sync_exercises<P: AsRef<Path>>(
+    watch: &mut PolarWatch,
+    archive: P,
+) -> Result<Vec<String>, PolarError> {
+    let archive = archive.as_ref();
+    let state_path = archive.join(STATE_NAME);
+    let mut state = SyncState::load(&state_path)?;
+
+    let serial = archive_name(watch.serial_number());
+    let mut synced = vec![];
+
+    for exercise in find_exercises(watch)? {
+        let id = format!("{}/{}", serial, exercise.id);
+        if state.synced.contains(&id) {
+            continue;
+        }
+
+        let destination: PathBuf = id
+            .split('/')
+            .fold(archive.to_path_buf(), |path, part| path.join(part));
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent).map_err(|error| io_error("create", parent, error))?;
+        }
+
+        info!("Syncing {} into {}", exercise.path, destination.display());
+        download_exercise(watch, &exercise, &destination)?;
+
+        state.synced.insert(id.clone());
+        state.save(&state_path)?;
+        synced.push(id);
+    }
+
+    Ok(synced)
+}
@@ -0,0 +1,357 @@
+// Sessions generated from a few parameters instead of written phase by phase. Templates are written
+// like this, durations as 1h, 3m, 90s or 1m30s and rest as a duration or a percentage of the work
+// before it:
+//
+//     6x3m/2m                  6 times 3 minutes of work and 2 of recovery
+//     ladder:1m,2m,3m,2m,1m/1m work of each length with 1 minute of recovery after
+//     pyramid:1m,4m,1m/50%     1 to 4 minutes and back in steps of 1, recovering half of the work
+//     descending:5m,1m,1m/1m   5 minutes down to 1 in steps of 1
+//     tabata:2                 2 sets of 8 times 20 seconds of work and 10 of recovery
+use crate::formats::{seconds, session, Block, Goal, Step, Target};
+use crate::polar_watch::PolarError;
+use polar::data::PbTrainingSessionTarget;
+use std::time::Duration;
+
+const TABATA_ROUNDS: u32 = 8;
+const TABATA_WORK: Duration = Duration::from_secs(20);
+const TABATA_REST: Duration = Duration::from_secs(10);
+const TABATA_SET_REST: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rest {
+    Fixed(Duration),
+    // Fraction of the work before it, 0.5 recovers half as long as the work
+    Ratio(f32),
+}
+
+impl Rest {
+    // The recovery after work of this length
+    fn after(self, work: Duration) -> Result<Duration, PolarError> {
+        match self {
+            Rest::Fixed(duration) => Ok(duration),
+            Rest::Ratio(ratio) => seconds(work.as_secs_f64() * f64::from(ratio)).ok_or_else(|| {
+                PolarError::new(format!(
+                    "A recovery of {}% of {} is too long",
+                    ratio * 100.0,
+                    crate::human_duration(work)
+                ))
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Template {
+    Intervals {
+        times: u32,
+        work: Duration,
+        rest: Rest,
+    },
+    // Work of each of these lengths, in order
+    Ladder {
+        work: Vec<Duration>,
+        rest: Rest,
+    },
+    // From the shortest to the peak and back
+    Pyramid {
+        from: Duration,
+        peak: Duration,
+        step: Duration,
+        rest: Rest,
+    },
+    Descending {
+        from: Duration,
+        to: Duration,
+        step: Duration,
+        rest: Rest,
+    },
+    Tabata {
+        sets: u32,
+    },
+}
+
+// Everything around the template
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub name: String,
+    pub sport: Option<u64>,
+    pub warm_up: Option<Duration>,
+    pub cool_down: Option<Duration>,
+    pub easy_target: Target,
+    pub work_target: Target,
+    pub recovery_target: Target,
+    pub template: Template,
+}
+
+// Like 1h, 3m, 90s, 1m30s or 1.5m. Plain numbers are seconds
+pub fn parse_duration(text: &str) -> Result<Duration, PolarError> {
+    let invalid = || {
+        PolarError::new(format!(
+            "Invalid duration '{}', use 1h, 3m, 90s or 1m30s",
+            text
+        ))
+    };
+
+    let text = text.trim();
+    if let Ok(value) = text.parse::<f64>() {
+        return seconds(value).ok_or_else(invalid);
+    }
+
+    let mut total = 0.0;
+    let mut number = String::new();
+    for character in text.chars() {
+        let unit = match character {
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => {
+                number.push(character);
+                continue;
+            }
+        };
+
+        total += number.parse::<f64>().map_err(|_| invalid())? * unit;
+        number.clear();
+    }
+
+    if !number.is_empty() || text.is_empty() {
+        return Err(invalid());
+    }
+
+    seconds(total).ok_or_else(invalid)
+}
+
+fn parse_rest(text: &str) -> Result<Rest, PolarError> {
+    match text.trim().strip_suffix('%') {
+        Some(percent) => percent
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|percent| percent.is_finite() && *percent >= 0.0)
+            .map(|percent| Rest::Ratio(percent / 100.0))
+            .ok_or_else(|| PolarError::new(format!("Invalid rest '{}'", text))),
+        None => Ok(Rest::Fixed(parse_duration(text)?)),
+    }
+}
+
+fn parse_durations(text: &str) -> Result<Vec<Duration>, PolarError> {
+    text.split(',').map(parse_duration).collect()
+}
+
+// Lengths from first to last in steps of step, in either direction
+fn stepped(first: Duration, last: Duration, step: Duration) -> Result<Vec<Duration>, PolarError> {
+    if step == Duration::from_secs(0) {
+        return Err(PolarError::new("The step between intervals can't be 0"));
+    }
+
+    let mut lengths = vec![first];
+    let mut current = first;
+    while current != last {
+        current = if last > current {
+            (current + step).min(last)
+        } else {
+            current.saturating_sub(step).max(last)
+        };
+        lengths.push(current);
+    }
+
+    Ok(lengths)
+}
+
+impl std::str::FromStr for Template {
+    type Err = PolarError;
+
+    fn from_str(value: &str) -> Result<Template, PolarError> {
+        let invalid = || {
+            PolarError::new(format!(
+                "Unknown template '{}', use <N>x<work>/<rest>, ladder:, pyramid:, descending: or tabata",
+                value
+            ))
+        };
+
+        let (kind, arguments) = value.split_once(':').unwrap_or((value, ""));
+
+        if kind == "tabata" {
+            let sets = match arguments {
+                "" => 1,
+                sets => sets.trim().parse().map_err(|_| invalid())?,
+            };
+            return Ok(Template::Tabata { sets });
+        }
+
+        let (lengths, rest) = match arguments {
+            "" => kind.split_once('/'),
+            arguments => arguments.split_once('/'),
+        }
+        .ok_or_else(invalid)?;
+
+        let three = || match parse_durations(lengths)?.as_slice() {
+            [first, second, third] => Ok((*first, *second, *third)),
+            _ => Err(invalid()),
+        };
+
+        match kind {
+            "ladder" => Ok(Template::Ladder {
+                work: parse_durations(lengths)?,
+                rest: parse_rest(rest)?,
+            }),
+            "pyramid" => {
+                let (from, peak, step) = three()?;
+                Ok(Template::Pyramid {
+                    from,
+                    peak,
+                    step,
+                    rest: parse_rest(rest)?,
+                })
+            }
+            "descending" => {
+                let (from, to, step) = three()?;
+                Ok(Template::Descending {
+                    from,
+                    to,
+                    step,
+                    rest: parse_rest(rest)?,
+                })
+            }
+            _ => {
+                let (times, work) = lengths.split_once('x').ok_or_else(invalid)?;
+                Ok(Template::Intervals {
+                    times: times.trim().parse().map_err(|_| invalid())?,
+                    work: parse_duration(work)?,
+                    rest: parse_rest(rest)?,
+                })
+            }
+        }
+    }
+}
+
+pub(crate) fn step(name: String, duration: Duration, target: Target) -> Block {
+    Block::Step(Step {
+        name,
+        goal: Goal::Time(duration),
+        target,
+    })
+}
+
+impl Template {
+    // Work lengths of the templates that are written out phase by phase
+    fn lengths(&self) -> Result<Option<(Vec<Duration>, Rest)>, PolarError> {
+        match self {
+            Template::Ladder { work, rest } => Ok(Some((work.clone(), *rest))),
+            Template::Pyramid {
+                from,
+                peak,
+                step,
+                rest,
+            } => {
+                let mut lengths = stepped(*from, *peak, *step)?;
+                let down: Vec<Duration> = lengths.iter().rev().skip(1).cloned().collect();
+                lengths.extend(down);
+                Ok(Some((lengths, *rest)))
+            }
+            Template::Descending {
+                from,
+                to,
+                step,
+                rest,
+            } => Ok(Some((stepped(*from, *to, *step)?, *rest))),
+            _ => Ok(None),
+        }
+    }
+
+    // The main part of the session, without warm up and cool down
+    pub fn blocks(&self, work: Target, recovery: Target) -> Result<Vec<Block>, PolarError> {
+        match *self {
+            Template::Intervals {
+                times,
+                work: length,
+                rest,
+            } => Ok(vec![Block::Repeat {
+                times,
+                blocks: vec![
+                    step("Work".into(), length, work),
+                    step("Recovery".into(), rest.after(length)?, recovery),
+                ],
+            }]),
+            Template::Tabata { sets } => {
+                let rounds = Block::Repeat {
+                    times: TABATA_ROUNDS,
+                    blocks: vec![
+                        step("Work".into(), TABATA_WORK, work),
+                        step("Recovery".into(), TABATA_REST, recovery),
+                    ],
+                };
+
+                Ok(match sets {
+                    0 => vec![],
+                    1 => vec![rounds],
+                    sets => vec![Block::Repeat {
+                        times: sets,
+                        blocks: vec![
+                            rounds,
+                            step("Set recovery".into(), TABATA_SET_REST, recovery),
+                        ],
+                    }],
+                })
+            }
+            _ => {
+                let (lengths, rest) = match self.lengths()? {
+                    Some(lengths) => lengths,
+                    None => return Ok(vec![]),
+                };
+                let mut blocks = vec![];
+
+                for (index, length) in lengths.iter().enumerate() {
+                    blocks.push(step(format!("Work {}", index + 1), *length, work));
+
+                    // The cool down follows the last one
+                    if index + 1 < lengths.len() {
+                        let rest = rest.after(*length)?;
+                        blocks.push(step(format!("Recovery {}", index + 1), rest, recovery));
+                    }
+                }
+
+                Ok(blocks)
+            }
+        }
+    }
+}
+
+impl Plan {
+    pub fn new(name: &str, template: Template) -> Plan {
+        Plan {
+            name: name.to_string(),
+            sport: None,
+            warm_up: None,
+            cool_down: None,
+            easy_target: Target::Free,
+            work_target: Target::Free,
+            recovery_target: Target::Free,
+            template,
+        }
+    }
+
+    pub fn blocks(&self) -> Result<Vec<Block>, PolarError> {
+        let mut blocks = vec![];
+
+        if let Some(duration) = self.warm_up {
+            blocks.push(step("Warm up".into(), duration, self.easy_target));
+        }
+
+        blocks.extend(
+            self.template
+                .blocks(self.work_target, self.recovery_target)?,
+        );
+
+        if let Some(duration) = self.cool_down {
+            blocks.push(step("Cool down".into(), duration, self.easy_target));
+        }
+
+        Ok(blocks)
+    }
+
+    // Ready to be encoded and uploaded as a favourite
+    pub fn session(&self) -> Result<PbTrainingSessionTarget, PolarError> {
+        session(&self.name, "", self.sport, &self.blocks()?)
+    }
+}
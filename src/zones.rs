@@ -0,0 +1,383 @@
+// Training zones worked out from the athlete's profile, and targets written with them. The watch
+// only knows its own heart rate zones (by % of max heart rate), power zones and speeds, so zones
+// of the other models are turned into those when a session is made:
+//
+//     Z4, hr:Z2-Z3             heart rate zones, by % of max or Karvonen
+//     power:Z6                 Coggan power zones, 1 to 7
+//     easy, marathon, threshold, interval, repetition
+//                              Daniels paces, from the threshold pace
+//
+// The concrete targets of formats::Target are accepted too, like hr:4 or speed:12-14
+use crate::formats::{heart_rate_zone, pace, power_zone, Target};
+use crate::polar_watch::PolarError;
+use std::fmt;
+use std::time::Duration;
+
+// Heart rate zones 1 to 5, as % of max heart rate or of heart rate reserve
+const HEART_RATE_LIMITS: [(f32, f32); 5] = [
+    (0.50, 0.60),
+    (0.60, 0.70),
+    (0.70, 0.80),
+    (0.80, 0.90),
+    (0.90, 1.00),
+];
+
+// Coggan's power zones 1 to 7, as a fraction of FTP. Zone 7 has no top, 2.0 stands for it
+const POWER_LIMITS: [(f32, f32); 7] = [
+    (0.00, 0.55),
+    (0.55, 0.75),
+    (0.75, 0.90),
+    (0.90, 1.05),
+    (1.05, 1.20),
+    (1.20, 1.50),
+    (1.50, 2.00),
+];
+
+// Daniels' paces as a fraction of the speed at threshold pace. His tables go by VO2max, these
+// are the same ranges relative to T pace, close enough for targets on a watch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pace {
+    Easy,
+    Marathon,
+    Threshold,
+    Interval,
+    Repetition,
+}
+
+pub const PACES: [Pace; 5] = [
+    Pace::Easy,
+    Pace::Marathon,
+    Pace::Threshold,
+    Pace::Interval,
+    Pace::Repetition,
+];
+
+impl Pace {
+    fn limits(self) -> (f32, f32) {
+        match self {
+            Pace::Easy => (0.74, 0.90),
+            Pace::Marathon => (0.91, 0.97),
+            Pace::Threshold => (0.98, 1.02),
+            Pace::Interval => (1.08, 1.14),
+            Pace::Repetition => (1.19, 1.25),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Pace::Easy => "easy",
+            Pace::Marathon => "marathon",
+            Pace::Threshold => "threshold",
+            Pace::Interval => "interval",
+            Pace::Repetition => "repetition",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HeartRateModel {
+    // % of max heart rate, like the watch
+    #[default]
+    PercentOfMax,
+    // % of heart rate reserve, the difference between max and resting heart rate
+    Karvonen,
+}
+
+impl std::str::FromStr for HeartRateModel {
+    type Err = PolarError;
+
+    fn from_str(value: &str) -> Result<HeartRateModel, PolarError> {
+        match value {
+            "max" => Ok(HeartRateModel::PercentOfMax),
+            "karvonen" => Ok(HeartRateModel::Karvonen),
+            _ => Err(PolarError::new(format!(
+                "Unknown heart rate model '{}', use max or karvonen",
+                value
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for HeartRateModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeartRateModel::PercentOfMax => write!(f, "max"),
+            HeartRateModel::Karvonen => write!(f, "karvonen"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Athlete {
+    pub max_heart_rate: Option<u32>,
+    pub resting_heart_rate: Option<u32>,
+    // Time per km
+    pub threshold_pace: Option<Duration>,
+    // In watts
+    pub ftp: Option<f32>,
+    pub heart_rate_model: HeartRateModel,
+}
+
+// Time per km like 4:30
+pub fn parse_pace(text: &str) -> Result<Duration, PolarError> {
+    let invalid = || {
+        PolarError::new(format!(
+            "Invalid pace '{}', use minutes:seconds per km",
+            text
+        ))
+    };
+
+    let (minutes, seconds) = text.trim().split_once(':').ok_or_else(invalid)?;
+    let minutes: u64 = minutes.parse().map_err(|_| invalid())?;
+    let seconds: u64 = seconds.parse().map_err(|_| invalid())?;
+    if seconds >= 60 || minutes + seconds == 0 {
+        return Err(invalid());
+    }
+
+    Ok(Duration::from_secs(minutes * 60 + seconds))
+}
+
+// Inverse of parse_pace
+pub fn format_pace(per_km: Duration) -> String {
+    pace(3600.0 / per_km.as_secs_f32())
+}
+
+fn missing(setting: &str) -> PolarError {
+    PolarError::new(format!(
+        "This needs your {0}, set {0} in the config file",
+        setting
+    ))
+}
+
+fn check_zone(zone: u32, zones: usize) -> Result<usize, PolarError> {
+    if zone == 0 || zone as usize > zones {
+        return Err(PolarError::new(format!(
+            "Zone {} doesn't exist, there are {} zones",
+            zone, zones
+        )));
+    }
+
+    Ok(zone as usize - 1)
+}
+
+impl Athlete {
+    pub fn validate(&self) -> Result<(), PolarError> {
+        if self.max_heart_rate == Some(0) {
+            return Err(PolarError::new("max_heart_rate can't be 0"));
+        }
+
+        if let (Some(max), Some(resting)) = (self.max_heart_rate, self.resting_heart_rate) {
+            if resting >= max {
+                return Err(PolarError::new(format!(
+                    "resting_heart_rate ({}) must be below max_heart_rate ({})",
+                    resting, max
+                )));
+            }
+        }
+
+        match self.ftp {
+            Some(ftp) if !ftp.is_finite() || ftp <= 0.0 => Err(PolarError::new(format!(
+                "ftp must be in watts above 0, got {}",
+                ftp
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn max_heart_rate(&self) -> Result<u32, PolarError> {
+        self.validate()?;
+        self.max_heart_rate.ok_or_else(|| missing("max_heart_rate"))
+    }
+
+    fn ftp(&self) -> Result<f32, PolarError> {
+        self.validate()?;
+        self.ftp.ok_or_else(|| missing("ftp"))
+    }
+
+    // Lower and upper bpm of a heart rate zone, 1 to 5
+    pub fn heart_rate_zone(&self, zone: u32) -> Result<(u32, u32), PolarError> {
+        let (low, high) = HEART_RATE_LIMITS[check_zone(zone, HEART_RATE_LIMITS.len())?];
+        let max = self.max_heart_rate()? as f32;
+
+        let (base, range) = match self.heart_rate_model {
+            HeartRateModel::PercentOfMax => (0.0, max),
+            HeartRateModel::Karvonen => {
+                let resting =
+                    self.resting_heart_rate
+                        .ok_or_else(|| missing("resting_heart_rate"))? as f32;
+                (resting, max - resting)
+            }
+        };
+
+        Ok((
+            (base + low * range).round() as u32,
+            (base + high * range).round() as u32,
+        ))
+    }
+
+    // Lower and upper watts of a Coggan power zone, 1 to 7
+    pub fn power_zone(&self, zone: u32) -> Result<(f32, f32), PolarError> {
+        let (low, high) = POWER_LIMITS[check_zone(zone, POWER_LIMITS.len())?];
+        let ftp = self.ftp()?;
+
+        Ok((low * ftp, high * ftp))
+    }
+
+    // The watch heart rate zones that cover low to high bpm
+    pub fn heart_rate_target(&self, low: u32, high: u32) -> Result<Target, PolarError> {
+        let max = self.max_heart_rate()? as f32;
+        let percent = |bpm: u32| (100.0 * bpm as f32 / max).round() as u32;
+
+        let lower = heart_rate_zone(percent(low), false);
+        Ok(Target::HeartRateZone {
+            lower,
+            upper: heart_rate_zone(percent(high), true).max(lower),
+        })
+    }
+
+    // The watch power zones that cover low to high watts
+    pub fn power_target(&self, low: f32, high: f32) -> Result<Target, PolarError> {
+        let ftp = self.ftp()?;
+        Ok(power_range(low / ftp, high / ftp))
+    }
+
+    // Slower and faster end of a pace, in km/h
+    pub fn pace_zone(&self, pace: Pace) -> Result<(f32, f32), PolarError> {
+        let threshold = self
+            .threshold_pace
+            .ok_or_else(|| missing("threshold_pace"))?;
+        let speed = 3600.0 / threshold.as_secs_f32();
+        let (slow, fast) = pace.limits();
+
+        Ok((slow * speed, fast * speed))
+    }
+
+    // Every zone the profile has enough settings for, one per line
+    pub fn describe_zones(&self) -> String {
+        let mut result = String::new();
+
+        if self.max_heart_rate.is_some() {
+            result += &format!("Heart rate ({})\n", self.heart_rate_model);
+            for zone in 1..=HEART_RATE_LIMITS.len() as u32 {
+                match self.heart_rate_zone(zone) {
+                    Ok((lower, upper)) => {
+                        result += &format!("  Z{}  {}-{} bpm\n", zone, lower, upper)
+                    }
+                    Err(error) => {
+                        result += &format!("  {}\n", crate::error_message(&error));
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.ftp.is_some() {
+            result += "Power (Coggan)\n";
+            for zone in 1..=POWER_LIMITS.len() as u32 {
+                if let Ok((lower, upper)) = self.power_zone(zone) {
+                    result += &if zone as usize == POWER_LIMITS.len() {
+                        format!("  Z{}  {:.0}+ W\n", zone, lower)
+                    } else {
+                        format!("  Z{}  {:.0}-{:.0} W\n", zone, lower, upper)
+                    };
+                }
+            }
+        }
+
+        if self.threshold_pace.is_some() {
+            result += "Pace (Daniels)\n";
+            for pace_zone in &PACES {
+                if let Ok((slow, fast)) = self.pace_zone(*pace_zone) {
+                    result += &format!(
+                        "  {:<10}  {}-{}/km\n",
+                        pace_zone.name(),
+                        pace(slow),
+                        pace(fast)
+                    );
+                }
+            }
+        }
+
+        if result.is_empty() {
+            result += "No zones, set max_heart_rate, ftp or threshold_pace in the config file\n";
+        }
+
+        result
+    }
+}
+
+// Watch power zones from low to high, as fractions of FTP. The top is left out, it is where the
+// next zone starts
+fn power_range(low: f32, high: f32) -> Target {
+    let lower = power_zone(low);
+    Target::PowerZone {
+        lower,
+        upper: power_zone(high - 0.001).max(lower),
+    }
+}
+
+// A target as written by the user, which may need the profile to become a phase target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Intensity {
+    Target(Target),
+    HeartRateZone { lower: u32, upper: u32 },
+    PowerZone { lower: u32, upper: u32 },
+    Pace(Pace),
+}
+
+// Z4 or Z2-Z3
+fn parse_zones(text: &str) -> Option<(u32, u32)> {
+    let zone = |zone: &str| zone.trim().strip_prefix(['Z', 'z'])?.parse().ok();
+
+    match text.split_once('-') {
+        Some((lower, upper)) => Some((zone(lower)?, zone(upper)?)),
+        None => zone(text).map(|zone| (zone, zone)),
+    }
+}
+
+impl std::str::FromStr for Intensity {
+    type Err = PolarError;
+
+    fn from_str(value: &str) -> Result<Intensity, PolarError> {
+        if let Some(pace) = PACES.iter().find(|pace| pace.name() == value) {
+            return Ok(Intensity::Pace(*pace));
+        }
+
+        let (kind, zones) = value.split_once(':').unwrap_or(("hr", value));
+        match (kind, parse_zones(zones)) {
+            ("hr", Some((lower, upper))) => Ok(Intensity::HeartRateZone { lower, upper }),
+            ("power", Some((lower, upper))) => Ok(Intensity::PowerZone { lower, upper }),
+            _ => value.parse().map(Intensity::Target).map_err(|_| {
+                PolarError::new(format!(
+                    "Unknown target '{}', use Z<zone>, power:Z<zone>, a pace like threshold, \
+                     or free, hr:<zones>, power:<zones> or speed:<km/h>",
+                    value
+                ))
+            }),
+        }
+    }
+}
+
+impl Intensity {
+    // The phase target closest to the intensity. Heart rate zones become the watch zones that
+    // cover the same bpm, power zones the watch power zones that cover the same % of FTP
+    pub fn resolve(&self, athlete: &Athlete) -> Result<Target, PolarError> {
+        match *self {
+            Intensity::Target(target) => Ok(target),
+            Intensity::HeartRateZone { lower, upper } => {
+                let (low, _) = athlete.heart_rate_zone(lower)?;
+                let (_, high) = athlete.heart_rate_zone(upper)?;
+                athlete.heart_rate_target(low, high)
+            }
+            Intensity::PowerZone { lower, upper } => {
+                let (low, _) = POWER_LIMITS[check_zone(lower, POWER_LIMITS.len())?];
+                let (_, high) = POWER_LIMITS[check_zone(upper, POWER_LIMITS.len())?];
+                Ok(power_range(low, high))
+            }
+            Intensity::Pace(pace) => {
+                let (lower, upper) = athlete.pace_zone(pace)?;
+                Ok(Target::Speed { lower, upper })
+            }
+        }
+    }
+}
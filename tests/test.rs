@@ -1,7 +1,8 @@
 extern crate nfd;
 extern crate polar_send_training;
 
-use polar_send_training::polar_watch::{polar, PolarWatch};
+use polar_send_training::formats::{Block, Goal, Step, Target};
+use polar_send_training::polar_watch::{polar, Mismatch, Notification, PolarWatch};
 
 fn test_all_files_from(test_dir: &str) {
     let mut context = rusb::Context::new().unwrap();
@@ -73,3 +74,958 @@ fn fuzz_by_size() {
         watch.send_file("/U/0/FAV/00/TST.BPB", &data).unwrap();
     }
 }
+
+#[test]
+// Stream a file bigger than a few packets to the watch and back, checking the progress reports
+fn stream_round_trip() {
+    let mut context = rusb::Context::new().unwrap();
+    let mut watch = PolarWatch::find_one(&mut context).unwrap();
+
+    let data = polar_send_training::read_bytes("tests/examples/many-rec-phases.BPB").unwrap();
+
+    let mut uploaded = 0;
+    watch
+        .send_file_from(
+            "/U/0/FAV/00/TST.BPB",
+            data.as_slice(),
+            data.len(),
+            &mut |done, _| uploaded = done,
+        )
+        .unwrap();
+    assert_eq!(uploaded, data.len());
+
+    let mut downloaded = Vec::new();
+    let mut reported = (0, None);
+    let size = watch
+        .get_file_to(
+            "/U/0/FAV/00/TST.BPB",
+            &mut downloaded,
+            Some(data.len()),
+            &mut |done, total| reported = (done, total),
+        )
+        .unwrap();
+
+    assert_eq!(size, data.len());
+    assert_eq!(downloaded, data);
+    assert_eq!(reported, (data.len(), Some(data.len())));
+}
+
+#[test]
+fn mismatch_reports_first_difference() {
+    assert_eq!(Mismatch::compare("/A", &[1, 2, 3], &[1, 2, 3]), None);
+
+    let mismatch = Mismatch::compare("/A", &[1, 2, 3], &[1, 5, 3, 4]).unwrap();
+    assert_eq!(mismatch.first_difference, Some(1));
+    assert_eq!((mismatch.sent_len, mismatch.found_len), (3, 4));
+
+    // Same prefix, so the first difference is where the shorter one ends
+    let mismatch = Mismatch::compare("/A", &[1, 2, 3], &[1, 2]).unwrap();
+    assert_eq!(mismatch.first_difference, Some(2));
+
+    // Streamed files are compared by checksum, which can't tell where they differ
+    let mismatch = Mismatch {
+        first_difference: None,
+        found_len: 3,
+        ..mismatch
+    };
+    assert!(mismatch
+        .to_string()
+        .ends_with("found 3 bytes, with different content"));
+}
+
+#[test]
+fn sync_state_round_trip() {
+    use polar_send_training::sync::SyncState;
+
+    let state = SyncState::parse("ABC123/20200101/120000\n\n  ABC123/20200102/083000  \n");
+    assert_eq!(state.synced.len(), 2);
+    assert!(state.synced.contains("ABC123/20200102/083000"));
+    assert_eq!(SyncState::parse(&state.to_text()), state);
+}
+
+#[test]
+fn notifications_are_decoded() {
+    assert_eq!(
+        Notification::parse(&[0x11, 3 << 2 | 0x2, 0, 3, 0, 85]),
+        Notification::Battery { percent: 85 }
+    );
+    // The battery level is read from byte 5 even when the size doesn't cover it
+    assert_eq!(
+        Notification::parse(&[0x11, 1 << 2 | 0x2, 0, 3, 0, 40, 0, 0]),
+        Notification::Battery { percent: 40 }
+    );
+    assert_eq!(
+        Notification::parse(&[0x11, 1 << 2 | 0x2, 0, 2, 0, 0]),
+        Notification::Idle
+    );
+    assert_eq!(
+        Notification::parse(&[0x11, 2 << 2 | 0x2, 0, 42, 7, 0]),
+        Notification::Unknown {
+            code: 42,
+            data: vec![42, 7]
+        }
+    );
+}
+
+#[test]
+fn trace_decodes_frame_headers() {
+    use polar_send_training::polar_watch::{describe_frame, describe_request, Direction};
+
+    // Last packet of an answer: 2 payload bytes, no more packets, sequence 4
+    let frame = [0x11, 3 << 2, 4, 0xab, 0xcd, 0, 0, 0];
+    let description = describe_frame(Direction::In, &frame);
+    assert!(description.starts_with("IN  kind=0x11 seq=4 len=3 more=no notification=no"));
+    assert!(description.contains("ab cd  "));
+
+    let frame = [0x1, 62 << 2 | 0x1, 0, 0];
+    assert!(describe_frame(Direction::Out, &frame).contains("more=yes"));
+
+    // Get /U/0/
+    let request = [9, 0, 0x8, 0x0, 0x12, 0x5, b'/', b'U', b'/', b'0', b'/', 0];
+    assert_eq!(
+        describe_request(&request),
+        Some("PbPFtpOperation get /U/0/ + 1 bytes".to_string())
+    );
+}
+
+#[test]
+fn pcap_records_have_usbmon_headers() {
+    use polar_send_training::polar_watch::{Direction, PcapWriter};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let time = UNIX_EPOCH + Duration::new(1_600_000_000, 250_000_000);
+    let frame = [0x1, 3 << 2, 0, 0xab, 0xcd];
+
+    let mut writer = PcapWriter::new(vec![]).unwrap();
+    writer
+        .write_frame(Direction::Out, 3, 7, 1, &frame, time)
+        .unwrap();
+    writer
+        .write_frame(Direction::In, 3, 7, 1, &frame, time)
+        .unwrap();
+    let data = writer.into_inner();
+
+    // Global header with LINKTYPE_USB_LINUX_MMAPPED
+    assert_eq!(&data[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(&data[20..24], &220u32.to_le_bytes());
+
+    // Each record is 16 bytes of pcap header, 64 bytes of usbmon header and the frame
+    let record = 16 + 64 + frame.len();
+    assert_eq!(data.len(), 24 + 2 * record);
+
+    let first = &data[24..24 + record];
+    assert_eq!(&first[8..12], &(64 + frame.len() as u32).to_le_bytes());
+    assert_eq!(
+        (first[16 + 8], first[16 + 10], first[16 + 11]),
+        (b'S', 1, 7)
+    );
+    assert_eq!(&first[16 + 64..], &frame);
+
+    let second = &data[24 + record..];
+    assert_eq!(second[16], 1);
+    assert_eq!((second[16 + 8], second[16 + 10]), (b'C', 0x81));
+}
+
+#[test]
+fn dissector_describes_every_field() {
+    use polar_send_training::polar_watch::FRAME_FIELDS;
+
+    let dissector = PolarWatch::lua_dissector();
+    for field in FRAME_FIELDS.iter() {
+        assert!(dissector.contains(&format!("\"polar.{}\"", field.name)));
+    }
+    assert!(dissector.contains("add(0x0da40008, polar)"));
+}
+
+#[test]
+fn config_file_round_trip() {
+    use polar_send_training::config::Config;
+    use polar_send_training::polar_watch::Verification;
+    use std::time::Duration;
+
+    let text = "
+        # Defaults for the whole squad
+        log_level = debug
+        verify = size
+        serial = ABC123
+
+        [set.race-week]
+        file = intervals.BPB
+        file = recovery.BPB
+
+        [model.Polar M430]
+        read_timeout = 10
+        verify = full
+    ";
+
+    let config = Config::parse(text).unwrap();
+    assert_eq!(config.log_level, log::LevelFilter::Debug);
+    assert_eq!(config.serial.as_deref(), Some("ABC123"));
+    assert_eq!(
+        config.sets["race-week"],
+        vec!["intervals.BPB", "recovery.BPB"]
+    );
+    assert_eq!(Config::parse(&config.to_text()).unwrap(), config);
+
+    let options = config.upload_options(None);
+    assert_eq!(options.verification, Verification::Size);
+    assert!(options.check_sports);
+
+    let unchecked = Config::parse("check_sports = no").unwrap();
+    assert!(!unchecked.upload_options(None).check_sports);
+    assert_eq!(Config::parse(&unchecked.to_text()).unwrap(), unchecked);
+
+    let options = config.upload_options(Some("Polar M430"));
+    assert_eq!(options.verification, Verification::Full);
+    assert_eq!(options.usb.read_timeout, Duration::from_secs(10));
+
+    assert!(Config::parse("unknown = 1").is_err());
+    for timeout in &["-1", "nan", "inf", "1e30"] {
+        let text = format!("read_timeout = {}", timeout);
+        assert!(Config::parse(&text).is_err(), "{}", text);
+    }
+    for ftp in &["0", "-250", "inf", "nan"] {
+        let text = format!("ftp = {}", ftp);
+        assert!(Config::parse(&text).is_err(), "{}", text);
+    }
+}
+
+#[test]
+fn usb_config_validation() {
+    use polar_send_training::polar_watch::UsbConfig;
+
+    assert!(UsbConfig::default().validate().is_ok());
+
+    for &packet_size in &[0, 7, 65, 512] {
+        let config = UsbConfig {
+            packet_size,
+            ..UsbConfig::default()
+        };
+        assert!(config.validate().is_err(), "{}", packet_size);
+    }
+
+    for &packet_size in &[8, 32, 64] {
+        let config = UsbConfig {
+            packet_size,
+            ..UsbConfig::default()
+        };
+        assert!(config.validate().is_ok(), "{}", packet_size);
+    }
+}
+
+#[test]
+fn zwo_rejects_malformed_attributes() {
+    use polar_send_training::formats::zwo;
+
+    for step in &[
+        r#"<SteadyState Duration="1e30" Power="0.65"/>"#,
+        r#"<SteadyState Duration="inf" Power="0.65"/>"#,
+        r#"<SteadyState Duration="-60" Power="0.65"/>"#,
+        r#"<SteadyState Duration="ten" Power="0.65"/>"#,
+        r#"<SteadyState Power="0.65"/>"#,
+        r#"<SteadyState Duration="60" Power="NaN"/>"#,
+        r#"<Warmup Duration="60" PowerLow="0.4"/>"#,
+        r#"<IntervalsT Repeat="2.5" OnDuration="60" OffDuration="60" OnPower="1" OffPower="0.5"/>"#,
+        r#"<IntervalsT Repeat="1e12" OnDuration="60" OffDuration="60" OnPower="1" OffPower="0.5"/>"#,
+    ] {
+        let text = format!("<workout_file><workout>{}</workout></workout_file>", step);
+        assert!(zwo::parse(&text, "file").is_err(), "{}", step);
+    }
+}
+
+#[test]
+fn zwo_intervals_become_repeated_phases() {
+    use polar_send_training::formats::zwo;
+    use polar_send_training::polar_watch::Message;
+
+    let text = r#"<?xml version="1.0"?>
+        <workout_file>
+            <name>Over &amp; under</name>
+            <sportType>bike</sportType>
+            <workout>
+                <Warmup Duration="600" PowerLow="0.40" PowerHigh="0.80"/>
+                <IntervalsT Repeat="4" OnDuration="120" OffDuration="60" OnPower="1.10" OffPower="0.50">
+                    <textevent timeoffset="0" message="Go!"/>
+                </IntervalsT>
+                <SteadyState Duration="300" Power="0.65"/>
+                <FreeRide Duration="90"/>
+            </workout>
+        </workout_file>"#;
+
+    let session = zwo::parse(text, "file").unwrap();
+    assert_eq!(session.name.text, "Over & under");
+
+    let phases = &session.exercise_target[0].phases.as_ref().unwrap().phase;
+    assert_eq!(phases.len(), 5);
+
+    let zones: Vec<_> = phases
+        .iter()
+        .map(|phase| {
+            phase
+                .intensity
+                .power_zone
+                .as_ref()
+                .map(|zone| (zone.lower, zone.upper))
+        })
+        .collect();
+    assert_eq!(
+        zones,
+        vec![Some((1, 3)), Some((5, 5)), Some((1, 1)), Some((2, 2)), None]
+    );
+
+    // The off phase jumps back to the on phase three more times
+    assert_eq!(phases[2].jump_index, Some(2));
+    assert_eq!(phases[2].repeat_count, Some(3));
+    assert_eq!(phases[1].jump_index, None);
+
+    assert_eq!(phases[0].goal.duration.as_ref().unwrap().minutes, Some(10));
+
+    // The result uploads like any other session file
+    let data = polar::encode(session).unwrap();
+    assert!(polar::data::PbTrainingSessionTarget::decode(data.as_slice()).is_ok());
+
+    assert!(zwo::parse(
+        "<workout_file><workout><Unknown/></workout></workout_file>",
+        ""
+    )
+    .is_err());
+}
+
+#[test]
+fn fit_workouts_round_trip() {
+    use polar_send_training::formats::{
+        fit, session, session_blocks, session_sport, ImportOptions,
+    };
+    use polar_send_training::polar_watch::Message;
+    use std::time::Duration;
+
+    for entry in std::fs::read_dir("tests/examples").unwrap() {
+        let path = entry.unwrap().path();
+        let data = std::fs::read(&path).unwrap();
+        let session = polar::data::PbTrainingSessionTarget::decode(data.as_slice()).unwrap();
+
+        let converted = fit::parse(
+            &fit::write(&session).unwrap(),
+            "",
+            &ImportOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(converted.name, session.name, "{}", path.display());
+        assert_eq!(
+            session_blocks(&converted).unwrap(),
+            session_blocks(&session).unwrap(),
+            "{}",
+            path.display()
+        );
+        assert_eq!(
+            converted.exercise_target[0].phases,
+            session.exercise_target[0].phases,
+            "{}",
+            path.display()
+        );
+        assert_eq!(
+            session_sport(&converted),
+            session_sport(&session),
+            "{}",
+            path.display()
+        );
+    }
+
+    // Every kind of target and goal, and a repeat inside another one
+    let blocks = vec![
+        step(
+            "Warm up",
+            Goal::Time(Duration::from_secs(600)),
+            Target::Free,
+        ),
+        Block::Repeat {
+            times: 3,
+            blocks: vec![
+                Block::Repeat {
+                    times: 4,
+                    blocks: vec![
+                        step(
+                            "Hard",
+                            Goal::Distance(400.0),
+                            Target::HeartRateZone { lower: 4, upper: 4 },
+                        ),
+                        step(
+                            "Easy",
+                            Goal::Time(Duration::from_secs(90)),
+                            Target::HeartRateZone { lower: 1, upper: 2 },
+                        ),
+                    ],
+                },
+                step(
+                    "Tempo",
+                    Goal::Distance(1500.0),
+                    Target::Speed {
+                        lower: 10.8,
+                        upper: 12.6,
+                    },
+                ),
+            ],
+        },
+        step(
+            "Sweet spot",
+            Goal::Time(Duration::from_secs(1200)),
+            Target::PowerZone { lower: 3, upper: 4 },
+        ),
+        step(
+            "Sprint",
+            Goal::Manual,
+            Target::PowerZone { lower: 5, upper: 5 },
+        ),
+    ];
+
+    // Sport 95 has no FIT sport, it is kept in a field only this crate reads
+    for &sport in &[Some(1), Some(2), Some(95), None] {
+        let session = session("Everything", "All targets", sport, &blocks).unwrap();
+        let converted = fit::parse(
+            &fit::write(&session).unwrap(),
+            "",
+            &ImportOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(session_blocks(&converted).unwrap(), blocks);
+        assert_eq!(session_sport(&converted), sport);
+    }
+
+    // A damaged file is refused
+    let data = std::fs::read("tests/examples/double-repeat.BPB").unwrap();
+    let session = polar::data::PbTrainingSessionTarget::decode(data.as_slice()).unwrap();
+    let mut fit_data = fit::write(&session).unwrap();
+    fit_data[20] ^= 0xff;
+    assert!(fit::parse(&fit_data, "", &ImportOptions::default()).is_err());
+}
+
+#[test]
+fn erg_profiles_become_power_zone_phases() {
+    use polar_send_training::formats::{erg, ImportOptions};
+    use polar_send_training::zones::Athlete;
+
+    let mrc = "
+        [COURSE HEADER]
+        VERSION = 2
+        DESCRIPTION = Sweet spot
+        FILE NAME = sweet-spot.mrc
+        MINUTES PERCENT
+        [END COURSE HEADER]
+        [COURSE DATA]
+        0.00    40
+        10.00   80
+        10.00   95
+        20.00   95
+        30.00   95
+        30.00   50
+        35.00   50
+        [END COURSE DATA]
+    ";
+
+    let session = erg::parse(mrc, "file", true, &ImportOptions::default()).unwrap();
+    assert_eq!(session.name.text, "sweet-spot");
+
+    let phases = &session.exercise_target[0].phases.as_ref().unwrap().phase;
+    let summary: Vec<_> = phases
+        .iter()
+        .map(|phase| {
+            let duration = phase.goal.duration.as_ref().unwrap();
+            let zone = phase.intensity.power_zone.as_ref().unwrap();
+            (
+                duration.minutes.unwrap() * 60 + duration.seconds.unwrap(),
+                zone.lower,
+            )
+        })
+        .collect();
+
+    // The ramp is stepped through zones 1 to 3, the two rows at 95% are one phase
+    assert_eq!(
+        summary,
+        vec![(240, 1), (300, 2), (60, 3), (1200, 4), (300, 1)]
+    );
+
+    let erg = "
+        [COURSE HEADER]
+        MINUTES WATTS
+        [END COURSE HEADER]
+        [COURSE DATA]
+        0   200
+        5   200
+        [END COURSE DATA]
+    ";
+
+    assert!(erg::parse(erg, "file", false, &ImportOptions::default()).is_err());
+
+    let options = ImportOptions {
+        athlete: Athlete {
+            ftp: Some(250.0),
+            ..Athlete::default()
+        },
+    };
+    let session = erg::parse(erg, "file", false, &options).unwrap();
+    let phase = &session.exercise_target[0].phases.as_ref().unwrap().phase[0];
+    assert_eq!(phase.intensity.power_zone.as_ref().unwrap().lower, 3);
+
+    // A course this long would take forever to split into ramp steps
+    let huge = "
+        [COURSE DATA]
+        0      50
+        1e300  100
+        [END COURSE DATA]
+    ";
+    assert!(erg::parse(huge, "file", true, &ImportOptions::default()).is_err());
+    assert!(erg::parse(
+        "FTP = inf\n[COURSE DATA]\n0 200\n5 200",
+        "file",
+        false,
+        &ImportOptions::default()
+    )
+    .is_err());
+}
+
+#[test]
+fn sessions_export_to_tcx_and_text() {
+    use polar_send_training::formats::{session, tcx, text};
+    use std::time::Duration;
+
+    let blocks = vec![
+        step(
+            "Warm up",
+            Goal::Time(Duration::from_secs(600)),
+            Target::HeartRateZone { lower: 2, upper: 2 },
+        ),
+        Block::Repeat {
+            times: 4,
+            blocks: vec![
+                step(
+                    "On",
+                    Goal::Time(Duration::from_secs(120)),
+                    Target::PowerZone { lower: 5, upper: 5 },
+                ),
+                step("Off", Goal::Time(Duration::from_secs(60)), Target::Free),
+            ],
+        },
+        step(
+            "Cool down",
+            Goal::Distance(1500.0),
+            Target::HeartRateZone { lower: 1, upper: 2 },
+        ),
+    ];
+
+    let session = session("Intervals & more", "", Some(1), &blocks).unwrap();
+
+    assert_eq!(
+        text::write(&session).unwrap(),
+        "- Warm up 10m Z2 HR\n\n4x\n- On 2m Z5\n- Off 1m\n\n- Cool down 1.5km 50-70% HR\n"
+    );
+
+    let tcx = tcx::write(&session).unwrap();
+    assert!(tcx.contains("<Workout Sport=\"Running\">"));
+    assert!(tcx.contains("<Name>Intervals &amp; mor</Name>"));
+    assert!(tcx.contains("<Step xsi:type=\"Repeat_t\">"));
+    assert!(tcx.contains("<Repetitions>4</Repetitions>"));
+    assert_eq!(tcx.matches("<Child xsi:type=\"Step_t\">").count(), 2);
+    assert!(tcx.contains("<StepId>5</StepId>"));
+    assert!(tcx.contains("<Meters>1500</Meters>"));
+}
+
+#[test]
+fn timeline_expands_repeats() {
+    use polar_send_training::formats::{decode_session, session_blocks, timeline::timeline};
+    use polar_send_training::polar_watch::Message;
+    use polar_send_training::{describe_favourite, formats, human_duration};
+    use std::time::Duration;
+
+    let data = std::fs::read("tests/examples/complex-repeat.BPB").unwrap();
+    let session = decode_session(&data).unwrap();
+    let blocks = session_blocks(&session).unwrap();
+
+    let entries: Vec<_> = timeline(&blocks).collect();
+    let phases: Vec<_> = entries.iter().map(|entry| entry.phase).collect();
+    assert_eq!(
+        phases,
+        vec![1, 2, 3, 2, 3, 4, 2, 3, 2, 3, 4, 2, 3, 2, 3, 4, 5]
+    );
+
+    // Second time through the inner repeat, on the second round of the outer one
+    assert_eq!(entries[8].repetitions, vec![(2, 3), (2, 2)]);
+    assert_eq!(entries[16].repetitions, vec![]);
+
+    // Every phase starts where the one before ended
+    for pair in entries.windows(2) {
+        assert_eq!(pair[0].end, pair[1].start);
+    }
+
+    let total: Duration = entries
+        .iter()
+        .map(|entry| entry.end.unwrap() - entry.start.unwrap())
+        .sum();
+    assert_eq!(entries.last().unwrap().end, Some(total));
+    assert_eq!(blocks.iter().map(Block::duration).sum::<Duration>(), total);
+
+    // The description of a favourite is built from the same blocks
+    let description = describe_favourite(data).unwrap();
+    assert!(description.starts_with(&format!("Repeat x1 [{}]", human_duration(total))));
+    assert!(description.contains("\n\tRepeat x3 ["));
+    assert!(description.contains("\n\t\tRepeat x2 ["));
+
+    // A jump without a repeat count is done once more, like to_phases writes it
+    let mut once = formats::session(
+        "Once more",
+        "",
+        None,
+        &[
+            Block::Repeat {
+                times: 2,
+                blocks: vec![step(
+                    "On",
+                    Goal::Time(Duration::from_secs(60)),
+                    Target::Free,
+                )],
+            },
+            step("Off", Goal::Time(Duration::from_secs(30)), Target::Free),
+        ],
+    )
+    .unwrap();
+    once.exercise_target[0].phases.as_mut().unwrap().phase[0].repeat_count = None;
+
+    let mut data = vec![];
+    once.encode(&mut data).unwrap();
+    let description = describe_favourite(data).unwrap();
+    assert!(
+        description.starts_with("Repeat x1 [1'30''"),
+        "{}",
+        description
+    );
+    assert!(description.contains("\n\tRepeat x1 [1'"), "{}", description);
+}
+
+#[test]
+fn recordings_are_decoded_from_exercise_files() {
+    use polar_send_training::exercise::Recording;
+    use std::time::Duration;
+
+    // Written by hand with the field numbers of Polar's exercise_laps.proto and
+    // exercise_samples.proto, then gzipped like the watch does for SAMPLES.GZB
+    let recording = Recording::load("tests/exercises/intervals").unwrap();
+
+    assert_eq!(
+        recording.laps,
+        vec![Duration::from_secs(60), Duration::from_secs(120)]
+    );
+    assert_eq!(recording.interval, Duration::from_secs(1));
+    assert_eq!(recording.heart_rate, vec![150, 150, 150, 150, 0, 150]);
+    assert_eq!(recording.speed, vec![15.0, 15.0, 15.5, 14.5, 10.0, 10.0]);
+    // Left and right pedals added up, the last sample has no right pedal
+    assert_eq!(recording.power, vec![205, 220, 235, 260, 120, 0]);
+
+    // The 00/ directory can be given directly too
+    assert_eq!(
+        Recording::load("tests/exercises/intervals/00").unwrap(),
+        recording
+    );
+}
+
+#[test]
+fn templates_generate_sessions() {
+    use polar_send_training::formats::{session_blocks, timeline::timeline, Block, Target};
+    use polar_send_training::templates::{parse_duration, Plan, Rest, Template};
+    use std::time::Duration;
+
+    assert_eq!(parse_duration("1m30s").unwrap(), Duration::from_secs(90));
+    assert!(parse_duration("3x").is_err());
+    for text in &["inf", "1e30", "1e30s", "-5", "NaN", "-1m", "infm"] {
+        assert!(parse_duration(text).is_err(), "{}", text);
+    }
+    assert!("pyramid:1m,3m,1m/inf%".parse::<Template>().is_err());
+    assert!("pyramid:1m,3m,1m/-50%".parse::<Template>().is_err());
+
+    // Every number is fine on its own, the recovery they make isn't
+    let plan = Plan::new("Long", "ladder:1000000h,1h/1e30%".parse().unwrap());
+    assert!(plan.blocks().is_err());
+
+    let mut plan = Plan::new("Pyramid", "pyramid:1m,3m,1m/50%".parse().unwrap());
+    plan.warm_up = Some(Duration::from_secs(600));
+    plan.cool_down = Some(Duration::from_secs(300));
+    plan.work_target = "hr:4".parse().unwrap();
+
+    let session = plan.session().unwrap();
+    let entries: Vec<_> = timeline(&session_blocks(&session).unwrap()).collect();
+    let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec![
+            "Warm up",
+            "Work 1",
+            "Recovery 1",
+            "Work 2",
+            "Recovery 2",
+            "Work 3",
+            "Recovery 3",
+            "Work 4",
+            "Recovery 4",
+            "Work 5",
+            "Cool down"
+        ]
+    );
+    assert_eq!(
+        entries[6].end.unwrap() - entries[6].start.unwrap(),
+        Duration::from_secs(90)
+    );
+    assert_eq!(
+        entries[5].target,
+        Target::HeartRateZone { lower: 4, upper: 4 }
+    );
+    assert_eq!(
+        entries[10].end,
+        Some(Duration::from_secs(600 + 9 * 60 + 4 * 60 + 300))
+    );
+
+    // Repeated templates stay repeats on the watch
+    let plan = Plan::new("Tabata", "tabata:2".parse::<Template>().unwrap());
+    match &plan.blocks().unwrap()[..] {
+        [Block::Repeat { times: 2, blocks }] => match &blocks[..] {
+            [Block::Repeat { times: 8, .. }, Block::Step(_)] => (),
+            blocks => panic!("Unexpected set {:?}", blocks),
+        },
+        blocks => panic!("Unexpected blocks {:?}", blocks),
+    }
+    assert_eq!(timeline(&plan.blocks().unwrap()).count(), 2 * (8 * 2 + 1));
+
+    assert_eq!(
+        "6x3m/90s".parse::<Template>().unwrap(),
+        Template::Intervals {
+            times: 6,
+            work: Duration::from_secs(180),
+            rest: Rest::Fixed(Duration::from_secs(90))
+        }
+    );
+
+    // Intervals can recover a share of the work too
+    let plan = Plan::new("Intervals", "6x3m/50%".parse::<Template>().unwrap());
+    match &plan.blocks().unwrap()[..] {
+        [Block::Repeat { times: 6, blocks }] => {
+            assert_eq!(blocks[1].duration(), Duration::from_secs(90))
+        }
+        blocks => panic!("Unexpected blocks {:?}", blocks),
+    }
+    let plan = Plan::new("Intervals", "6x1000000h/1e30%".parse().unwrap());
+    assert!(plan.blocks().is_err());
+}
+
+#[test]
+fn zones_resolve_symbolic_targets() {
+    use polar_send_training::config::Config;
+    use polar_send_training::formats::Target;
+    use polar_send_training::zones::{Intensity, Pace};
+
+    let config = Config::parse(
+        "max_heart_rate = 190\nresting_heart_rate = 50\nheart_rate_model = karvonen\nthreshold_pace = 4:00\n",
+    )
+    .unwrap();
+    assert_eq!(Config::parse(&config.to_text()).unwrap(), config);
+
+    let athlete = config.athlete();
+    assert_eq!(athlete.heart_rate_zone(3).unwrap(), (148, 162));
+
+    // 148-162 bpm is 78-85% of max, the watch's zones 3 and 4
+    let resolve = |text: &str| text.parse::<Intensity>().unwrap().resolve(&athlete);
+    assert_eq!(
+        resolve("Z3").unwrap(),
+        Target::HeartRateZone { lower: 3, upper: 4 }
+    );
+    assert_eq!(
+        resolve("power:Z6-Z7").unwrap(),
+        Target::PowerZone { lower: 5, upper: 5 }
+    );
+    assert_eq!(
+        resolve("power:Z4").unwrap(),
+        Target::PowerZone { lower: 4, upper: 4 }
+    );
+    assert_eq!(
+        resolve("hr:2").unwrap(),
+        Target::HeartRateZone { lower: 2, upper: 2 }
+    );
+
+    // 4:00/km is 15 km/h
+    assert_eq!(
+        "threshold".parse::<Intensity>().unwrap(),
+        Intensity::Pace(Pace::Threshold)
+    );
+    match resolve("threshold").unwrap() {
+        Target::Speed { lower, upper } => {
+            assert!((lower - 14.7).abs() < 0.01 && (upper - 15.3).abs() < 0.01)
+        }
+        target => panic!("Unexpected target {:?}", target),
+    }
+
+    // Power zones need the FTP to be shown in watts, not to become watch zones
+    assert!(athlete.power_zone(4).is_err());
+    assert!(resolve("Z6").is_err());
+    assert!("Z".parse::<Intensity>().is_err());
+
+    // A profile that can't be right is an error, not a zone of 0 bpm
+    assert!(Config::parse("max_heart_rate = 0\n").is_err());
+    assert!(Config::parse("max_heart_rate = 180\nresting_heart_rate = 190\n").is_err());
+    assert!(Config::parse("max_heart_rate = 180\nresting_heart_rate = 180\n").is_err());
+}
+
+#[test]
+fn physical_data_updates_keep_other_fields() {
+    use polar_send_training::polar_watch::{Gender, PhysicalData};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // Max heart rate 180, an unknown field 11 with 3 and resting heart rate 55
+    let file = [
+        0x2a, 0x03, 0x08, 0xb4, 0x01, 0x58, 0x03, 0x32, 0x02, 0x08, 0x37,
+    ];
+
+    let data = PhysicalData::decode(&file).unwrap();
+    assert_eq!(data.max_heart_rate, Some(180));
+    assert_eq!(data.resting_heart_rate, Some(55));
+    assert_eq!(data.to_text(), "max_hr = 180 bpm\nresting_hr = 55 bpm\n");
+
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    assert_eq!(PhysicalData::default().apply(&file, now).unwrap(), file);
+
+    let mut changes = PhysicalData::default();
+    changes.set_field("max_hr", "185").unwrap();
+    changes.set_field("gender", "female").unwrap();
+    assert!(changes.set_field("max_hr", "fast").is_err());
+    assert!(changes.set_field("shoe_size", "42").is_err());
+    for (name, value) in &[
+        ("weight", "NaN"),
+        ("weight", "inf"),
+        ("weight", "-70"),
+        ("height", "0"),
+        ("max_hr", "0"),
+        ("resting_hr", "-50"),
+        ("ftp", "0"),
+        ("birthday", "2025-02-31"),
+        ("birthday", "2023-02-29"),
+        ("birthday", "1990-04-31"),
+        ("birthday", "0-01-01"),
+    ] {
+        assert!(
+            changes.set_field(name, value).is_err(),
+            "{} = {}",
+            name,
+            value
+        );
+    }
+    changes.set_field("birthday", "2024-02-29").unwrap();
+    changes.set_field("weight", "70.5").unwrap();
+
+    let updated = changes.apply(&file, now).unwrap();
+    let data = PhysicalData::decode(&updated).unwrap();
+    assert_eq!(data.max_heart_rate, Some(185));
+    assert_eq!(data.resting_heart_rate, Some(55));
+    assert_eq!(data.gender, Some(Gender::Female));
+
+    // The unknown field is still there, and the file is marked as changed
+    assert!(updated.windows(2).any(|bytes| bytes == [0x58, 0x03]));
+    assert!(updated.windows(2).any(|bytes| bytes == [0xa2, 0x06]));
+}
+
+#[test]
+fn sport_profiles_keep_unknown_settings() {
+    use polar_send_training::polar_watch::{copy_for_sport, with_position};
+    use polar_send_training::polar_watch::{HeartRateView, SportProfile};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // Identifier 3, running, heart rate as % of max, an unknown field 50 with 7 and position 0
+    let file = [
+        0x08, 0x03, 0x12, 0x02, 0x08, 0x01, 0x1a, 0x02, 0x20, 0x03, 0x90, 0x03, 0x07, 0x38, 0x00,
+    ];
+
+    let profile = SportProfile::decode("/U/0/SPORTPRO/0/", &file).unwrap();
+    assert_eq!(profile.identifier, Some(3));
+    assert_eq!(profile.sport_id, Some(1));
+    assert_eq!(profile.position, Some(0));
+    assert_eq!(profile.heart_rate_view, Some(HeartRateView::PercentOfMax));
+
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let moved = with_position(&file, 4, now).unwrap();
+    let profile = SportProfile::decode("/U/0/SPORTPRO/0/", &moved).unwrap();
+    assert_eq!(profile.position, Some(4));
+    assert_eq!(profile.sport_id, Some(1));
+
+    let copy = copy_for_sport(&file, 9, 2, 1, now).unwrap();
+    let profile = SportProfile::decode("/U/0/SPORTPRO/1/", &copy).unwrap();
+    assert_eq!(profile.identifier, Some(9));
+    assert_eq!(profile.sport_id, Some(2));
+    assert_eq!(profile.position, Some(1));
+    assert_eq!(profile.heart_rate_view, Some(HeartRateView::PercentOfMax));
+
+    // The unknown field is still there, and the file is marked as changed
+    assert!(copy.windows(3).any(|bytes| bytes == [0x90, 0x03, 0x07]));
+    assert!(copy.windows(2).any(|bytes| bytes == [0xa2, 0x06]));
+}
+
+#[test]
+fn heart_rate_zone_updates_keep_other_settings() {
+    use polar_send_training::polar_watch::{with_heart_rate_zones, HeartRateView};
+    use polar_send_training::polar_watch::{HeartRateZones, SportProfile};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // Identifier 3, running and position 0. The settings have heart rate as % of max, an unknown
+    // field 50 with 9 and zone limits with a speed zone setting and one heart rate zone, 95-114
+    let file = [
+        0x08, 0x03, 0x12, 0x02, 0x08, 0x01, 0x1a, 0x15, 0x20, 0x03, 0x90, 0x03, 0x09, 0x32, 0x0e,
+        0x0a, 0x08, 0x08, 0x00, 0x12, 0x04, 0x08, 0x5f, 0x10, 0x72, 0x12, 0x02, 0x08, 0x05, 0x38,
+        0x00,
+    ];
+
+    let profile = SportProfile::decode("/U/0/SPORTPRO/0/", &file).unwrap();
+    assert_eq!(
+        profile.heart_rate_zones,
+        Some(HeartRateZones(vec![(95, 114)]))
+    );
+    assert_eq!(profile.heart_rate_view, Some(HeartRateView::PercentOfMax));
+
+    let zones: HeartRateZones = "96-115, 115-134,134-153,153-172,172-191".parse().unwrap();
+    assert_eq!(zones.to_string(), "96-115,115-134,134-153,153-172,172-191");
+    assert_eq!(zones.to_string().parse::<HeartRateZones>().unwrap(), zones);
+
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let updated = with_heart_rate_zones(&file, &zones, now).unwrap();
+    let profile = SportProfile::decode("/U/0/SPORTPRO/0/", &updated).unwrap();
+    assert_eq!(profile.heart_rate_zones, Some(zones.clone()));
+    assert_eq!(profile.heart_rate_view, Some(HeartRateView::PercentOfMax));
+    assert_eq!(profile.identifier, Some(3));
+    assert_eq!(profile.sport_id, Some(1));
+    assert_eq!(profile.position, Some(0));
+
+    // The unknown setting and the speed zones are still there, and the file is marked as changed
+    assert!(updated.windows(3).any(|bytes| bytes == [0x90, 0x03, 0x09]));
+    assert!(updated
+        .windows(4)
+        .any(|bytes| bytes == [0x12, 0x02, 0x08, 0x05]));
+    assert!(updated.windows(2).any(|bytes| bytes == [0xa2, 0x06]));
+
+    // Profiles without settings get them
+    let bare = with_heart_rate_zones(&[0x08, 0x03], &zones, now).unwrap();
+    let profile = SportProfile::decode("/U/0/SPORTPRO/0/", &bare).unwrap();
+    assert_eq!(profile.heart_rate_zones, Some(zones));
+
+    for text in &[
+        "95-114",
+        "95-114,114-133,133-152,152-171",
+        "0-114,114-133,133-152,152-171,171-190",
+        "114-95,114-133,133-152,152-171,171-190",
+        "95-120,114-133,133-152,152-171,171-190",
+        "95-114,114-133,133-152,152-171,171-fast",
+    ] {
+        assert!(text.parse::<HeartRateZones>().is_err(), "{}", text);
+    }
+    assert!(with_heart_rate_zones(&file, &HeartRateZones(vec![(95, 114)]), now).is_err());
+}
+
+fn step(name: &str, goal: Goal, target: Target) -> Block {
+    Block::Step(Step {
+        name: name.to_string(),
+        goal,
+        target,
+    })
+}